@@ -45,6 +45,7 @@ if [ -f "$TARGET_DIR/Group16" ]; then
   cp "$TARGET_DIR/Group16" "$BASE_DIR/bin/"
   cp "$TARGET_DIR/backup_program" "$BASE_DIR/bin/"
   cp "$TARGET_DIR/config_program" "$BASE_DIR/bin/"
+  cp "$TARGET_DIR/backup_cli" "$BASE_DIR/bin/"
   cp "$TARGET_DIR/service" "$BASE_DIR/bin/"
   cp "$TARGET_DIR/uninstall_service" "$BASE_DIR/bin/"
 else
@@ -1,17 +1,214 @@
-use std::{fs, io};
+use std::{env, fmt, fs, io};
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
-use std::time::{Duration, Instant};
-use fs_extra::dir::CopyOptions;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Instant, SystemTime};
+use chrono::{Local, NaiveDateTime};
 use fs_extra::error::Error as FsExtraError;
 use walkdir::{WalkDir, DirEntry};
+use crate::compression::{self, CompressionConfig};
+use crate::encryption::{self, Cipher, EncryptionConfig};
+use crate::filter::EntryFilter;
+use crate::image;
+use crate::lock::{DestinationLock, LockOwner};
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::metadata::{self, EntryMetadata};
+use crate::repository;
+use crate::retention::{self, RetentionPolicy};
+use crate::s3::S3Config;
+use crate::sftp::SftpConfig;
+use crate::staging::{self, Journal};
+use crate::storage::{self, RemoteSnapshot, SnapshotFile};
+use crate::volumes;
+use crate::webdav::WebDavConfig;
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
 pub struct Config {
+    // Name of the backup, naming its series folder when set
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    #[serde(default)]
     pub source_path: String,
+    // Folders backed up together, each in its own folder of the snapshots, instead of `source_path`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    // A folder, a folder of a removable drive wherever it is mounted: `UUID=<uuid>/path` or
+    // `LABEL=<label>/path`, or a remote destination given as a URL: `file:///path`,
+    // `s3://bucket/prefix`, `sftp://user@host:port/path` or `webdavs://host:port/path`
+    #[serde(default)]
     pub destination_path: String,
+    #[serde(default)]
     pub backup_type: String,
+    #[serde(default)]
     pub extensions_to_backup: Vec<String>,
+    // Mount points of the volumes saved by a full-disk backup, the volume holding the source if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    // Gitignore-style patterns, in order: `pattern` excludes, `!pattern` includes again
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<String>,
+    // Back up the files and folders symlinks point to, instead of keeping the symlinks
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    // Allow a destination inside the source, which is then left out of the backups
+    #[serde(default)]
+    pub allow_nested_destination: bool,
+    // Hard-link the files unchanged since the previous snapshot instead of copying them
+    #[serde(default)]
+    pub incremental: bool,
+    // "plain" copies the files as they are, "repository" stores deduplicated chunks
+    #[serde(default = "default_storage_format")]
+    pub storage_format: String,
+    // Number of files copied at the same time by the plain format
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+    // When the destination seems too small: "refuse" to start the backup, or only "warn"
+    #[serde(default = "default_space_check")]
+    pub space_check: String,
+    // Compression of the files saved, none by default
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    // Encryption of contents and names, disabled if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionConfig>,
+    // Old snapshots to delete after each backup, everything is kept if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retention: Option<RetentionPolicy>,
+    // Folder of the local working copies of remote destinations, the cache folder of the user if empty
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub cache_path: String,
+    // Connection to the S3-compatible service of an `s3://` destination
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub s3: Option<S3Config>,
+    // Keys and known hosts of an `sftp://` destination, the ones of ~/.ssh if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sftp: Option<SftpConfig>,
+    // Login of a `webdav://` or `webdavs://` destination
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub webdav: Option<WebDavConfig>,
+    // Backups started by plugging in the drive of a `UUID=` or `LABEL=` destination, disabled if missing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auto_backup: Option<AutoBackupConfig>,
+    // Named jobs of the `[[job]]` tables, sharing the settings above. Without any, the
+    // configuration is a single backup of `source_path`.
+    #[serde(default, rename = "job", skip_serializing_if = "Vec::is_empty")]
+    pub jobs: Vec<Job>,
+}
+
+/// A backup job: the folders it saves, where, and which of their files.
+/// The other settings, such as the storage format or the encryption, are shared by all the jobs.
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Job {
+    pub name: String,
+    pub sources: Vec<String>,
+    pub destination_path: String,
+    pub backup_type: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub extensions_to_backup: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub volumes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub filters: Vec<String>,
+}
+
+/// `[auto_backup]` section of config.toml: the jobs whose destination drive is plugged in are
+/// backed up by `backup_program`, without waiting for the gesture
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct AutoBackupConfig {
+    // Hours to wait after the latest snapshot of a job before plugging in its drive backs it up again
+    #[serde(default = "default_min_interval_hours")]
+    pub min_interval_hours: u32,
+    // Start the backup at once instead of waiting for the confirmation gesture
+    #[serde(default)]
+    pub auto_confirm: bool,
+}
+
+impl Job {
+    /// Configuration running the job, with the settings shared by the jobs taken from `shared`.
+    /// A job with a single source backs it up as `source_path` does.
+    pub(crate) fn config(&self, shared: &Config) -> Config {
+        let mut sources: Vec<String> = self.sources.iter()
+            .map(|source| source.trim().to_string())
+            .filter(|source| !source.is_empty())
+            .collect();
+        let source_path = if sources.len() == 1 { sources.remove(0) } else { String::new() };
+        Config {
+            name: self.name.trim().to_string(),
+            source_path,
+            sources,
+            destination_path: self.destination_path.clone(),
+            backup_type: self.backup_type.clone(),
+            extensions_to_backup: self.extensions_to_backup.clone(),
+            volumes: self.volumes.clone(),
+            filters: self.filters.clone(),
+            jobs: Vec::new(),
+            ..shared.clone()
+        }
+    }
+}
+
+impl Config {
+    /// Configuration of each job to run, with the settings shared by the jobs: the configuration
+    /// itself if it has no jobs.
+    pub fn job_configs(&self) -> Result<Vec<Config>, BackupError> {
+        if self.jobs.is_empty() {
+            return Ok(vec![self.clone()]);
+        }
+
+        let mut configs: Vec<Config> = Vec::new();
+        for job in &self.jobs {
+            // The name is the folder of the snapshots of the job in its destination
+            let name = job.name.trim();
+            if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+                return Err(BackupError::InvalidJob(format!("invalid job name \"{}\"", job.name)));
+            }
+            if configs.iter().any(|config| config.name == name) {
+                return Err(BackupError::InvalidJob(format!("two jobs are named \"{}\"", name)));
+            }
+
+            configs.push(job.config(self));
+        }
+        Ok(configs)
+    }
+
+    /// Configuration of the job named `name`, or of the only one when no name is given
+    pub fn job_config(&self, name: Option<&str>) -> Result<Config, BackupError> {
+        let mut configs = self.job_configs()?;
+        match name {
+            Some(name) => configs.into_iter()
+                .find(|config| config.name == name)
+                .ok_or_else(|| BackupError::UnknownJob(name.to_string())),
+            None if configs.len() == 1 => Ok(configs.remove(0)),
+            None => Err(BackupError::JobRequired),
+        }
+    }
+
+    /// Folders backed up: `sources`, or `source_path` if there are none
+    pub fn source_paths(&self) -> Vec<PathBuf> {
+        if self.sources.is_empty() {
+            vec![PathBuf::from(&self.source_path)]
+        } else {
+            self.sources.iter().map(PathBuf::from).collect()
+        }
+    }
+}
+
+pub(crate) fn default_storage_format() -> String {
+    "plain".to_string()
+}
+
+pub(crate) fn default_concurrency() -> usize {
+    4
+}
+
+pub(crate) fn default_space_check() -> String {
+    "refuse".to_string()
+}
+
+fn default_min_interval_hours() -> u32 {
+    24
 }
 
 pub fn read_config(config_path: &str) -> Config {
@@ -22,13 +219,60 @@ pub fn read_config(config_path: &str) -> Config {
 }
 
 #[derive(Debug)]
-pub(crate) enum BackupError {
+pub enum BackupError {
     SourceNotFound,
+    DestinationInsideSource,
+    DestinationBusy(LockOwner),
+    DriveNotConnected(String),
+    InsufficientSpace { needed: u64, available: u64 },
     InvalidBackupType,
+    InvalidImageSource,
+    InvalidStorageFormat,
+    InvalidCompression,
+    InvalidSpaceCheck,
+    InvalidFilter(String),
+    InvalidJob(String),
+    InvalidDestination(String),
+    UnknownJob(String),
+    JobRequired,
+    SnapshotNotFound,
+    InvalidRetentionPolicy(String),
+    WrongEncryptionKey,
+    MissingEncryptionKey,
     IoError(io::Error),
     FsExtraError(FsExtraError),
 }
 
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::SourceNotFound => write!(f, "Source path does not exist"),
+            BackupError::DestinationInsideSource => write!(f, "Destination is inside the source, the backup would copy itself"),
+            BackupError::DestinationBusy(owner) => write!(f, "Destination busy: another backup is writing to it ({})", owner),
+            BackupError::DriveNotConnected(drive) => write!(f, "Destination drive not connected: {}", drive),
+            BackupError::InsufficientSpace { needed, available } => {
+                write!(f, "Not enough free space on the destination: about {} bytes needed, {} bytes available", needed, available)
+            }
+            BackupError::InvalidBackupType => write!(f, "Invalid backup type specified"),
+            BackupError::InvalidImageSource => write!(f, "The source of an image backup must be a device, a partition or an image file"),
+            BackupError::InvalidStorageFormat => write!(f, "Invalid storage format specified"),
+            BackupError::InvalidCompression => write!(f, "Invalid compression mode or level specified"),
+            BackupError::InvalidSpaceCheck => write!(f, "Invalid free space check specified"),
+            BackupError::InvalidFilter(message) => write!(f, "Invalid filter pattern: {}", message),
+            BackupError::InvalidJob(message) => write!(f, "Invalid job: {}", message),
+            BackupError::InvalidDestination(message) => write!(f, "Invalid destination: {}", message),
+            BackupError::UnknownJob(name) => write!(f, "No job named \"{}\" in the configuration", name),
+            BackupError::JobRequired => write!(f, "The configuration has several jobs: choose one"),
+            BackupError::SnapshotNotFound => write!(f, "Snapshot not found"),
+            BackupError::InvalidRetentionPolicy(message) => write!(f, "Invalid retention policy: {}", message),
+            BackupError::WrongEncryptionKey => write!(f, "Wrong passphrase or key file for the encrypted backups"),
+            BackupError::MissingEncryptionKey => write!(f, "The backups are encrypted but no passphrase or key file is configured"),
+            BackupError::IoError(e) => write!(f, "IO error: {}", e),
+            BackupError::FsExtraError(e) => write!(f, "fs_extra error: {}", e),
+        }
+    }
+}
+
 impl From<io::Error> for BackupError {
     fn from(error: io::Error) -> Self {
         BackupError::IoError(error)
@@ -41,166 +285,1301 @@ impl From<FsExtraError> for BackupError {
     }
 }
 
-pub(crate) fn backup_files(config: &Config) -> Result<(), BackupError> {
-    let source_path = Path::new(&config.source_path);
-    let mut destination_path= PathBuf::from(&config.destination_path);
+/// Name of the report of a backup, saved in the metadata folder of its snapshot
+pub(crate) const REPORT_FILE: &str = "report.json";
 
-    // Check if source_path has a file_name
-    if let Some(source_folder_name) = source_path.file_name() {
-        destination_path.push(source_folder_name);
+/// What a backup did, returned by `run_job` and saved along with the snapshot
+#[derive(Debug, Default, serde::Serialize)]
+pub struct BackupReport {
+    pub snapshot_id: String,
+    // Job the backup was run for, empty for a configuration without jobs
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub job: String,
+    pub source_path: String,
+    // Files saved in the snapshot, including the ones linked to the previous snapshot or deduplicated
+    pub copied: usize,
+    // Files of `copied` already saved by an interrupted run, which this one resumed
+    pub resumed: usize,
+    // Entries left out because of the backup type, the filters, or because they are not regular files
+    pub skipped: usize,
+    pub failed: usize,
+    pub failures: Vec<BackupFailure>,
+    // Size of the files saved, and space actually taken by them on the destination
+    // once compressed, linked to a previous snapshot or deduplicated
+    pub bytes: u64,
+    pub stored_bytes: u64,
+    pub duration_secs: f64,
+    // Snapshots removed by the retention policy after the backup
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pruned: Vec<String>,
+    // Problems that did not stop the backup, such as a failed pruning
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+/// An entry of the source that could not be saved
+#[derive(Debug, serde::Serialize)]
+pub struct BackupFailure {
+    // Path relative to the source, with `/` as separator
+    pub path: String,
+    #[serde(serialize_with = "serialize_error_kind")]
+    pub kind: io::ErrorKind,
+    pub message: String,
+}
+
+impl BackupReport {
+    /// Count a file saved in the snapshot
+    pub(crate) fn add_file(&mut self, bytes: u64, stored_bytes: u64) {
+        self.copied += 1;
+        self.bytes += bytes;
+        self.stored_bytes += stored_bytes;
     }
 
-    println!("Backup started from: {:?}", source_path);
-    println!("Backup towards folder: {:?}", destination_path);
+    pub(crate) fn add_failure(&mut self, path: String, error: &io::Error) {
+        self.failed += 1;
+        self.failures.push(BackupFailure { path, kind: error.kind(), message: error.to_string() });
+    }
+}
 
-    // Total size of files copied
-    let mut total_size: u64 = 0;
+impl fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.job.is_empty() {
+            writeln!(f, "Job: {}", self.job)?;
+        }
+        writeln!(f, "Snapshot: {}", self.snapshot_id)?;
+        writeln!(f, "Files saved: {} ({} bytes, {} bytes used on destination)", self.copied, self.bytes, self.stored_bytes)?;
+        if self.resumed > 0 {
+            writeln!(f, "Resumed from an interrupted backup: {} files", self.resumed)?;
+        }
+        writeln!(f, "Skipped entries: {}", self.skipped)?;
+        writeln!(f, "Failed entries: {}", self.failed)?;
+        for failure in &self.failures {
+            writeln!(f, "  {}  ({:?}: {})", failure.path, failure.kind, failure.message)?;
+        }
+        for snapshot_id in &self.pruned {
+            writeln!(f, "Pruned snapshot: {}", snapshot_id)?;
+        }
+        for warning in &self.warnings {
+            writeln!(f, "Warning: {}", warning)?;
+        }
+        writeln!(f, "Completed in: {:.2} seconds", self.duration_secs)
+    }
+}
+
+/// `io::ErrorKind` has no serde support: its name is saved instead, e.g. "PermissionDenied"
+fn serialize_error_kind<S: serde::Serializer>(kind: &io::ErrorKind, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(&format_args!("{:?}", kind))
+}
+
+/// Result of `run_job`
+#[derive(Debug)]
+pub enum BackupOutcome {
+    // Dry run: what the backup would do, nothing has been written
+    Planned(BackupPlan),
+    Completed(BackupReport),
+}
+
+/// Files waiting in the copy queue for each worker, before the walk waits for them
+const COPY_QUEUE_PER_WORKER: usize = 4;
+
+/// Number of files listed in the largest files of a plan
+const PLAN_LARGEST_FILES: usize = 10;
+
+/// Why an entry of the source is left out of a backup
+#[derive(Debug, Clone)]
+pub enum SkipReason {
+    Hidden,
+    SystemDirectory,
+    // The destination nested inside the source
+    BackupDestination,
+    ExcludedByFilters,
+    // Symlinks, sockets, devices...
+    NotRegularFile,
+    Unreadable(String),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Hidden => write!(f, "hidden"),
+            SkipReason::SystemDirectory => write!(f, "system directory"),
+            SkipReason::BackupDestination => write!(f, "backup destination"),
+            SkipReason::ExcludedByFilters => write!(f, "excluded by filters"),
+            SkipReason::NotRegularFile => write!(f, "not a regular file"),
+            SkipReason::Unreadable(e) => write!(f, "unreadable: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SkippedEntry {
+    pub path: String,
+    pub reason: SkipReason,
+}
+
+/// What a backup would do, returned by a dry run
+#[derive(Debug, Default)]
+pub struct BackupPlan {
+    // Files that would be saved, and their total size
+    pub files: usize,
+    pub bytes: u64,
+    // Estimate of the space the files would take on the destination: the files unchanged since the
+    // previous snapshot take none when they are linked to it or deduplicated
+    pub needed_bytes: u64,
+    // Free space on the destination, if it could be found
+    pub available_bytes: Option<u64>,
+    // Largest files first, with their size
+    pub largest_files: Vec<(String, u64)>,
+    pub skipped: Vec<SkippedEntry>,
+}
+
+impl fmt::Display for BackupPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Files to back up: {}", self.files)?;
+        writeln!(f, "Total size: {} bytes", self.bytes)?;
+        writeln!(f, "Space needed on destination: about {} bytes", self.needed_bytes)?;
+        if let Some(available) = self.available_bytes {
+            writeln!(f, "Free space on destination: {} bytes", available)?;
+        }
+        if !self.largest_files.is_empty() {
+            writeln!(f, "Largest files:")?;
+            for (path, size) in &self.largest_files {
+                writeln!(f, "  {:>14}  {}", size, path)?;
+            }
+        }
+        writeln!(f, "Skipped entries: {}", self.skipped.len())?;
+        for skipped in &self.skipped {
+            writeln!(f, "  {}  ({})", skipped.path, skipped.reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// Format of the snapshot ids, used as directory names under `<destination>/<source-name>/`.
+/// No colons, so that the ids are valid folder names on Windows too.
+const SNAPSHOT_ID_FORMAT: &str = "%Y-%m-%d_%H-%M-%S";
+
+/// Hidden folder inside each snapshot holding the files written by BackMeUp itself.
+/// Hidden files are never backed up, so it cannot collide with the source content.
+pub(crate) const SNAPSHOT_METADATA_DIR: &str = ".backmeup";
+
+/// Name of the pointer to the most recent snapshot inside the series folder.
+pub(crate) const LATEST_SNAPSHOT: &str = "latest";
+
+/// A job run by `backup_files`, with its outcome
+#[derive(Debug)]
+pub struct JobRun {
+    // Empty for a configuration without jobs
+    pub name: String,
+    pub result: Result<BackupOutcome, BackupError>,
+}
+
+/// Run the job named `job`, or all the jobs of the configuration in order: a configuration
+/// without jobs is a single backup. A failed job does not stop the next ones, its error is
+/// returned along with the outcome of the others.
+pub fn backup_files(config: &Config, job: Option<&str>, dry_run: bool) -> Result<Vec<JobRun>, BackupError> {
+    let configs = match job {
+        Some(_) => vec![config.job_config(job)?],
+        None => config.job_configs()?,
+    };
+
+    let mut runs = Vec::new();
+    for config in configs {
+        if !config.name.is_empty() {
+            println!("Running job: {}", config.name);
+        }
+        let result = run_job(&config, dry_run);
+        runs.push(JobRun { name: config.name, result });
+    }
+    Ok(runs)
+}
+
+/// Back up the sources of a job, or of a configuration without jobs, into a new snapshot.
+/// Problems with single entries do not stop the backup: they are listed in the returned report.
+/// With `dry_run` the sources are walked and filtered but nothing is written: the plan of the
+/// backup is returned instead.
+pub(crate) fn run_job(config: &Config, dry_run: bool) -> Result<BackupOutcome, BackupError> {
+    // A destination drive that is not connected stops the backup before anything is written
+    let series_path = series_path(config)?;
 
     let start_time = Instant::now();
 
-    // Check if source path exists
-    if !source_path.exists() {
+    if !["full-disk", "directory", "selective", "image"].contains(&config.backup_type.as_str()) {
+        return Err(BackupError::InvalidBackupType);
+    }
+    // An image backup reads the source as a whole instead of walking it
+    let image = config.backup_type == "image";
+
+    // Select the files based on the backup type and the filters.
+    // A full-disk backup walks each volume selected instead of the source folders.
+    let roots = source_roots(config)?;
+    for root in &roots {
+        println!("Backup started from: {:?}", root.path);
+    }
+
+    // Check if the source paths exist
+    if roots.is_empty() || roots.iter().any(|root| !root.path.exists()) {
         return Err(BackupError::SourceNotFound);
     }
 
-    // Create destination directory if it doesn't exist
-    if !destination_path.exists() {
-        fs::create_dir_all(destination_path.as_path())?;
-        println!("Created destination directory: {:?}", destination_path.as_path());
+    // An image is read from a single device or file
+    if image && (!config.sources.is_empty() || Path::new(&config.source_path).is_dir()) {
+        return Err(BackupError::InvalidImageSource);
+    }
+
+    // A destination inside the source would be walked while it is written
+    check_destination(config)?;
+
+    if !["plain", "repository"].contains(&config.storage_format.as_str()) {
+        return Err(BackupError::InvalidStorageFormat);
+    }
+
+    if !config.compression.is_valid() {
+        return Err(BackupError::InvalidCompression);
+    }
+
+    if !["refuse", "warn"].contains(&config.space_check.as_str()) {
+        return Err(BackupError::InvalidSpaceCheck);
+    }
+
+    // The files of a remote destination are written straight into it, and the metadata of the
+    // snapshots in its working copy, then uploaded
+    let backend = storage::open_backend(config)?;
+
+    // Another backup writing to the same destination stops this one before anything is written.
+    // The lock is held until the backup returns.
+    let _lock = if dry_run { None } else { Some(DestinationLock::acquire(series_path.as_path())?) };
+
+    // The working copy may be missing the key and the latest snapshots, such as on another computer
+    if let Some(backend) = backend.as_deref().filter(|_| !dry_run) {
+        storage::sync_series(backend, series_path.as_path())?;
+    }
+
+    // The previous snapshot is used to skip unchanged files, if there is one
+    let previous_snapshot = latest_snapshot(series_path.as_path()).map(|id| series_path.join(id));
+
+    // Every run gets its own snapshot folder, so the previous backups are never overwritten
+    let snapshot_id = new_snapshot_id(series_path.as_path());
+    let destination_path = series_path.join(&snapshot_id);
+    println!("Backup towards folder: {:?}", destination_path);
+
+    // A wrong passphrase stops the backup before anything is written
+    let cipher = encryption::load_cipher(config, series_path.as_path(), !dry_run)?;
+    let cipher = cipher.as_ref();
+
+    let mut report = BackupReport {
+        snapshot_id: snapshot_id.clone(),
+        job: config.name.clone(),
+        source_path: roots.iter().map(|root| root.path.display().to_string()).collect::<Vec<_>>().join(", "),
+        ..BackupReport::default()
+    };
+
+    // Files can only be shared with a previous snapshot encrypted the same way
+    let previous_snapshot = previous_snapshot.filter(|path| encryption::is_encrypted_snapshot(path) == cipher.is_some());
+
+    // The plan of the backup: the files selected are the same whatever the storage format.
+    // Before a backup it gives the space needed: the unchanged files are not counted when they
+    // will be linked to the previous snapshot or deduplicated. A remote destination has no links:
+    // the unchanged files are copied there, or sent again.
+    let reuses_previous = config.storage_format == "repository"
+        || (config.incremental && config.compression.mode != "tar-zst" && backend.is_none());
+    let available_bytes = match &backend {
+        Some(backend) => backend.available_space(),
+        None => available_space(series_path.as_path()),
+    };
+    let mut plan = BackupPlan { available_bytes, ..BackupPlan::default() };
+    let mut sources = WalkedSources::default();
+    if image {
+        image::plan_image(Path::new(&config.source_path), &mut plan)?;
+    } else {
+        // The sources are walked once: the entries listed by the plan are the ones then saved
+        check_roots(&roots)?;
+        let walk = SourceWalk::new(&roots, config.follow_symlinks, dry_run);
+        sources = plan_backup(walk, previous_snapshot.as_deref().filter(|_| reuses_previous), cipher, &mut plan);
+    }
+    if dry_run {
+        return Ok(BackupOutcome::Planned(plan));
+    }
+
+    // A backup that cannot fit is refused before anything is written, unless only a warning is wanted
+    if let Some(warning) = check_space(&plan, &config.space_check)? {
+        println!("{}", warning);
+        report.warnings.push(warning);
+    }
+
+    // The snapshot is written in the staging folder, and only moved into the series once complete.
+    // A snapshot left there by an interrupted backup is resumed, keeping its id.
+    if let Some(unfinished_id) = staging::unfinished_snapshot(series_path.as_path(), config, backend.as_deref(), cipher)? {
+        println!("Resuming the interrupted snapshot: {}", unfinished_id);
+        report.snapshot_id = unfinished_id;
+    }
+    let snapshot_id = report.snapshot_id.clone();
+    let destination_path = series_path.join(&snapshot_id);
+    let staged_path = staging::staged_path(series_path.as_path(), &snapshot_id);
+
+    // Create the staged snapshot directory if it doesn't exist
+    if !staged_path.exists() {
+        fs::create_dir_all(staged_path.as_path())?;
+        println!("Created staging directory: {:?}", staged_path.as_path());
+    }
+    if cipher.is_some() {
+        encryption::mark_encrypted(staged_path.as_path())?;
+    }
+    let remote = backend.as_deref().map(|backend| RemoteSnapshot::new(backend, series_path.as_path(), &snapshot_id, previous_snapshot.as_deref()));
+    let remote = remote.as_ref();
+
+    // Perform backup based on the backup type and the storage format
+    let mut manifest = Manifest::new(&snapshot_id, &config.source_path);
+    // Restoring a snapshot of several folders puts each one back at its original location
+    manifest.roots = roots.iter()
+        .filter(|root| !root.name.is_empty())
+        .map(|root| (root.name.clone(), root.path.display().to_string()))
+        .collect();
+    if image {
+        image::backup_image(
+            Path::new(&config.source_path),
+            staged_path.as_path(),
+            remote,
+            &config.compression,
+            cipher,
+            &mut manifest,
+            &mut report,
+        )?;
+    } else if config.storage_format == "repository" {
+        repository::backup_with_chunks(
+            sources,
+            series_path.as_path(),
+            staged_path.as_path(),
+            remote,
+            previous_snapshot.as_deref(),
+            config.follow_symlinks,
+            &config.compression,
+            cipher,
+            &mut manifest,
+            &mut report,
+        )?;
+    } else if config.compression.mode == "tar-zst" {
+        compression::backup_to_archive(
+            sources,
+            staged_path.as_path(),
+            remote,
+            config.follow_symlinks,
+            config.compression.level,
+            cipher,
+            &mut manifest,
+            &mut report,
+        )?;
+    } else {
+        // In incremental mode unchanged files are linked to the previous snapshot
+        // The journal of the files completed lets an interrupted backup resume
+        let link_dest = previous_snapshot.as_deref().filter(|_| config.incremental);
+        let mut journal = Journal::open(staged_path.as_path(), cipher)?;
+        backup_with_walkdir(
+            sources,
+            staged_path.as_path(),
+            remote,
+            link_dest,
+            config,
+            cipher,
+            &mut manifest,
+            &mut report,
+            Some(&mut journal),
+        )?;
+        if let Some(e) = journal.error() {
+            report.warnings.push(format!("The journal could not be written, an interrupted backup would start over: {}", e));
+        }
+    }
+
+    // Checksums and metadata of the entries saved, used to verify and restore the snapshot later
+    manifest::write_manifest(staged_path.as_path(), &manifest, cipher)?;
+    if let Some(remote) = remote {
+        remote.save_etags(staged_path.as_path())?;
+    }
+
+    // The snapshot is complete: it appears in the series at once
+    staging::commit(series_path.as_path(), &snapshot_id)?;
+
+    update_latest_snapshot(series_path.as_path(), &snapshot_id)?;
+
+    report.duration_secs = start_time.elapsed().as_secs_f64();
+    backup_monitor(series_path.as_path(), &report);
+
+    // The backup succeeded: apply the retention policy to the older snapshots
+    if let Some(retention) = &config.retention {
+        match retention::prune(series_path.as_path(), retention, false, cipher) {
+            Ok(decisions) => {
+                prune_monitor(series_path.as_path(), &decisions);
+                report.pruned = decisions.into_iter().filter(|d| !d.keep).map(|d| d.snapshot_id).collect();
+            }
+            Err(e) => report.warnings.push(format!("Pruning of old snapshots failed: {}", e)),
+        }
+    }
+
+    write_report(destination_path.as_path(), &report, cipher)?;
+
+    // The metadata of the snapshot stays in the working copy when the upload fails, and the next
+    // backup uploads it
+    if let Some(backend) = &backend {
+        if let Err(e) = storage::push_series(backend.as_ref(), series_path.as_path(), &report.pruned, cipher) {
+            let warning = format!("Upload to {} failed, the next backup will upload the snapshot: {}", config.destination_path, e);
+            println!("{}", warning);
+            report.warnings.push(warning);
+            write_report(destination_path.as_path(), &report, cipher)?;
+        }
     }
 
-    let mut dir_options = CopyOptions::new();
-    dir_options.overwrite = true;
+    Ok(BackupOutcome::Completed(report))
+}
+
+/// Save the report of a backup in its snapshot, encrypted like the manifest, as the failures name
+/// files of the source
+fn write_report(snapshot_path: &Path, report: &BackupReport, cipher: Option<&Cipher>) -> io::Result<()> {
+    let report_json = serde_json::to_vec_pretty(report).map_err(io::Error::other)?;
+    encryption::write_file(&snapshot_path.join(SNAPSHOT_METADATA_DIR).join(REPORT_FILE), &report_json, cipher)
+}
+
+/// Folder holding all the snapshots of the configured source: `<destination>/<source-name>/`.
+/// The snapshots of a full-disk backup are named after the computer: `<destination>/<host-name>/`,
+/// and the ones of a named job after the job: `<destination>/<job-name>/`.
+pub fn series_path(config: &Config) -> Result<PathBuf, BackupError> {
+    let mut series_path = storage::local_path(config)?;
 
-    // Perform backup based on the backup type
-    match config.backup_type.as_str() {
-        "full-disk" | "directory" => {
-            // Perform backup and calculate total size
-            total_size = backup_with_walkdir(source_path, destination_path.as_path(), None)?;
-        },
-        "selective" => {
-            // Copy files with specific extensions and calculate total size
-            total_size = backup_with_walkdir(source_path, destination_path.as_path(), Some(&config.extensions_to_backup))?;
-        },
-        _ => return Err(BackupError::InvalidBackupType),
+    if !config.name.is_empty() {
+        series_path.push(&config.name);
+    } else if config.backup_type == "full-disk" {
+        series_path.push(sysinfo::System::host_name().unwrap_or_else(|| "full-disk".to_string()));
+    } else if let Some(source_folder_name) = Path::new(&config.source_path).file_name() {
+        // Check if source_path has a file_name
+        series_path.push(source_folder_name);
     }
 
-    let backup_time = start_time.elapsed();
-    backup_monitor(destination_path.as_path(), total_size, backup_time);
+    Ok(series_path)
+}
+
+/// A folder walked by a backup: the source folder, one of the sources of a job or one of the
+/// volumes of a full-disk backup
+pub(crate) struct SourceRoot {
+    // Folder of the snapshot the root is saved in, empty when it is saved as the whole snapshot
+    pub name: String,
+    pub path: PathBuf,
+    // Other filesystems mounted inside the root are not walked
+    pub one_file_system: bool,
+    pub filter: EntryFilter,
+}
+
+impl SourceRoot {
+    /// Path of an entry of the root in the manifest, with `/` as separator, under the folder of the root
+    pub(crate) fn manifest_path(&self, path: &Path) -> String {
+        let relative = path.strip_prefix(&self.path).map(repository::index_path).unwrap_or_else(|_| path.display().to_string());
+        if self.name.is_empty() {
+            relative
+        } else if relative.is_empty() {
+            self.name.clone()
+        } else {
+            format!("{}/{}", self.name, relative)
+        }
+    }
+}
+
+/// Folders walked by a backup: the sources, or for a full-disk backup the mount points of the
+/// volumes selected, or of the volumes holding the sources if none is.
+fn root_paths(config: &Config) -> Vec<PathBuf> {
+    if config.backup_type != "full-disk" {
+        return config.source_paths();
+    }
+    if config.volumes.is_empty() {
+        let mut mount_points: Vec<PathBuf> = Vec::new();
+        for mount_point in config.source_paths().iter().filter_map(|source| volumes::volume_of(source)) {
+            if !mount_points.contains(&mount_point) {
+                mount_points.push(mount_point);
+            }
+        }
+        return mount_points;
+    }
+    config.volumes.iter().map(PathBuf::from).collect()
+}
+
+/// Folders walked by a backup, with their filter. A single source is saved as the whole
+/// snapshot. Otherwise each source is saved in its own folder of the snapshot, named after the
+/// source folder, and each volume of a full-disk backup in a folder named after its mount point,
+/// the walk staying on the volume.
+pub(crate) fn source_roots(config: &Config) -> Result<Vec<SourceRoot>, BackupError> {
+    let full_disk = config.backup_type == "full-disk";
+    let mut roots: Vec<SourceRoot> = Vec::new();
+
+    for path in root_paths(config) {
+        let mut name = String::new();
+        if full_disk || !config.sources.is_empty() {
+            // Two folders can give the same name, such as `/mnt/data` and `/mnt_data`, or `~/data` and `/srv/data`
+            let base = match path.file_name() {
+                Some(file_name) if !full_disk => file_name.to_string_lossy().to_string(),
+                _ => volumes::subtree_name(&path),
+            };
+            name = base.clone();
+            let mut counter = 2;
+            while roots.iter().any(|root| root.name == name) {
+                name = format!("{}-{}", base, counter);
+                counter += 1;
+            }
+        }
+        let filter = EntryFilter::new(config, &path)?;
+        roots.push(SourceRoot { name, path, one_file_system: full_disk, filter });
+    }
+
+    Ok(roots)
+}
+
+/// Part of the destination lying inside `source`, relative to it: the destination itself, or
+/// only the series folder when the destination is a parent of the source.
+/// Paths are compared in their canonical form, so that symlinks and `..` cannot hide the nesting.
+/// A full-disk backup does not leave the volumes it saves, so a destination on another
+/// filesystem mounted inside one of them is not nested.
+pub(crate) fn nested_destination(config: &Config, source: &Path) -> Option<PathBuf> {
+    let source = fs::canonicalize(source).ok()?;
+    [storage::local_path(config).ok()?, series_path(config).ok()?]
+        .iter()
+        .map(|path| canonical_path(path))
+        .filter(|path| config.backup_type != "full-disk" || same_file_system(&source, path))
+        .find_map(|path| path.strip_prefix(&source).ok().map(Path::to_path_buf))
+}
+
+/// Part of the destination lying inside each folder walked by the backup, for the folders it lies in
+pub(crate) fn nested_destinations(config: &Config) -> Vec<PathBuf> {
+    root_paths(config).iter().filter_map(|source| nested_destination(config, source)).collect()
+}
+
+/// Refuse a destination inside the source, unless nesting is allowed in the configuration.
+/// Even then the snapshots cannot be written in the source folder itself.
+pub(crate) fn check_destination(config: &Config) -> Result<(), BackupError> {
+    let refused = |nested: &PathBuf| !config.allow_nested_destination || nested.as_os_str().is_empty();
+    if nested_destinations(config).iter().any(refused) {
+        return Err(BackupError::DestinationInsideSource);
+    }
     Ok(())
 }
 
-fn backup_monitor(destination_path: &Path, total_size: u64, backup_time: Duration) {
-    let log_path = destination_path.join("backup_log.txt");
+/// Check whether `path`, which may not exist yet, is on the filesystem of the folder `root`:
+/// its nearest existing parent is
+#[cfg(unix)]
+fn same_file_system(root: &Path, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |path: &Path| fs::metadata(path).map(|metadata| metadata.dev()).ok();
+    let existing = path.ancestors().find(|ancestor| ancestor.exists());
+    match (device(root), existing.and_then(device)) {
+        (Some(root), Some(path)) => root == path,
+        _ => true,
+    }
+}
+
+/// Filesystems cannot be told apart here: every path is taken to be on the one of the root
+#[cfg(not(unix))]
+fn same_file_system(_root: &Path, _path: &Path) -> bool {
+    true
+}
+
+/// Free space on the filesystem holding `path`, which may not exist yet: the one holding its
+/// nearest existing parent.
+/// `statvfs` is used on Unix, as the list of disks leaves out network and memory filesystems.
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // the types of the fields differ between platforms
+pub(crate) fn available_space(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = canonical_path(path);
+    let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+    let c_path = std::ffi::CString::new(existing.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Free space on the disk mounted on the longest prefix of `path`, if any
+#[cfg(not(unix))]
+pub(crate) fn available_space(path: &Path) -> Option<u64> {
+    let path = canonical_path(path);
+    let disks = sysinfo::Disks::new_with_refreshed_list();
+    disks.list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())
+        .map(|disk| disk.available_space())
+}
+
+/// Canonical form of a path that may not exist yet, such as a destination not created yet:
+/// the existing components are resolved one at a time and the missing ones appended as they are.
+fn canonical_path(path: &Path) -> PathBuf {
+    let mut canonical = if path.is_relative() { env::current_dir().unwrap_or_default() } else { PathBuf::new() };
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            // The path so far is resolved, so its parent is the real one
+            Component::ParentDir => {
+                canonical.pop();
+            }
+            component => {
+                canonical.push(component);
+                if let Ok(resolved) = fs::canonicalize(&canonical) {
+                    canonical = resolved;
+                }
+            }
+        }
+    }
+
+    canonical
+}
+
+/// Build the id of a new snapshot from the current time.
+/// If two backups start within the same second, a counter is appended to keep the ids unique.
+fn new_snapshot_id(series_path: &Path) -> String {
+    let timestamp = Local::now().format(SNAPSHOT_ID_FORMAT).to_string();
+    let mut snapshot_id = timestamp.clone();
+    let mut counter = 1;
+
+    while series_path.join(&snapshot_id).exists() {
+        snapshot_id = format!("{}-{}", timestamp, counter);
+        counter += 1;
+    }
+
+    snapshot_id
+}
+
+/// Point `latest` to the given snapshot.
+/// On Unix it is a relative symlink, replaced atomically through a rename.
+/// On Windows, where symlinks need extra privileges, it is a text file holding the snapshot id.
+pub(crate) fn update_latest_snapshot(series_path: &Path, snapshot_id: &str) -> io::Result<()> {
+    let latest_path = series_path.join(LATEST_SNAPSHOT);
+
+    #[cfg(unix)]
+    {
+        let tmp_path = series_path.join(format!("{}.tmp", LATEST_SNAPSHOT));
+        if fs::symlink_metadata(&tmp_path).is_ok() {
+            fs::remove_file(&tmp_path)?;
+        }
+        std::os::unix::fs::symlink(snapshot_id, &tmp_path)?;
+        fs::rename(&tmp_path, &latest_path)
+    }
+
+    #[cfg(not(unix))]
+    {
+        fs::write(&latest_path, snapshot_id)
+    }
+}
+
+/// Time a snapshot was started at, parsed from its id
+pub fn snapshot_time(snapshot_id: &str) -> Option<NaiveDateTime> {
+    let timestamp = snapshot_id.get(..19)?;
+    NaiveDateTime::parse_from_str(timestamp, SNAPSHOT_ID_FORMAT).ok()
+}
+
+/// Ids of all the snapshots of a series, from the oldest to the newest
+pub(crate) fn list_snapshots(series_path: &Path) -> Vec<String> {
+    let snapshots: Vec<String> = match fs::read_dir(series_path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+
+    sort_snapshots(snapshots)
+}
+
+/// Sort snapshot ids from the oldest to the newest
+pub(crate) fn sort_snapshots(snapshot_ids: Vec<String>) -> Vec<String> {
+    let mut snapshots: Vec<(NaiveDateTime, String)> = snapshot_ids.into_iter()
+        .filter_map(|snapshot_id| snapshot_time(&snapshot_id).map(|time| (time, snapshot_id)))
+        .collect();
+
+    // Ids created within the same second only differ by their counter
+    snapshots.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.len().cmp(&b.1.len())).then(a.1.cmp(&b.1)));
+    snapshots.into_iter().map(|(_, snapshot_id)| snapshot_id).collect()
+}
+
+/// Id of the snapshot `latest` points to, if any backup has been completed yet.
+pub fn latest_snapshot(series_path: &Path) -> Option<String> {
+    let latest_path = series_path.join(LATEST_SNAPSHOT);
+
+    let snapshot_id = match fs::read_link(&latest_path) {
+        Ok(target) => target.file_name()?.to_string_lossy().to_string(),
+        Err(_) => fs::read_to_string(&latest_path).ok()?.trim().to_string(),
+    };
+
+    if series_path.join(&snapshot_id).is_dir() {
+        Some(snapshot_id)
+    } else {
+        None
+    }
+}
+
+fn backup_monitor(series_path: &Path, report: &BackupReport) {
+    let log_path = series_path.join("backup_log.txt");
     let mut file = fs::OpenOptions::new()
         .create(true)
         .append(true)
         .open(log_path)
         .unwrap();
 
-    writeln!(file, "Total size of saved files: {} bytes", total_size).unwrap();
-    writeln!(file, "Backup completed in: {:.2} seconds", backup_time.as_secs_f64()).unwrap();
+    if !report.job.is_empty() {
+        writeln!(file, "Job: {}", report.job).unwrap();
+    }
+    writeln!(file, "Snapshot: {}", report.snapshot_id).unwrap();
+    writeln!(file, "Files saved: {}, skipped: {}, failed: {}", report.copied, report.skipped, report.failed).unwrap();
+    writeln!(file, "Total size of saved files: {} bytes", report.bytes).unwrap();
+    writeln!(file, "Space used on destination: {} bytes", report.stored_bytes).unwrap();
+    writeln!(file, "Backup completed in: {:.2} seconds", report.duration_secs).unwrap();
 }
 
-fn backup_with_walkdir<P: AsRef<Path>, Q: AsRef<Path>>(
-    source: P,
-    destination: Q,
-    extensions_to_backup: Option<&Vec<String>>,
-) -> io::Result<u64> {
-    let source = source.as_ref();
-    let destination = destination.as_ref();
+fn prune_monitor(series_path: &Path, decisions: &[retention::PruneDecision]) {
+    let log_path = series_path.join("backup_log.txt");
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .unwrap();
 
-    if !source.exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::NotFound,
-            format!("Percorso non trovato: {}", source.display()),
-        ));
+    for decision in decisions.iter().filter(|d| !d.keep) {
+        writeln!(file, "Pruned snapshot: {}", decision.snapshot_id).unwrap();
     }
+}
+
+/// A file to copy, handed by the walk to the copy workers
+struct CopyJob {
+    source: PathBuf,
+    destination: PathBuf,
+    // Path of the copy relative to the snapshot
+    stored_name: PathBuf,
+    // zstd level if the file is compressed
+    level: Option<i32>,
+    manifest_path: String,
+    metadata: fs::Metadata,
+    entry_metadata: EntryMetadata,
+}
+
+/// A file copied by a worker, with its size, the size written and the hash, or the error
+struct CopiedFile {
+    job: CopyJob,
+    result: io::Result<(u64, u64, String)>,
+}
 
-    if !source.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!("Il percorso non è una directory: {}", source.display()),
-        ));
+/// Make the plan of a backup from the entries of the sources: the files that would be saved and
+/// the entries skipped. The previous snapshot only tells which files would take no new space.
+/// Returns the entries walked, for the backup to save them.
+fn plan_backup<'a>(mut sources: SourceWalk<'a>, previous_snapshot: Option<&Path>, cipher: Option<&Cipher>, plan: &mut BackupPlan) -> WalkedSources<'a> {
+    let previous_entries = previous_snapshot.map(|path| manifest::manifest_entries(path, cipher)).unwrap_or_default();
+    let mut entries = Vec::new();
+
+    for (root, entry) in &mut sources {
+        match &entry {
+            Err(e) => {
+                let path = e.path().map(|path| root.manifest_path(path)).unwrap_or_default();
+                plan.skipped.push(SkippedEntry { path, reason: SkipReason::Unreadable(e.to_string()) });
+            }
+            Ok(entry) if entry.file_type().is_file() => {
+                let manifest_path = root.manifest_path(entry.path());
+                match entry.metadata() {
+                    Ok(metadata) => {
+                        plan.files += 1;
+                        plan.bytes += metadata.len();
+                        // Same size and modification time as in the previous snapshot: no new space
+                        let unchanged = previous_entries.get(&manifest_path).is_some_and(|previous| {
+                            previous.size == metadata.len() && metadata.modified().map(unix_seconds).ok() == Some(previous.modified)
+                        });
+                        if !unchanged {
+                            plan.needed_bytes += metadata.len();
+                        }
+                        // Only the largest files are kept, whatever the number of files
+                        plan.largest_files.push((manifest_path, metadata.len()));
+                        if plan.largest_files.len() > 2 * PLAN_LARGEST_FILES {
+                            plan.largest_files.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+                            plan.largest_files.truncate(PLAN_LARGEST_FILES);
+                        }
+                    }
+                    Err(e) => plan.skipped.push(SkippedEntry { path: manifest_path, reason: SkipReason::Unreadable(e.to_string()) }),
+                }
+            }
+            Ok(entry) if entry.file_type().is_symlink() => plan.files += 1,
+            Ok(entry) if !entry.file_type().is_dir() => {
+                plan.skipped.push(SkippedEntry { path: root.manifest_path(entry.path()), reason: SkipReason::NotRegularFile });
+            }
+            Ok(_) => {}
+        }
+        entries.push((root, entry));
     }
 
-    // Creates the destination directory if it doesn't exist
-    if let Err(e) = fs::create_dir_all(destination) {
-        eprintln!(
-            "Errore durante la creazione della directory {}: {}. Ignorata.",
-            destination.display(),
-            e
-        );
+    plan.skipped.extend(sources.skipped_entries.take().unwrap_or_default());
+    plan.largest_files.sort_by_key(|(_, size)| std::cmp::Reverse(*size));
+    plan.largest_files.truncate(PLAN_LARGEST_FILES);
+    WalkedSources { entries, skipped: sources.skipped }
+}
+
+/// Warning to report when the destination may be too small for the plan of a backup, or an
+/// error when the `space_check` policy is to refuse the backup
+fn check_space(plan: &BackupPlan, space_check: &str) -> Result<Option<String>, BackupError> {
+    let Some(available) = plan.available_bytes.filter(|available| *available < plan.needed_bytes) else {
+        return Ok(None);
+    };
+    if space_check == "refuse" {
+        return Err(BackupError::InsufficientSpace { needed: plan.needed_bytes, available });
     }
+    Ok(Some(format!("The destination may be too small: about {} bytes needed, {} bytes available", plan.needed_bytes, available)))
+}
+
+/// Copy the entries of the sources into `destination`, each root in its folder.
+/// The folders, symlinks and incremental links are created in the order of the walk, and the
+/// files to copy are queued for `config.concurrency` workers: a folder always exists before the
+/// files inside it are queued. The queue is bounded, so that only a few files wait for the
+/// workers at a time.
+/// Symlinks are kept as symlinks unless `config.follow_symlinks` is set, and the metadata of
+/// every entry saved is added to the manifest. The files saved, the entries skipped and the
+/// ones that could not be saved are counted in the report.
+/// With a journal, the entries saved are recorded in it and the ones an interrupted run already
+/// saved are kept instead of being saved again.
+/// For a remote destination the files are written straight into it, and the unchanged ones
+/// copied there from the previous snapshot when the backend can. Folders only exist there through
+/// their files, and symlinks through the manifest.
+#[allow(clippy::too_many_arguments)]
+fn backup_with_walkdir<Q: AsRef<Path>>(
+    sources: WalkedSources,
+    destination: Q,
+    remote: Option<&RemoteSnapshot>,
+    previous_snapshot: Option<&Path>,
+    config: &Config,
+    cipher: Option<&Cipher>,
+    manifest: &mut Manifest,
+    report: &mut BackupReport,
+    mut journal: Option<&mut Journal>,
+) -> io::Result<()> {
+    let destination = destination.as_ref();
+    let follow_symlinks = config.follow_symlinks;
+    let resuming = journal.as_ref().is_some_and(|journal| journal.is_resuming());
 
-    let mut total_size = 0;
+    // Creates the destination directory if it doesn't exist
+    fs::create_dir_all(destination)?;
 
-    for entry in WalkDir::new(source)
-        .into_iter()
-        .filter_entry(|e| !is_hidden_or_problematic(e))            // Ignore hidden files and problematic directories
-    {
-        let entry = match entry {
-            Ok(e) => e,
-            Err(e) => {
-                eprintln!("Errore durante l'accesso a un elemento: {}. Ignorato.", e);
-                continue;
+    // Checksums of the previous snapshot, reused for the files linked to it
+    let previous_entries = previous_snapshot.map(|path| manifest::manifest_entries(path, cipher)).unwrap_or_default();
+
+    let workers = config.concurrency.max(1);
+    let (jobs, queue) = mpsc::sync_channel::<CopyJob>(workers * COPY_QUEUE_PER_WORKER);
+    let queue = Mutex::new(queue);
+    let (copied, results) = mpsc::channel::<CopiedFile>();
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            let queue = &queue;
+            let copied = copied.clone();
+            scope.spawn(move || loop {
+                // The lock is only held while waiting for the next job
+                let job = queue.lock().unwrap().recv();
+                let Ok(job) = job else { break };
+                let stored_name = match job.level {
+                    Some(_) => compression::compressed_file_path(&job.stored_name),
+                    None => job.stored_name.clone(),
+                };
+                let result = save_file(&job.source, destination, &stored_name, remote, job.level, cipher);
+                // A partial copy would only take space, such as the space left on a full destination.
+                // A remote object is only written once complete.
+                if result.is_err() && remote.is_none() {
+                    let _ = fs::remove_file(destination.join(&stored_name));
+                }
+                if copied.send(CopiedFile { job, result }).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(copied);
+
+        for (root, entry) in sources.entries {
+            // Collect the copies done so far, so that the results do not pile up
+            for copied_file in results.try_iter() {
+                record_copy(copied_file, manifest, report, journal.as_deref_mut());
             }
-        };
 
-        let entry_path = entry.path();
-        println!("Elaborando: {:?}", entry_path);
-        let relative_path = match entry_path.strip_prefix(source) {
-            Ok(rel) => rel,
-            Err(_) => {
-                eprintln!(
-                    "Errore nel calcolo del percorso relativo per: {}. Ignorato.",
-                    entry_path.display()
-                );
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    let path = e.path().map(|path| root.manifest_path(path)).unwrap_or_default();
+                    report.add_failure(path, &io::Error::from(e));
+                    continue;
+                }
+            };
+
+            let entry_path = entry.path();
+            if !entry_path.starts_with(&root.path) {
                 continue;
             }
-        };
 
-        let dest_path = destination.join(relative_path);
-
-        if entry.file_type().is_dir() {
-            // Crea la directory di destinazione
-            if let Err(e) = fs::create_dir_all(&dest_path) {
-                eprintln!(
-                    "Errore durante la creazione della directory {}: {}. Ignorata.",
-                    dest_path.display(),
-                    e
-                );
-            }
-        } else if entry.file_type().is_file() {
-            // Se ci sono estensioni specificate, copia solo i file con le estensioni corrispondenti
-            if let Some(extensions) = extensions_to_backup {
-                if let Some(extension) = entry_path.extension() {
-                    if !extensions.contains(&extension.to_string_lossy().to_string()) {
-                        continue; // Skip files that don't match with the extensions selected
+            // With encryption each name is stored encrypted
+            let manifest_path = root.manifest_path(entry_path);
+            let stored_name = encryption::stored_name(&manifest_path, cipher);
+            let dest_path = destination.join(&stored_name);
+
+            if entry.file_type().is_dir() {
+                let created = if remote.is_none() { fs::create_dir_all(&dest_path) } else { Ok(()) };
+                let result = created
+                    .and_then(|_| EntryMetadata::read(entry_path, &manifest_path, follow_symlinks, &mut report.warnings));
+                match result {
+                    // The source folder itself is restored as the target folder, which is left as it is.
+                    // The folder of a volume is restored as its mount point, left as it is too.
+                    Ok(entry_metadata) if entry.depth() > 0 => manifest.metadata.push(entry_metadata),
+                    Ok(_) => {}
+                    Err(e) => report.add_failure(manifest_path, &e),
+                }
+            } else if entry.file_type().is_symlink() {
+                // Symlinks are only met when they are not followed, and are kept as symlinks.
+                // With encryption the target is only saved in the manifest, which is encrypted too.
+                let entry_metadata = match EntryMetadata::read(entry_path, &manifest_path, false, &mut report.warnings) {
+                    Ok(entry_metadata) => entry_metadata,
+                    Err(e) => {
+                        report.add_failure(manifest_path, &e);
+                        continue;
+                    }
+                };
+                if resume_entry(journal.as_deref(), destination, remote, &entry_metadata, 0, manifest, report) {
+                    println!("Resumed: {:?}", dest_path);
+                    continue;
+                }
+
+                if cipher.is_none() && remote.is_none() {
+                    let target = entry_metadata.link_target.as_deref().unwrap_or_default();
+                    let removed = if resuming { remove_partial(&dest_path) } else { Ok(()) };
+                    if let Err(e) = removed.and_then(|_| metadata::create_symlink(target, &dest_path)) {
+                        report.add_failure(manifest_path, &e);
+                        continue;
                     }
-                } else {
-                    continue; // Skip files without an extension
                 }
+                println!("Symlink: {:?}", dest_path);
+                report.add_file(0, 0);
+                if let Some(journal) = journal.as_deref_mut() {
+                    journal.append(None, &entry_metadata, 0);
+                }
+                manifest.metadata.push(entry_metadata);
+            } else if entry.file_type().is_file() {
+                let metadata = match entry.metadata() {
+                    Ok(metadata) => metadata,
+                    Err(e) => {
+                        report.add_failure(manifest_path, &io::Error::from(e));
+                        continue;
+                    }
+                };
+                let entry_metadata = match EntryMetadata::read(entry_path, &manifest_path, follow_symlinks, &mut report.warnings) {
+                    Ok(entry_metadata) => entry_metadata,
+                    Err(e) => {
+                        report.add_failure(manifest_path, &e);
+                        continue;
+                    }
+                };
+
+                // A file completed by an interrupted run is kept as it is. Anything else that run
+                // left may be partial, or linked to the previous snapshot: it is removed rather
+                // than overwritten. Remote objects are replaced as a whole.
+                if resume_entry(journal.as_deref(), destination, remote, &entry_metadata, metadata.len(), manifest, report) {
+                    println!("Resumed: {:?}", dest_path);
+                    continue;
+                }
+                if resuming && remote.is_none() {
+                    if let Err(e) = remove_partial(&dest_path) {
+                        report.add_failure(manifest_path, &e);
+                        continue;
+                    }
+                }
+
+                // Incremental mode: link the file of the previous snapshot if it did not change,
+                // or copy its object in a remote destination
+                if let Some(previous_snapshot) = previous_snapshot {
+                    if let Some(previous) = unchanged_entry(&entry, previous_snapshot, &manifest_path, &previous_entries) {
+                        let linked_path = compression::stored_path(destination, &previous, cipher);
+                        let linked = match remote {
+                            Some(remote) => remote.copy_previous(&compression::stored_path(Path::new(""), &previous, cipher)),
+                            None => fs::hard_link(compression::stored_path(previous_snapshot, &previous, cipher), &linked_path).is_ok(),
+                        };
+                        if linked {
+                            println!("Linked: {:?}", linked_path);
+                            report.add_file(previous.size, 0);
+                            if let Some(journal) = journal.as_deref_mut() {
+                                journal.append(Some(&previous), &entry_metadata, 0);
+                            }
+                            manifest.entries.push(previous);
+                            manifest.metadata.push(entry_metadata);
+                            continue;
+                        }
+                    }
+                }
+
+                // Copy the file, compressed if requested; waits while the queue is full
+                let job = CopyJob {
+                    source: entry_path.to_path_buf(),
+                    destination: dest_path,
+                    stored_name,
+                    level: config.compression.level_for(entry_path),
+                    manifest_path,
+                    metadata,
+                    entry_metadata,
+                };
+                if let Err(mpsc::SendError(job)) = jobs.send(job) {
+                    report.add_failure(job.manifest_path, &io::Error::other("copy workers stopped"));
+                }
+            } else {
+                // Sockets, devices and pipes are not saved
+                report.skipped += 1;
             }
+        }
 
-            // Copia il file
-            match fs::copy(&entry_path, &dest_path) {
-                Ok(size) => total_size += size,
-                Err(e) => eprintln!(
-                    "Errore durante la copia del file {}: {}. Ignorato.",
-                    entry_path.display(),
-                    e
-                ),
+        // No more files: the workers stop once the queue is empty
+        drop(jobs);
+    });
+
+    for copied_file in results.try_iter() {
+        record_copy(copied_file, manifest, report, journal.as_deref_mut());
+    }
+    report.skipped += sources.skipped;
+
+    Ok(())
+}
+
+/// Add a file copied by a worker to the manifest, the report and the journal
+fn record_copy(copied_file: CopiedFile, manifest: &mut Manifest, report: &mut BackupReport, journal: Option<&mut Journal>) {
+    let CopiedFile { job, result } = copied_file;
+    match result {
+        Ok((size, stored, hash)) => {
+            println!("Copied: {:?}", job.destination);
+            report.add_file(size, stored);
+            let entry = ManifestEntry {
+                compressed: job.level.is_some(),
+                ..ManifestEntry::new(job.manifest_path, &job.metadata, hash)
+            };
+            if let Some(journal) = journal {
+                journal.append(Some(&entry), &job.entry_metadata, stored);
             }
+            manifest.entries.push(entry);
+            manifest.metadata.push(job.entry_metadata);
+        }
+        Err(e) => report.add_failure(job.manifest_path, &e),
+    }
+}
 
-            println!("Copiato: {:?}", dest_path);
+/// Reuse an entry completed by an interrupted run, adding it to the manifest and the report
+fn resume_entry(
+    journal: Option<&Journal>,
+    snapshot_path: &Path,
+    remote: Option<&RemoteSnapshot>,
+    entry_metadata: &EntryMetadata,
+    size: u64,
+    manifest: &mut Manifest,
+    report: &mut BackupReport,
+) -> bool {
+    let Some(completed) = journal.and_then(|journal| journal.completed(snapshot_path, remote, entry_metadata, size)) else {
+        return false;
+    };
+
+    report.add_file(size, completed.stored_size);
+    report.resumed += 1;
+    manifest.entries.extend(completed.entry.clone());
+    manifest.metadata.push(entry_metadata.clone());
+    true
+}
+
+/// Remove the file an interrupted run may have left where an entry is about to be saved,
+/// compressed or not
+fn remove_partial(path: &Path) -> io::Result<()> {
+    for path in [path.to_path_buf(), compression::compressed_file_path(path)] {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
         }
     }
+    Ok(())
+}
 
-    Ok(total_size)
+/// Save a file into the snapshot at `snapshot_path`, under its path relative to it, compressed at
+/// the given level if any and encrypted if a key is given. The content is hashed while saved, for
+/// the manifest of the snapshot. A local copy keeps the modification time of the file, which
+/// incremental backups compare against.
+/// Returns the size of the file, the space it takes and the hash.
+fn save_file(
+    source: &Path,
+    snapshot_path: &Path,
+    stored_name: &Path,
+    remote: Option<&RemoteSnapshot>,
+    level: Option<i32>,
+    cipher: Option<&Cipher>,
+) -> io::Result<(u64, u64, String)> {
+    let file = fs::File::open(source)?;
+    let metadata = file.metadata()?;
+    let mut reader = compression::HashingReader::new(file);
+
+    // The size of the copy is only known in advance when it is not compressed
+    let size = level.is_none().then(|| encryption::stored_size(metadata.len(), cipher));
+    let writer = SnapshotFile::create(snapshot_path, stored_name, remote, size)?;
+    let (writer, read) = compression::write_stored(&mut reader, writer, level, cipher)?;
+    if let SnapshotFile::Local(file) = &writer {
+        file.set_modified(metadata.modified()?)?;
+        file.set_permissions(metadata.permissions())?;
+    }
+    Ok((read, writer.finish()?, reader.hash()))
 }
 
-fn is_hidden_or_problematic(entry: &DirEntry) -> bool {
+/// Manifest entry of the previous snapshot for a file that did not change since then.
+/// Snapshots written before manifests existed are compared through the copies themselves.
+fn unchanged_entry(
+    entry: &DirEntry,
+    previous_snapshot: &Path,
+    manifest_path: &str,
+    previous_entries: &HashMap<String, ManifestEntry>,
+) -> Option<ManifestEntry> {
+    if let Some(previous) = previous_entries.get(manifest_path) {
+        let metadata = entry.metadata().ok()?;
+        let modified = metadata.modified().map(unix_seconds).ok()?;
+        return (metadata.len() == previous.size && modified == previous.modified).then(|| previous.clone());
+    }
+
+    let previous_path = previous_snapshot.join(manifest_path);
+    if !is_unchanged(entry, &previous_path) {
+        return None;
+    }
+    let hash = manifest::hash_file(&previous_path).ok()?;
+    Some(ManifestEntry::new(manifest_path.to_string(), &fs::metadata(&previous_path).ok()?, hash))
+}
+
+/// A file is considered unchanged if the previous snapshot holds a file with the same size and
+/// the same modification time (to the second, as not all filesystems store sub-second times).
+fn is_unchanged(entry: &DirEntry, previous_path: &Path) -> bool {
+    let (Ok(current), Ok(previous)) = (entry.metadata(), fs::symlink_metadata(previous_path)) else {
+        return false;
+    };
+
+    if !previous.is_file() || current.len() != previous.len() {
+        return false;
+    }
+
+    match (current.modified(), previous.modified()) {
+        (Ok(current), Ok(previous)) => unix_seconds(current) == unix_seconds(previous),
+        _ => false,
+    }
+}
+
+pub(crate) fn unix_seconds(time: SystemTime) -> i64 {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+/// Check that each root is a folder that can be walked
+fn check_roots(roots: &[SourceRoot]) -> io::Result<()> {
+    for root in roots {
+        let source = root.path.as_path();
+        if !source.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Path not found: {}", source.display()),
+            ));
+        }
+
+        if !source.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Source is not a directory: {}", source.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Walk of the entries of each root kept by its filter, the roots themselves included, along
+/// with the root they belong to, given as they are met
+pub(crate) struct SourceWalk<'a> {
+    roots: std::slice::Iter<'a, SourceRoot>,
+    walk: Option<(&'a SourceRoot, walkdir::IntoIter)>,
+    follow_symlinks: bool,
+    // Number of entries left out by the filters
+    pub skipped: usize,
+    // Entries left out, with the reason, only kept for the plan of a dry run
+    pub skipped_entries: Option<Vec<SkippedEntry>>,
+}
+
+impl<'a> SourceWalk<'a> {
+    pub(crate) fn new(roots: &'a [SourceRoot], follow_symlinks: bool, list_skipped: bool) -> Self {
+        SourceWalk {
+            roots: roots.iter(),
+            walk: None,
+            follow_symlinks,
+            skipped: 0,
+            skipped_entries: list_skipped.then(Vec::new),
+        }
+    }
+}
+
+impl<'a> Iterator for SourceWalk<'a> {
+    type Item = (&'a SourceRoot, walkdir::Result<DirEntry>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if self.walk.is_none() {
+                let root = self.roots.next()?;
+                let walk = WalkDir::new(&root.path)
+                    .follow_links(self.follow_symlinks)
+                    .same_file_system(root.one_file_system)
+                    .into_iter();
+                self.walk = Some((root, walk));
+            }
+            let (root, walk) = self.walk.as_mut().expect("set above");
+            let root = *root;
+            let entry = match walk.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => return Some((root, Err(e))),
+                None => {
+                    self.walk = None;
+                    continue;
+                }
+            };
+
+            // Hidden files, problematic and excluded directories are left out, with what they hold
+            match skip_reason(&entry, &root.filter) {
+                Some(reason) => {
+                    if entry.file_type().is_dir() {
+                        walk.skip_current_dir();
+                    }
+                    self.skipped += 1;
+                    if let Some(skipped_entries) = &mut self.skipped_entries {
+                        skipped_entries.push(SkippedEntry { path: root.manifest_path(entry.path()), reason });
+                    }
+                }
+                None => return Some((root, Ok(entry))),
+            }
+        }
+    }
+}
+
+/// Entries of the sources walked for the plan of a backup, then saved by the backup, so that the
+/// sources are only walked once
+#[derive(Default)]
+pub(crate) struct WalkedSources<'a> {
+    pub entries: Vec<(&'a SourceRoot, walkdir::Result<DirEntry>)>,
+    // Number of entries left out by the filters
+    pub skipped: usize,
+}
+
+/// Why an entry met while walking the source is left out, if it is
+fn skip_reason(entry: &DirEntry, filter: &EntryFilter) -> Option<SkipReason> {
+    if filter.is_destination(entry) {
+        return Some(SkipReason::BackupDestination);
+    }
+    if is_hidden_or_problematic(entry) {
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            return Some(SkipReason::Hidden);
+        }
+        return Some(SkipReason::SystemDirectory);
+    }
+    filter.is_excluded(entry).then_some(SkipReason::ExcludedByFilters)
+}
+
+pub(crate) fn is_hidden_or_problematic(entry: &DirEntry) -> bool {
     let file_name = entry.file_name().to_string_lossy();
     let path = entry.path();
 
@@ -213,12 +1592,465 @@ fn is_hidden_or_problematic(entry: &DirEntry) -> bool {
     #[cfg(target_os = "linux")]
     {
         let problematic_dirs = ["/sys", "/proc", "/dev", "/run", "/tmp"];
-        if path.is_dir() {
-            if problematic_dirs.iter().any(|&d| path.starts_with(d)) {
-                return true;
-            }
+        if path.is_dir() && problematic_dirs.iter().any(|&d| path.starts_with(d)) {
+            return true;
         }
     }
 
     false
-}
\ No newline at end of file
+}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Folder holding a `source` folder with `files` and their contents, under `target` as
+    /// backups leave out the temporary folder of the system
+    pub(crate) fn test_folder<C: AsRef<[u8]>>(name: &str, files: &[(&str, C)]) -> PathBuf {
+        let folder = Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join(format!("backmeup-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(folder.join("source")).unwrap();
+        for (path, contents) in files {
+            let path = folder.join("source").join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        folder
+    }
+
+    /// Incremental plain backup of the `source` folder of `folder` into its `destination` folder
+    pub(crate) fn test_config(folder: &Path) -> Config {
+        Config {
+            source_path: folder.join("source").display().to_string(),
+            destination_path: folder.join("destination").display().to_string(),
+            backup_type: "directory".to_string(),
+            storage_format: "plain".to_string(),
+            incremental: true,
+            concurrency: 2,
+            space_check: "warn".to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Files of `size` bytes
+    fn filled<'a>(files: &[(&'a str, usize)]) -> Vec<(&'a str, Vec<u8>)> {
+        files.iter().map(|(path, size)| (*path, vec![b'x'; *size])).collect()
+    }
+
+    fn dry_run(config: &Config) -> BackupPlan {
+        match run_job(config, true).unwrap() {
+            BackupOutcome::Planned(plan) => plan,
+            BackupOutcome::Completed(_) => panic!("a dry run made a backup"),
+        }
+    }
+
+    #[test]
+    fn each_run_gets_its_own_snapshot() {
+        let folder = test_folder("snapshots", &[("a.txt", "first version")]);
+        let config = test_config(&folder);
+        let series_path = series_path(&config).unwrap();
+        let snapshot_id = |outcome| match outcome {
+            BackupOutcome::Completed(report) => report.snapshot_id,
+            BackupOutcome::Planned(_) => panic!("no backup was made"),
+        };
+
+        let first = snapshot_id(run_job(&config, false).unwrap());
+        assert_eq!(latest_snapshot(&series_path).as_deref(), Some(first.as_str()));
+        // Within the same second, or not
+        fs::write(folder.join("source/a.txt"), "second version").unwrap();
+        let second = snapshot_id(run_job(&config, false).unwrap());
+        assert_ne!(second, first);
+        assert!(second == format!("{}-1", first) || snapshot_time(&second) > snapshot_time(&first), "{} after {}", second, first);
+        assert_eq!(list_snapshots(&series_path), [first.clone(), second.clone()]);
+        assert_eq!(latest_snapshot(&series_path).as_deref(), Some(second.as_str()));
+        assert_eq!(fs::read_to_string(series_path.join(&first).join("a.txt")).unwrap(), "first version");
+        assert_eq!(fs::read_to_string(series_path.join(&second).join("a.txt")).unwrap(), "second version");
+
+        // A run failing before writing, or while writing its snapshot, leaves the pointer as it is
+        fs::write(series_path.join(staging::STAGING_DIR), "in the way").unwrap();
+        assert!(run_job(&config, false).is_err());
+        fs::remove_file(series_path.join(staging::STAGING_DIR)).unwrap();
+        fs::remove_dir_all(folder.join("source")).unwrap();
+        assert!(matches!(run_job(&config, false), Err(BackupError::SourceNotFound)));
+        assert_eq!(list_snapshots(&series_path), [first, second.clone()]);
+        assert_eq!(latest_snapshot(&series_path).as_deref(), Some(second.as_str()));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unchanged_files_are_linked_to_the_previous_snapshot() {
+        use std::os::unix::fs::MetadataExt;
+
+        let folder = test_folder("links", &[("same.txt", "unchanged"), ("folder/changed.txt", "before")]);
+        let config = test_config(&folder);
+        let series_path = series_path(&config).unwrap();
+        let snapshot_path = |outcome| match outcome {
+            BackupOutcome::Completed(report) => series_path.join(report.snapshot_id),
+            BackupOutcome::Planned(_) => panic!("no backup was made"),
+        };
+        let first = snapshot_path(run_job(&config, false).unwrap());
+        fs::write(folder.join("source/folder/changed.txt"), "after the change").unwrap();
+        let BackupOutcome::Completed(report) = run_job(&config, false).unwrap() else {
+            panic!("no backup was made");
+        };
+        let second = series_path.join(&report.snapshot_id);
+        // Only the changed file takes space
+        assert_eq!((report.copied, report.stored_bytes), (2, "after the change".len() as u64));
+
+        let metadata = |snapshot: &Path, path: &str| fs::metadata(snapshot.join(path)).unwrap();
+        let (linked, previous) = (metadata(&second, "same.txt"), metadata(&first, "same.txt"));
+        assert_eq!((linked.ino(), linked.nlink()), (previous.ino(), 2));
+        let (copied, previous) = (metadata(&second, "folder/changed.txt"), metadata(&first, "folder/changed.txt"));
+        assert_ne!(copied.ino(), previous.ino());
+        assert_eq!((copied.nlink(), previous.nlink()), (1, 1));
+        assert_eq!(fs::read_to_string(first.join("folder/changed.txt")).unwrap(), "before");
+
+        // Without incremental mode every file is copied
+        let config = Config { incremental: false, ..config };
+        let third = snapshot_path(run_job(&config, false).unwrap());
+        assert_eq!(metadata(&third, "same.txt").nlink(), 1);
+        assert_eq!(metadata(&second, "same.txt").nlink(), 2);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn snapshot_ids_within_a_second_are_numbered() {
+        let series_path = crate::storage::tests::temp_folder("snapshot-ids");
+        let first = new_snapshot_id(&series_path);
+        assert!(snapshot_time(&first).is_some(), "{}", first);
+        fs::create_dir(series_path.join(&first)).unwrap();
+        let second = new_snapshot_id(&series_path);
+        assert!(second == format!("{}-1", first) || snapshot_time(&second) > snapshot_time(&first), "{} after {}", second, first);
+
+        let ids = ["2024-05-01_10-00-00-10", "2024-05-01_10-00-00-2", "2024-05-02_09-00-00", "2024-05-01_10-00-00", "latest", "2024-05-01_10-00-00-1"];
+        assert_eq!(sort_snapshots(ids.map(String::from).to_vec()), [
+            "2024-05-01_10-00-00",
+            "2024-05-01_10-00-00-1",
+            "2024-05-01_10-00-00-2",
+            "2024-05-01_10-00-00-10",
+            "2024-05-02_09-00-00",
+        ]);
+        fs::remove_dir_all(series_path).unwrap();
+    }
+
+    #[test]
+    fn dry_run_plans_without_writing() {
+        let mut files: Vec<(String, usize)> = (1..=12).map(|i| (format!("folder/file{:02}.bin", i), i * 100)).collect();
+        files.extend([(".hidden".to_string(), 5), ("notes.tmp".to_string(), 7), ("folder/.git/config".to_string(), 9)]);
+        let files: Vec<(&str, usize)> = files.iter().map(|(path, size)| (path.as_str(), *size)).collect();
+        let folder = test_folder("dry-run", &filled(&files));
+        let config = Config { filters: vec!["*.tmp".to_string()], ..test_config(&folder) };
+
+        let plan = dry_run(&config);
+        assert!(!folder.join("destination").exists());
+        assert_eq!(plan.files, 12);
+        assert_eq!(plan.bytes, (1..=12).map(|i| i * 100).sum::<usize>() as u64);
+        assert_eq!(plan.needed_bytes, plan.bytes);
+        assert_eq!(plan.largest_files.len(), PLAN_LARGEST_FILES);
+        assert_eq!(plan.largest_files[0], ("folder/file12.bin".to_string(), 1200));
+        assert_eq!(plan.largest_files[PLAN_LARGEST_FILES - 1], ("folder/file03.bin".to_string(), 300));
+
+        let mut skipped: Vec<(String, String)> = plan.skipped.iter().map(|entry| (entry.path.clone(), entry.reason.to_string())).collect();
+        skipped.sort();
+        assert_eq!(skipped, [
+            (".hidden".to_string(), "hidden".to_string()),
+            ("folder/.git".to_string(), "hidden".to_string()),
+            ("notes.tmp".to_string(), "excluded by filters".to_string()),
+        ]);
+        assert!(plan.to_string().contains("Files to back up: 12"));
+
+        // After a backup, only the changed files need space
+        run_job(&config, false).unwrap();
+        fs::write(folder.join("source/folder/file01.bin"), vec![b'y'; 150]).unwrap();
+        let plan = dry_run(&config);
+        assert_eq!(plan.files, 12);
+        assert_eq!(plan.needed_bytes, 150);
+        assert_eq!(list_snapshots(&series_path(&config).unwrap()).len(), 1);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn report_is_saved_as_json() {
+        let folder = test_folder("report", &filled(&[("a.txt", 10), ("folder/b.txt", 20), (".hidden", 5)]));
+        let config = test_config(&folder);
+        let BackupOutcome::Completed(report) = run_job(&config, false).unwrap() else {
+            panic!("no backup was made");
+        };
+        let series_path = series_path(&config).unwrap();
+        assert_eq!(latest_snapshot(&series_path).as_deref(), Some(report.snapshot_id.as_str()));
+
+        let path = series_path.join(&report.snapshot_id).join(SNAPSHOT_METADATA_DIR).join(REPORT_FILE);
+        let json: serde_json::Value = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        assert_eq!(json["snapshot_id"], report.snapshot_id.as_str());
+        assert_eq!((json["copied"].as_u64(), json["skipped"].as_u64(), json["failed"].as_u64()), (Some(2), Some(1), Some(0)));
+        assert_eq!(json["bytes"].as_u64(), Some(30));
+        assert_eq!(json["failures"], serde_json::json!([]));
+        assert!(json["duration_secs"].is_f64());
+        // Left out when empty
+        assert!(json.get("job").is_none() && json.get("pruned").is_none() && json.get("warnings").is_none());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn failures_are_reported_with_their_kind() {
+        let mut report = BackupReport::default();
+        report.add_file(10, 4);
+        report.add_failure("folder/a.txt".to_string(), &io::Error::new(io::ErrorKind::PermissionDenied, "access denied"));
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!((json["copied"].as_u64(), json["failed"].as_u64(), json["stored_bytes"].as_u64()), (Some(1), Some(1), Some(4)));
+        assert_eq!(json["failures"], serde_json::json!([{ "path": "folder/a.txt", "kind": "PermissionDenied", "message": "access denied" }]));
+    }
+
+    #[test]
+    fn destination_inside_the_source_is_refused() {
+        let folder = test_folder("nested", &filled(&[("a.txt", 10), ("sub/b.txt", 20)]));
+        let source = folder.join("source");
+        for destination in [source.join("backups"), source.join("sub/../backups"), source.clone()] {
+            let config = Config { destination_path: destination.display().to_string(), ..test_config(&folder) };
+            assert!(matches!(run_job(&config, false), Err(BackupError::DestinationInsideSource)), "{}", destination.display());
+            assert!(matches!(run_job(&config, true), Err(BackupError::DestinationInsideSource)), "{}", destination.display());
+        }
+        assert!(!source.join("backups").exists());
+
+        // Through a symlink to the source
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&source, folder.join("link")).unwrap();
+            let config = Config { destination_path: folder.join("link/backups").display().to_string(), ..test_config(&folder) };
+            assert!(matches!(run_job(&config, false), Err(BackupError::DestinationInsideSource)));
+        }
+
+        // Even allowed, the snapshots cannot be written in the source folder itself
+        let config = Config { destination_path: source.display().to_string(), allow_nested_destination: true, ..test_config(&folder) };
+        assert!(matches!(run_job(&config, false), Err(BackupError::DestinationInsideSource)));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn allowed_nested_destination_is_left_out() {
+        let folder = test_folder("nested-allowed", &filled(&[("a.txt", 10), ("sub/b.txt", 20)]));
+        let config = Config {
+            destination_path: folder.join("source/backups").display().to_string(),
+            allow_nested_destination: true,
+            ..test_config(&folder)
+        };
+
+        // The second backup does not save the first one
+        for _ in 0..2 {
+            let BackupOutcome::Completed(report) = run_job(&config, false).unwrap() else {
+                panic!("no backup was made");
+            };
+            assert_eq!(report.copied, 2);
+            std::thread::sleep(std::time::Duration::from_millis(1100));
+        }
+        let plan = dry_run(&config);
+        assert_eq!(plan.files, 2);
+        assert!(plan.skipped.iter().any(|entry| entry.path == "backups" && matches!(entry.reason, SkipReason::BackupDestination)));
+        let series_path = series_path(&config).unwrap();
+        for snapshot_id in list_snapshots(&series_path) {
+            assert!(series_path.join(&snapshot_id).join("a.txt").is_file());
+            assert!(!series_path.join(&snapshot_id).join("backups").exists());
+        }
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    /// Files of a snapshot and their contents, by path in the snapshot, leaving out its metadata
+    fn snapshot_contents(snapshot_path: &Path) -> Vec<(String, Vec<u8>)> {
+        let mut contents: Vec<(String, Vec<u8>)> = WalkDir::new(snapshot_path)
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != SNAPSHOT_METADATA_DIR)
+            .map(Result::unwrap)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| (repository::index_path(entry.path().strip_prefix(snapshot_path).unwrap()), fs::read(entry.path()).unwrap()))
+            .collect();
+        contents.sort();
+        contents
+    }
+
+    #[test]
+    fn workers_save_the_same_snapshot_as_one() {
+        let files: Vec<(String, usize)> = (0..200).map(|i| (format!("folder{}/sub{}/file{}.txt", i % 7, i % 3, i), (i * 37) % 5000)).collect();
+        let files: Vec<(&str, usize)> = files.iter().map(|(path, size)| (path.as_str(), *size)).collect();
+        let folder = test_folder("workers", &filled(&files));
+
+        for compression in ["none", "zstd"] {
+            let snapshots: Vec<_> = [1, 8].iter()
+                .map(|workers| {
+                    let config = Config {
+                        destination_path: folder.join(format!("destination-{}-{}", compression, workers)).display().to_string(),
+                        concurrency: *workers,
+                        compression: CompressionConfig { mode: compression.to_string(), level: 3 },
+                        ..test_config(&folder)
+                    };
+                    let BackupOutcome::Completed(report) = run_job(&config, false).unwrap() else {
+                        panic!("no backup was made");
+                    };
+                    let snapshot_path = series_path(&config).unwrap().join(&report.snapshot_id);
+                    let mut entries: Vec<(String, u64, String)> = manifest::manifest_entries(&snapshot_path, None)
+                        .into_values()
+                        .map(|entry| (entry.path, entry.size, entry.hash))
+                        .collect();
+                    entries.sort();
+                    (report, snapshot_contents(&snapshot_path), entries)
+                })
+                .collect();
+
+            let (one, many) = (&snapshots[0], &snapshots[1]);
+            assert_eq!(one.0.copied, files.len(), "{}", compression);
+            assert_eq!((many.0.copied, many.0.failed, many.0.bytes, many.0.stored_bytes), (one.0.copied, one.0.failed, one.0.bytes, one.0.stored_bytes), "{}", compression);
+            assert_eq!(many.2, one.2, "{}", compression);
+            assert!(many.1 == one.1, "{}", compression);
+        }
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn too_small_destination_refuses_or_warns() {
+        let plan = |available_bytes| BackupPlan { needed_bytes: 1000, available_bytes, ..BackupPlan::default() };
+        assert!(matches!(check_space(&plan(Some(999)), "refuse"), Err(BackupError::InsufficientSpace { needed: 1000, available: 999 })));
+        let warning = check_space(&plan(Some(999)), "warn").unwrap().unwrap();
+        assert!(warning.contains("1000 bytes needed, 999 bytes available"), "{}", warning);
+        // Enough space, or no way to know
+        for available_bytes in [Some(1000), None] {
+            assert!(check_space(&plan(available_bytes), "refuse").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn backup_larger_than_the_free_space_is_refused_before_writing() {
+        let folder = test_folder("space", &[("a.txt", "small file")]);
+        // A sparse file larger than the free space, taking none itself
+        let available = available_space(&folder).unwrap();
+        fs::File::create(folder.join("source/large.bin")).unwrap().set_len(available + (1 << 30)).unwrap();
+        let config = Config { space_check: "refuse".to_string(), ..test_config(&folder) };
+        let plan = dry_run(&config);
+        assert!(plan.needed_bytes > plan.available_bytes.unwrap());
+
+        match run_job(&config, false) {
+            Err(BackupError::InsufficientSpace { needed, available: found }) => {
+                assert_eq!(needed, available + (1 << 30) + 10);
+                assert!(found < needed);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let series_path = series_path(&config).unwrap();
+        assert!(list_snapshots(&series_path).is_empty());
+        assert!(!series_path.join(staging::STAGING_DIR).exists());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    fn job(name: &str, sources: &[&str], destination_path: &str) -> Job {
+        Job {
+            name: name.to_string(),
+            sources: sources.iter().map(|source| source.to_string()).collect(),
+            destination_path: destination_path.to_string(),
+            backup_type: "directory".to_string(),
+            ..Job::default()
+        }
+    }
+
+    #[test]
+    fn job_names_must_be_valid_and_distinct() {
+        for names in [vec!["docs", " docs "], vec!["docs", " "], vec!["a/b"], vec!["a\\b"], vec![".."]] {
+            let config = Config { jobs: names.iter().map(|name| job(name, &["/data"], "/backups")).collect(), ..Config::default() };
+            assert!(matches!(config.job_configs(), Err(BackupError::InvalidJob(_))), "{:?}", names);
+            assert!(matches!(config.job_config(Some("docs")), Err(BackupError::InvalidJob(_))), "{:?}", names);
+        }
+        let config = Config { jobs: vec![job(" docs ", &["/data"], "/backups"), job("photos", &["/data"], "/backups")], ..Config::default() };
+        let names: Vec<String> = config.job_configs().unwrap().into_iter().map(|config| config.name).collect();
+        assert_eq!(names, ["docs", "photos"]);
+    }
+
+    #[test]
+    fn jobs_are_chosen_by_name() {
+        let config = Config { jobs: vec![job("docs", &["/docs"], "/backups"), job("photos", &["/photos"], "/backups")], ..Config::default() };
+        assert!(matches!(config.job_config(None), Err(BackupError::JobRequired)));
+        assert!(matches!(config.job_config(Some("music")), Err(BackupError::UnknownJob(name)) if name == "music"));
+        assert_eq!(config.job_config(Some("photos")).unwrap().source_path, "/photos");
+        assert!(matches!(backup_files(&config, Some("music"), true), Err(BackupError::UnknownJob(_))));
+
+        // A single job, or a configuration without jobs, needs no name
+        let config = Config { jobs: vec![job("docs", &["/docs"], "/backups")], ..Config::default() };
+        assert_eq!(config.job_config(None).unwrap().name, "docs");
+        let config = Config { source_path: "/docs".to_string(), ..Config::default() };
+        assert_eq!(config.job_config(None).unwrap().source_path, "/docs");
+        assert!(matches!(config.job_config(Some("docs")), Err(BackupError::UnknownJob(_))));
+    }
+
+    #[test]
+    fn jobs_share_the_settings_they_do_not_set() {
+        let shared = Config {
+            source_path: "/shared".to_string(),
+            destination_path: "/shared-backups".to_string(),
+            filters: vec!["*.tmp".to_string()],
+            storage_format: "repository".to_string(),
+            compression: CompressionConfig { mode: "zstd".to_string(), level: 9 },
+            concurrency: 3,
+            incremental: true,
+            ..Config::default()
+        };
+        let config = Config {
+            jobs: vec![
+                Job { filters: vec!["*.bak".to_string()], ..job("single", &[" /docs ", ""], "/backups") },
+                job("several", &["/docs", "/photos"], "/other-backups"),
+            ],
+            ..shared.clone()
+        };
+        let configs = config.job_configs().unwrap();
+
+        // The folders, the destination and the filters are the job's own
+        let single = &configs[0];
+        assert_eq!((single.source_path.as_str(), single.sources.len()), ("/docs", 0));
+        assert_eq!((single.destination_path.as_str(), single.filters.as_slice()), ("/backups", ["*.bak".to_string()].as_slice()));
+        let several = &configs[1];
+        assert_eq!((several.source_path.as_str(), several.sources.as_slice()), ("", ["/docs".to_string(), "/photos".to_string()].as_slice()));
+        assert_eq!(several.source_paths(), [PathBuf::from("/docs"), PathBuf::from("/photos")]);
+        assert!(several.filters.is_empty());
+
+        // The rest comes from the shared settings
+        for job_config in &configs {
+            assert_eq!((job_config.storage_format.as_str(), job_config.compression.mode.as_str(), job_config.compression.level), ("repository", "zstd", 9));
+            assert_eq!((job_config.concurrency, job_config.incremental), (3, true));
+            assert!(job_config.jobs.is_empty());
+        }
+        assert_eq!(series_path(single).unwrap(), Path::new("/backups/single"));
+    }
+
+    #[test]
+    fn sources_of_a_job_are_saved_in_their_own_folders() {
+        let folder = test_folder("jobs", &[("docs/a.txt", "first"), ("docs/folder/b.txt", "second"), ("photos/c.jpg", "third")]);
+        fs::create_dir_all(folder.join("other/docs")).unwrap();
+        fs::write(folder.join("other/docs/d.txt"), "fourth").unwrap();
+        let destination = folder.join("destination").display().to_string();
+        let sources = ["source/docs", "source/photos", "other/docs"].map(|source| folder.join(source).display().to_string());
+        let config = Config {
+            jobs: vec![
+                job("several", &sources.iter().map(String::as_str).collect::<Vec<_>>(), &destination),
+                job("missing", &[&folder.join("missing").display().to_string()], &destination),
+                job("single", &[&sources[1]], &destination),
+            ],
+            ..test_config(&folder)
+        };
+
+        // A failed job does not stop the next ones
+        let runs = backup_files(&config, None, false).unwrap();
+        let names: Vec<&str> = runs.iter().map(|run| run.name.as_str()).collect();
+        assert_eq!(names, ["several", "missing", "single"]);
+        assert!(matches!(runs[1].result, Err(BackupError::SourceNotFound)));
+
+        let snapshot_of = |run: &JobRun| {
+            let Ok(BackupOutcome::Completed(report)) = &run.result else {
+                panic!("job {} failed: {:?}", run.name, run.result);
+            };
+            snapshot_contents(&folder.join("destination").join(&run.name).join(&report.snapshot_id))
+        };
+        let contents = |files: &[(&str, &str)]| -> Vec<(String, Vec<u8>)> {
+            files.iter().map(|(path, data)| (path.to_string(), data.as_bytes().to_vec())).collect()
+        };
+        // Folders with the same name are told apart
+        assert_eq!(snapshot_of(&runs[0]), contents(&[("docs-2/d.txt", "fourth"), ("docs/a.txt", "first"), ("docs/folder/b.txt", "second"), ("photos/c.jpg", "third")]));
+        // A single source is saved as the whole snapshot
+        assert_eq!(snapshot_of(&runs[2]), contents(&[("c.jpg", "third")]));
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
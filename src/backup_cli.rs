@@ -0,0 +1,341 @@
+use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use backmeup::{backup, encryption, lock, manifest, restore, retention, storage, volumes};
+use backup::BackupOutcome;
+use restore::{ConflictPolicy, RestoreOptions, RestoreOutcome};
+
+const USAGE: &str = "\
+Usage: backup_cli [--config <file>] <command> [--job <name>] [options]
+
+Commands:
+  backup [--dry-run]              Run all the jobs of the configuration, or the one given
+                                  Fails if any job failed or any entry could not be saved
+      --dry-run                   Only show what would be backed up and what would be skipped
+  jobs                            List the jobs of the configuration
+  snapshots                       List the snapshots of the configured source
+  volumes                         List the volumes a full-disk backup can save
+  restore [options] [<path>...]   Restore a snapshot, or only the given paths
+      --snapshot <id>             Snapshot to restore (default: latest)
+      --to <dir>                  Restore into <dir> instead of the source location
+                                  (an image: onto a device or into a file)
+      --policy <policy>           skip | overwrite | keep-both | only-if-newer (default: skip)
+  verify [--snapshot <id>] [--source]
+                                  Check the files of a snapshot against its manifest
+      --source                    Compare the live source with the manifest too
+  prune [--dry-run]               Delete the snapshots not kept by the retention policy
+      --dry-run                   Only list what would be deleted and why
+
+  --job <name>                    Job to run or whose snapshots to use, needed by the
+                                  commands other than backup when there are several
+";
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+
+    // Same config.toml used by backup_program, unless another one is given
+    let mut config_path = PathBuf::from(env::current_exe().unwrap().parent().unwrap())
+        .parent().unwrap()
+        .join("Resources/config.toml");
+    if args.first().map(String::as_str) == Some("--config") {
+        if args.len() < 2 {
+            eprint!("{}", USAGE);
+            return ExitCode::FAILURE;
+        }
+        config_path = PathBuf::from(args.remove(1));
+        args.remove(0);
+    }
+
+    if args.is_empty() {
+        eprint!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+
+    if !config_path.exists() {
+        eprintln!("Configuration file not found: {}", config_path.display());
+        return ExitCode::FAILURE;
+    }
+    let config = backup::read_config(config_path.to_str().unwrap());
+
+    let command = args.remove(0);
+
+    // The job is given after the command, with its other options
+    let mut job = None;
+    if let Some(i) = args.iter().position(|arg| arg == "--job") {
+        if i + 1 >= args.len() {
+            eprintln!("Missing value for --job");
+            return ExitCode::FAILURE;
+        }
+        job = Some(args.remove(i + 1));
+        args.remove(i);
+    }
+
+    match command.as_str() {
+        "backup" => {
+            let dry_run = args.iter().any(|arg| arg == "--dry-run");
+            match backup::backup_files(&config, job.as_deref(), dry_run) {
+                Ok(runs) => {
+                    let mut succeeded = true;
+                    for run in runs {
+                        match run.result {
+                            Ok(BackupOutcome::Planned(plan)) => {
+                                if !run.name.is_empty() {
+                                    println!("Job: {}", run.name);
+                                }
+                                print!("{}", plan);
+                            }
+                            Ok(BackupOutcome::Completed(report)) => {
+                                print!("{}", report);
+                                succeeded &= report.failed == 0;
+                            }
+                            Err(e) if run.name.is_empty() => {
+                                eprintln!("Backup failed: {}", e);
+                                succeeded = false;
+                            }
+                            Err(e) => {
+                                eprintln!("Backup of job {} failed: {}", run.name, e);
+                                succeeded = false;
+                            }
+                        }
+                    }
+                    // A destination drive can be unplugged once the command is over
+                    if !dry_run {
+                        volumes::flush_writes();
+                    }
+                    if succeeded { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+                }
+                Err(e) => {
+                    eprintln!("Backup failed: {}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        "jobs" => {
+            match config.job_configs() {
+                Ok(configs) => {
+                    for config in configs.iter().filter(|config| !config.name.is_empty()) {
+                        let sources: Vec<String> = config.source_paths().iter().map(|path| path.display().to_string()).collect();
+                        println!("{} ({}): {} -> {}", config.name, config.backup_type, sources.join(", "), config.destination_path);
+                    }
+                    ExitCode::SUCCESS
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        "volumes" => {
+            for volume in volumes::list_volumes() {
+                println!("{}", volume);
+            }
+            ExitCode::SUCCESS
+        }
+        "snapshots" | "restore" | "verify" | "prune" => {
+            // These commands work on the snapshots of a single job
+            let config = match config.job_config(job.as_deref()) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            match command.as_str() {
+                "snapshots" => list_snapshots(&config),
+                "restore" => run_restore(&config, args),
+                "verify" => run_verify(&config, args),
+                _ => run_prune(&config, args),
+            }
+        }
+        _ => {
+            eprint!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn list_snapshots(config: &backup::Config) -> ExitCode {
+    let series_path = match backup::series_path(config) {
+        Ok(series_path) => series_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let latest = backup::latest_snapshot(series_path.as_path());
+    // The snapshots of a remote destination are listed even when missing from the working copy
+    let snapshots = match storage::list_snapshots(config) {
+        Ok(snapshots) => snapshots,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for snapshot_id in snapshots {
+        let marker = if Some(&snapshot_id) == latest.as_ref() { " (latest)" } else { "" };
+        println!("{}{}", snapshot_id, marker);
+    }
+    ExitCode::SUCCESS
+}
+
+fn run_restore(config: &backup::Config, args: Vec<String>) -> ExitCode {
+    let mut options = RestoreOptions {
+        snapshot_id: None,
+        paths: Vec::new(),
+        target_path: None,
+        conflict_policy: ConflictPolicy::Skip,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--snapshot" | "--to" | "--policy" => {
+                let Some(value) = args.next() else {
+                    eprintln!("Missing value for {}", arg);
+                    return ExitCode::FAILURE;
+                };
+                match arg.as_str() {
+                    "--snapshot" => options.snapshot_id = Some(value),
+                    "--to" => options.target_path = Some(PathBuf::from(value)),
+                    _ => match ConflictPolicy::parse(&value) {
+                        Some(policy) => options.conflict_policy = policy,
+                        None => {
+                            eprintln!("Unknown conflict policy: {}", value);
+                            return ExitCode::FAILURE;
+                        }
+                    },
+                }
+            }
+            // A mistyped option must not be restored as a path
+            _ if arg.starts_with("--") => {
+                eprintln!("Unknown option for restore: {}", arg);
+                eprint!("{}", USAGE);
+                return ExitCode::FAILURE;
+            }
+            _ => options.paths.push(arg),
+        }
+    }
+
+    match restore::restore_snapshot(config, &options) {
+        Ok(results) => {
+            for result in &results {
+                println!("{}", result);
+            }
+            let failed = results.iter().filter(|r| matches!(r.outcome, RestoreOutcome::Failed(_))).count();
+            println!("{} files processed, {} failed", results.len(), failed);
+            if failed == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+        }
+        Err(e) => {
+            eprintln!("Restore failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_prune(config: &backup::Config, args: Vec<String>) -> ExitCode {
+    let dry_run = args.iter().any(|arg| arg == "--dry-run");
+
+    let Some(policy) = &config.retention else {
+        println!("No retention policy configured, all the snapshots are kept");
+        return ExitCode::SUCCESS;
+    };
+
+    let series_path = match backup::series_path(config) {
+        Ok(series_path) => series_path,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // Snapshots and chunks must not be deleted while a backup is using them
+    let _lock = if dry_run {
+        None
+    } else {
+        match lock::DestinationLock::acquire(series_path.as_path()) {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                eprintln!("Prune failed: {}", e);
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+
+    let backend = match storage::open_backend(config) {
+        Ok(backend) => backend,
+        Err(e) => {
+            eprintln!("Prune failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // The policy applies to every snapshot of the destination, including those made from another computer
+    if let Some(backend) = &backend {
+        if let Err(e) = storage::sync_series(backend.as_ref(), series_path.as_path()) {
+            eprintln!("Prune failed on {}: {}", config.destination_path, e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let cipher = match encryption::load_cipher(config, series_path.as_path(), false) {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            eprintln!("Prune failed: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match retention::prune(series_path.as_path(), policy, dry_run, cipher.as_ref()) {
+        Ok(decisions) => {
+            for decision in &decisions {
+                println!("{}", decision);
+            }
+            let removed = decisions.iter().filter(|d| !d.keep).count();
+            if dry_run {
+                println!("{} snapshots would be removed", removed);
+                return ExitCode::SUCCESS;
+            }
+            println!("{} snapshots removed", removed);
+
+            // The snapshots removed from the working copy are deleted from the remote destination too
+            let pruned: Vec<String> = decisions.into_iter().filter(|d| !d.keep).map(|d| d.snapshot_id).collect();
+            if let Some(backend) = backend.filter(|_| !pruned.is_empty()) {
+                if let Err(e) = storage::push_series(backend.as_ref(), series_path.as_path(), &pruned, cipher.as_ref()) {
+                    eprintln!("Prune failed on {}: {}", config.destination_path, e);
+                    return ExitCode::FAILURE;
+                }
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Prune failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run_verify(config: &backup::Config, args: Vec<String>) -> ExitCode {
+    let check_source = args.iter().any(|arg| arg == "--source");
+    let snapshot_id = args.iter()
+        .position(|arg| arg == "--snapshot")
+        .and_then(|i| args.get(i + 1));
+
+    match manifest::verify_snapshot(config, snapshot_id.map(String::as_str), check_source) {
+        Ok(report) => {
+            for issue in &report.issues {
+                println!("{}", issue);
+            }
+            println!(
+                "Snapshot {}: {} files verified, {} problems found",
+                report.snapshot_id,
+                report.verified,
+                report.issues.len()
+            );
+            if report.issues.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE }
+        }
+        Err(e) => {
+            eprintln!("Verify failed: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -3,15 +3,16 @@
 use std::{env, process, thread};
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use sysinfo::Pid;
 use eframe::{egui, Frame};
+use backmeup::{backup, volumes};
 
 mod cpu_evaluation;
+mod drive_watcher;
 mod mouse_tracker;
 mod audio;
-mod backup;
-mod display_window;
 
 #[cfg(target_os = "windows")]
 fn get_screen_resolution() -> (usize, usize){
@@ -68,7 +69,10 @@ fn main() {
     let (width, height) = get_screen_resolution();
     println!("Screen resolution: {}, {}", width, height);
 
-    mouse_tracker::track_mouse(width as f64, height as f64);
+    // A backup waiting for confirmation can be asked by the gesture or by plugging in a destination drive
+    let pending = Arc::new(Mutex::new(mouse_tracker::PendingBackup::default()));
+    mouse_tracker::track_mouse(width as f64, height as f64, Arc::clone(&pending));
+    drive_watcher::watch_drives(pending);
 
     let backup_pid = Pid::from_u32(process::id());
     cpu_evaluation::start_cpu_monitor(backup_pid, 120);
@@ -0,0 +1,452 @@
+use std::{fs, io};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use crate::backup::{BackupReport, WalkedSources, SNAPSHOT_METADATA_DIR};
+use crate::encryption::{self, Cipher, StoredFile};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::metadata::EntryMetadata;
+use crate::storage::{RemoteSnapshot, SeriesFiles, SnapshotFile};
+
+/// Extension added to the files compressed with zstd
+pub(crate) const COMPRESSED_EXTENSION: &str = "zst";
+
+/// Archive holding a whole snapshot in "tar-zst" mode, saved in its metadata folder
+pub(crate) const ARCHIVE_FILE: &str = "archive.tar.zst";
+
+// Formats that are already compressed, which zstd would only slow down
+const COMPRESSED_FORMATS: [&str; 36] = [
+    "zst", "gz", "tgz", "bz2", "xz", "lz4", "zip", "7z", "rar", "jar", "apk",
+    "docx", "xlsx", "pptx", "odt", "ods", "odp", "epub",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "m4a", "aac", "ogg", "opus", "flac",
+    "mp4", "m4v", "mkv", "mov", "webm",
+];
+
+/// `[compression]` section of config.toml
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct CompressionConfig {
+    // "none", "zstd" to compress each file, or "tar-zst" for a single archive per snapshot
+    #[serde(default = "default_mode")]
+    pub mode: String,
+    // zstd level, from 1 (fastest) to 22 (smallest)
+    #[serde(default = "default_level")]
+    pub level: i32,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig { mode: default_mode(), level: default_level() }
+    }
+}
+
+fn default_mode() -> String {
+    "none".to_string()
+}
+
+fn default_level() -> i32 {
+    3
+}
+
+impl CompressionConfig {
+    pub(crate) fn is_valid(&self) -> bool {
+        ["none", "zstd", "tar-zst"].contains(&self.mode.as_str())
+            && zstd::compression_level_range().contains(&self.level)
+    }
+
+    /// Level to compress the given file with in "zstd" mode, if it is worth compressing
+    pub(crate) fn level_for(&self, path: &Path) -> Option<i32> {
+        if self.mode == "zstd" && !is_already_compressed(path) {
+            Some(self.level)
+        } else {
+            None
+        }
+    }
+}
+
+pub(crate) fn is_already_compressed(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .map(|e| COMPRESSED_FORMATS.contains(&e.as_str()))
+        .unwrap_or(false)
+}
+
+/// Reader computing the BLAKE3 hash of everything read through it
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: blake3::Hasher::new() }
+    }
+
+    pub(crate) fn hash(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// Reader giving exactly `length` bytes: the first ones of `inner`, then zeros if it ends sooner
+/// or fails, in which case the error is kept in `error`
+struct FixedLengthReader<R> {
+    inner: R,
+    remaining: u64,
+    error: Option<io::Error>,
+}
+
+impl<R: Read> FixedLengthReader<R> {
+    fn new(inner: R, length: u64) -> Self {
+        FixedLengthReader { inner, remaining: length, error: None }
+    }
+}
+
+impl<R: Read> Read for FixedLengthReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let length = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        if length == 0 {
+            return Ok(0);
+        }
+
+        if self.error.is_none() {
+            match self.inner.read(&mut buf[..length]) {
+                Ok(0) => self.error = Some(io::Error::new(io::ErrorKind::UnexpectedEof, "the file shrank while being archived")),
+                Ok(read) => {
+                    self.remaining -= read as u64;
+                    return Ok(read);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+                Err(e) => self.error = Some(e),
+            }
+        }
+        buf[..length].fill(0);
+        self.remaining -= length as u64;
+        Ok(length)
+    }
+}
+
+/// Write the content of `reader` into `writer` as a file of a snapshot, compressed with zstd at
+/// the given level if any, and encrypted if a key is given.
+/// Returns the writer and the number of bytes read.
+pub(crate) fn write_stored<W: Write>(reader: &mut impl Read, writer: W, level: Option<i32>, cipher: Option<&Cipher>) -> io::Result<(W, u64)> {
+    let writer = StoredFile::new(writer, cipher)?;
+    match level {
+        Some(level) => {
+            let mut encoder = zstd::Encoder::new(writer, level)?;
+            let size = io::copy(reader, &mut encoder)?;
+            Ok((encoder.finish()?.finish()?, size))
+        }
+        None => {
+            let mut writer = writer;
+            let size = io::copy(reader, &mut writer)?;
+            Ok((writer.finish()?, size))
+        }
+    }
+}
+
+/// Open a file of a series, given relative to its folder, decrypting and decompressing it if needed
+pub(crate) fn open_stored<'a>(files: &SeriesFiles<'a>, relative_path: &Path, compressed: bool, cipher: Option<&Cipher>) -> io::Result<Box<dyn Read + 'a>> {
+    let reader = encryption::decrypt_reader(files.open(relative_path)?, cipher)?;
+    if compressed {
+        Ok(Box::new(zstd::Decoder::new(reader)?))
+    } else {
+        Ok(reader)
+    }
+}
+
+/// Path of a compressed chunk: `<hash>.zst`
+pub(crate) fn compressed_path(path: &Path) -> PathBuf {
+    let mut compressed_path = path.as_os_str().to_os_string();
+    compressed_path.push(".");
+    compressed_path.push(COMPRESSED_EXTENSION);
+    PathBuf::from(compressed_path)
+}
+
+/// Path of the compressed copy of a file of a snapshot: `.<name>.zst`.
+/// Hidden entries are never backed up, so no other file of the snapshot can have that name,
+/// not even a source file already ending with `.zst`.
+pub(crate) fn compressed_file_path(path: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(COMPRESSED_EXTENSION);
+    path.with_file_name(name)
+}
+
+/// Path of the file a stored path of a snapshot, written with `/`, is the compressed copy of,
+/// `None` if it is not a compressed copy
+pub(crate) fn original_path(stored_path: &str) -> Option<String> {
+    let (parent, name) = match stored_path.rsplit_once('/') {
+        Some((parent, name)) => (Some(parent), name),
+        None => (None, stored_path),
+    };
+    let name = name.strip_prefix('.')?.strip_suffix(&format!(".{}", COMPRESSED_EXTENSION))?;
+    match parent {
+        Some(parent) => Some(format!("{}/{}", parent, name)),
+        None => Some(name.to_string()),
+    }
+}
+
+/// Path of the stored copy of a file of a plain snapshot
+pub(crate) fn stored_path(snapshot_path: &Path, entry: &ManifestEntry, cipher: Option<&Cipher>) -> PathBuf {
+    let path = snapshot_path.join(encryption::stored_name(&entry.path, cipher));
+    if entry.compressed {
+        compressed_file_path(&path)
+    } else {
+        path
+    }
+}
+
+pub(crate) fn compress_bytes(data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    zstd::encode_all(data, level)
+}
+
+pub(crate) fn decompress_bytes(data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(data)
+}
+
+/// Back up the entries of the sources into a single tar archive compressed with zstd, each root
+/// in its folder.
+/// The archive is not split per file, so already compressed formats are compressed along with
+/// the rest, and incremental backups do not apply.
+/// Symlinks are only saved in the manifest, with the rest of the metadata of the entries.
+/// For a remote destination the archive is written straight into it.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn backup_to_archive(
+    sources: WalkedSources,
+    snapshot_path: &Path,
+    remote: Option<&RemoteSnapshot>,
+    follow_symlinks: bool,
+    level: i32,
+    cipher: Option<&Cipher>,
+    manifest: &mut Manifest,
+    report: &mut BackupReport,
+) -> io::Result<()> {
+    let metadata_path = snapshot_path.join(SNAPSHOT_METADATA_DIR);
+    fs::create_dir_all(&metadata_path)?;
+    let archive_file = SnapshotFile::create(snapshot_path, &Path::new(SNAPSHOT_METADATA_DIR).join(ARCHIVE_FILE), remote, None)?;
+    let encoder = zstd::Encoder::new(StoredFile::new(archive_file, cipher)?, level)?;
+    let mut builder = tar::Builder::new(encoder);
+
+    // The roots themselves are not saved, only what they hold
+    for (root, entry) in sources.entries.into_iter().filter(|(_, entry)| !entry.as_ref().is_ok_and(|e| e.depth() == 0)) {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                let path = e.path().map(|path| root.manifest_path(path)).unwrap_or_default();
+                report.add_failure(path, &io::Error::from(e));
+                continue;
+            }
+        };
+        let entry_name = root.manifest_path(entry.path());
+
+        // Sockets, devices and pipes are not saved
+        let file_type = entry.file_type();
+        if !file_type.is_dir() && !file_type.is_file() && !file_type.is_symlink() {
+            report.skipped += 1;
+            continue;
+        }
+        let entry_metadata = match EntryMetadata::read(entry.path(), &entry_name, follow_symlinks, &mut report.warnings) {
+            Ok(entry_metadata) => entry_metadata,
+            Err(e) => {
+                report.add_failure(entry_name, &e);
+                continue;
+            }
+        };
+
+        if file_type.is_dir() {
+            match builder.append_dir(&entry_name, entry.path()) {
+                Ok(()) => manifest.metadata.push(entry_metadata),
+                Err(e) => report.add_failure(entry_name, &e),
+            }
+        } else if file_type.is_symlink() {
+            report.add_file(0, 0);
+            manifest.metadata.push(entry_metadata);
+        } else {
+            let opened = fs::File::open(entry.path()).and_then(|file| file.metadata().map(|metadata| (file, metadata)));
+            let (file, metadata) = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    report.add_failure(entry_name, &e);
+                    continue;
+                }
+            };
+            let mut header = tar::Header::new_gnu();
+            header.set_metadata(&metadata);
+            // The size is written before the data: the entry always gets that many bytes, so that
+            // a file changing or failing while read does not shift the next entries. Only the
+            // archive failing to be written stops the backup.
+            let mut reader = FixedLengthReader::new(HashingReader::new(file), metadata.len());
+            builder.append_data(&mut header, &entry_name, &mut reader)?;
+
+            match reader.error {
+                None => {
+                    println!("Archived: {:?}", entry.path());
+                    report.add_file(metadata.len(), 0);
+                    manifest.entries.push(ManifestEntry::new(entry_name, &metadata, reader.inner.hash()));
+                    manifest.metadata.push(entry_metadata);
+                }
+                // The zeros of the entry are left out of the restores, the file not being in the manifest
+                Some(e) => report.add_failure(entry_name, &e),
+            }
+        }
+    }
+
+    report.skipped += sources.skipped;
+
+    // The space used is only known once the archive is complete
+    report.stored_bytes += builder.into_inner()?.finish()?.finish()?.finish()?;
+    Ok(())
+}
+
+fn archive_path(snapshot_id: &str) -> PathBuf {
+    Path::new(snapshot_id).join(SNAPSHOT_METADATA_DIR).join(ARCHIVE_FILE)
+}
+
+/// Check whether a snapshot holds a "tar-zst" archive
+pub(crate) fn is_archive_snapshot(files: &SeriesFiles, snapshot_id: &str) -> bool {
+    files.exists(&archive_path(snapshot_id))
+}
+
+/// Open the archive of a snapshot for reading, entry by entry
+pub(crate) fn open_archive<'a>(files: &SeriesFiles<'a>, snapshot_id: &str, cipher: Option<&Cipher>) -> io::Result<tar::Archive<Box<dyn Read + 'a>>> {
+    Ok(tar::Archive::new(open_stored(files, &archive_path(snapshot_id), true, cipher)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{self, Config};
+    use crate::backup::tests::{test_config, test_folder};
+    use crate::encryption::EncryptionConfig;
+    use crate::restore::{self, ConflictPolicy, RestoreOptions};
+
+    /// Back up `files` with per-file compression, then restore them into a new folder
+    fn restored_files(name: &str, files: &[(&str, Vec<u8>)], encryption: Option<EncryptionConfig>) -> (PathBuf, PathBuf) {
+        let folder = test_folder(&format!("compression-{}", name), files);
+        let config = Config {
+            compression: CompressionConfig { mode: "zstd".to_string(), level: 3 },
+            encryption,
+            ..test_config(&folder)
+        };
+        backup::run_job(&config, false).unwrap();
+
+        let options = RestoreOptions {
+            snapshot_id: None,
+            paths: Vec::new(),
+            target_path: Some(folder.join("restored")),
+            conflict_policy: ConflictPolicy::Skip,
+        };
+        let results = restore::restore_snapshot(&config, &options).unwrap();
+        assert_eq!(results.len(), files.len());
+        let series_path = backup::series_path(&config).unwrap();
+        let snapshot_path = series_path.join(backup::latest_snapshot(&series_path).unwrap());
+        (folder, snapshot_path)
+    }
+
+    #[test]
+    fn compressed_files_are_restored() {
+        let text = "compressible text ".repeat(1000).into_bytes();
+        let files = [("a.txt", text.clone()), ("folder/b.txt", text[..500].to_vec()), ("empty.txt", Vec::new())];
+        for (name, encryption) in [("plain", None), ("encrypted", Some(EncryptionConfig { passphrase: Some("secret".to_string()), key_file: None }))] {
+            let (folder, snapshot_path) = restored_files(name, &files, encryption.clone());
+            for (path, contents) in &files {
+                assert_eq!(fs::read(folder.join("restored").join(path)).unwrap(), *contents, "{}", path);
+            }
+            if encryption.is_none() {
+                assert!(fs::metadata(snapshot_path.join(".a.txt.zst")).unwrap().len() < text.len() as u64);
+                assert!(!snapshot_path.join("a.txt").exists());
+            }
+            fs::remove_dir_all(folder).unwrap();
+        }
+    }
+
+    #[test]
+    fn compressed_copy_does_not_replace_a_file_of_the_same_name() {
+        // `x` is compressed, while `x.zst` is already compressed and copied as it is
+        let files = [("x", b"compressed copy ".repeat(100)), ("x.zst", b"copied as it is".to_vec())];
+        let (folder, snapshot_path) = restored_files("collision", &files, None);
+        assert_eq!(fs::read(snapshot_path.join("x.zst")).unwrap(), files[1].1);
+        for (path, contents) in &files {
+            assert_eq!(fs::read(folder.join("restored").join(path)).unwrap(), *contents, "{}", path);
+        }
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn compressed_copies_are_named_after_their_file() {
+        assert_eq!(compressed_file_path(Path::new("snapshot/folder/x")), Path::new("snapshot/folder/.x.zst"));
+        assert_eq!(original_path("folder/.x.zst").as_deref(), Some("folder/x"));
+        assert_eq!(original_path(".x.zst").as_deref(), Some("x"));
+        assert_eq!(original_path("folder/x.zst"), None);
+        assert_eq!(original_path("folder/x"), None);
+    }
+
+    // Gives `data`, then fails
+    struct FailingReader<'a> {
+        data: &'a [u8],
+    }
+
+    impl Read for FailingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(io::Error::other("device error"));
+            }
+            self.data.read(buf)
+        }
+    }
+
+    #[test]
+    fn failed_entry_keeps_the_archive_aligned() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1000);
+        let mut reader = FixedLengthReader::new(FailingReader { data: &[7u8; 300] }, 1000);
+        builder.append_data(&mut header, "failed.bin", &mut reader).unwrap();
+        assert!(reader.error.is_some());
+
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        let mut reader = FixedLengthReader::new(&b"hello"[..], 5);
+        builder.append_data(&mut header, "next.txt", &mut reader).unwrap();
+        assert!(reader.error.is_none());
+
+        let archive = builder.into_inner().unwrap();
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let contents: Vec<(String, Vec<u8>)> = archive.entries().unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (entry.path().unwrap().display().to_string(), data)
+            })
+            .collect();
+        assert_eq!(contents.len(), 2);
+        assert_eq!(&contents[0].1[..300], &[7u8; 300][..]);
+        assert!(contents[0].1[300..].iter().all(|&byte| byte == 0));
+        assert_eq!(contents[1], ("next.txt".to_string(), b"hello".to_vec()));
+    }
+
+    #[test]
+    fn shrunk_file_is_padded() {
+        let mut data = Vec::new();
+        let mut reader = FixedLengthReader::new(&b"abc"[..], 8);
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abc\0\0\0\0\0");
+        assert_eq!(reader.error.unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        // Bytes appended while being read are left out
+        let mut data = Vec::new();
+        let mut reader = FixedLengthReader::new(&b"abcdef"[..], 4);
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abcd");
+        assert!(reader.error.is_none());
+    }
+}
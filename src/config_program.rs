@@ -1,8 +1,7 @@
 #![windows_subsystem = "windows"]
 
-mod display_window;
-
 use std::env;
+use backmeup::display_window;
 
 fn main(){
 
@@ -21,6 +20,11 @@ fn main(){
                 eprintln!("Errore nella generazione della GUI: {}", e);
             }
         },
+        "restore" => {
+            if let Err(e) = display_window::show_restore_gui() {
+                eprintln!("Errore nella generazione della GUI: {}", e);
+            }
+        },
         _ => {
             eprintln!("Last arg not present")
         }
@@ -1,53 +1,211 @@
-use eframe::egui::{self, CentralPanel, ComboBox};
+use eframe::egui::{self, CentralPanel, ComboBox, ScrollArea};
 use std::{env, fs};
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
 use std::io::Write;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use eframe::Frame;
 use egui::{Align, Color32, Context, Layout, RichText, ViewportCommand, Window};
+#[cfg(not(target_os = "linux"))]
 use rfd::FileDialog;
 #[cfg(target_os = "linux")]
 use std::process::Command;
-
-// Structure for the Config file
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
-struct Config {
-    source_path: String,
-    destination_path: String,
-    backup_type: String,
-    extensions_to_backup: Vec<String>,
-}
+use crate::backup::{self, default_concurrency, default_space_check, default_storage_format, BackupOutcome, BackupPlan, Config, Job};
+use crate::compression::CompressionConfig;
+use crate::encryption::EncryptionConfig;
+use crate::restore::{self, ConflictPolicy, RestoreOptions, RestoreOutcome, RestoreResult};
+use crate::storage;
+use crate::volumes::{self, Volume};
 
 // Application GUI
 #[derive(Default)]
 struct ConfigWindow {
-    source_path: String,
+    // Last saved configuration, keeps the settings not shown in the window
+    config: Config,
+    // Jobs of the configuration: the selected one is edited in the fields below, and saved
+    // back in the list when another one is selected
+    jobs: Vec<Job>,
+    selected_job: usize,
+    job_name: String,
+    // Source folders, one per line
+    sources: String,
     destination_path: String,
     backup_type: String,
     extensions_to_backup: String,
+    // Mount points of the volumes selected for a full-disk backup
+    volumes: Vec<String>,
+    // Volumes mounted when the window was opened
+    available_volumes: Vec<Volume>,
+    filters: String,
+    follow_symlinks: bool,
+    allow_nested_destination: bool,
+    incremental: bool,
+    storage_format: String,
+    concurrency: usize,
+    // Only warn when the destination seems too small, instead of refusing the backup
+    warn_low_space: bool,
+    compression: CompressionConfig,
+    encrypt: bool,
+    passphrase: String,
+    key_file: String,
+    // Plan of the last dry run, or its error message
+    dry_run: Option<Result<BackupPlan, String>>,
+    // Dry run being planned on another thread, which walks the whole source
+    planning: Option<mpsc::Receiver<Result<BackupPlan, String>>>,
+    // Containment checks of the destination of the selected job
+    destination_check: Option<DestinationCheck>,
+}
+
+/// Containment checks of the destination of a job, which canonicalize and look up its paths: only
+/// made again when the job or the nesting option changes, not at each frame
+struct DestinationCheck {
+    job: Job,
+    allow_nested_destination: bool,
+    // The destination is inside one of the sources
+    nested: bool,
+    // The destination is accepted, nested or not
+    valid: bool,
 }
 
 impl ConfigWindow {
     fn new(config_file_path: PathBuf) -> Self {
-        let mut window = Self::default();
+        let mut window = Self {
+            storage_format: default_storage_format(),
+            concurrency: default_concurrency(),
+            available_volumes: volumes::list_volumes(),
+            jobs: vec![Job::default()],
+            ..Default::default()
+        };
         if config_file_path.join("config.toml").exists() {
             window.read_config(config_file_path);
         }
         window
     }
 
-    // Method for saving the configuration file
-    fn save_config(&self, config_file_path: PathBuf) {
-        println!("{:?}", self.backup_type);
-        let config = Config {
-            source_path: self.source_path.clone(),
+    // Job with the values of the fields
+    fn current_job(&self) -> Job {
+        Job {
+            name: self.job_name.trim().to_string(),
+            sources: self.sources
+                .lines()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
             destination_path: self.destination_path.clone(),
             backup_type: self.backup_type.clone(),
             extensions_to_backup: self.extensions_to_backup
                 .split(',')
                 .map(|s| s.trim().to_string())
                 .collect(),
-        };
+            volumes: if self.backup_type == "full-disk" { self.volumes.clone() } else { Vec::new() },
+            filters: self.filters
+                .lines()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+        }
+    }
+
+    // Jobs with the values of the fields for the selected one
+    fn current_jobs(&self) -> Vec<Job> {
+        let mut jobs = self.jobs.clone();
+        jobs[self.selected_job] = self.current_job();
+        jobs
+    }
+
+    // Configuration with the values of the fields.
+    // A single job without a name is saved as a configuration without jobs.
+    fn current_config(&self) -> Config {
+        let jobs = self.current_jobs();
+        let shared = self.shared_config();
+        if jobs.len() == 1 && jobs[0].name.is_empty() {
+            return jobs[0].config(&shared);
+        }
+        Config { jobs, ..shared }
+    }
+
+    // Configuration of the selected job, with the values of the fields
+    fn selected_config(&self) -> Config {
+        self.current_job().config(&self.shared_config())
+    }
+
+    // Configuration with the values of the fields shared by the jobs, and no job
+    fn shared_config(&self) -> Config {
+        Config {
+            name: String::new(),
+            source_path: String::new(),
+            sources: Vec::new(),
+            destination_path: String::new(),
+            backup_type: String::new(),
+            extensions_to_backup: Vec::new(),
+            volumes: Vec::new(),
+            filters: Vec::new(),
+            follow_symlinks: self.follow_symlinks,
+            allow_nested_destination: self.allow_nested_destination,
+            incremental: self.incremental,
+            storage_format: self.storage_format.clone(),
+            concurrency: self.concurrency,
+            space_check: if self.warn_low_space { "warn".to_string() } else { default_space_check() },
+            compression: self.compression.clone(),
+            // The key file takes the place of the passphrase when given
+            encryption: self.encrypt.then(|| EncryptionConfig {
+                passphrase: Some(self.passphrase.clone()).filter(|p| !p.is_empty()),
+                key_file: Some(self.key_file.trim().to_string()).filter(|k| !k.is_empty()),
+            }),
+            jobs: Vec::new(),
+            ..self.config.clone()
+        }
+    }
+
+    // Whether the destination of the selected job is inside a source, and whether it is accepted
+    fn check_destination(&mut self) -> (bool, bool) {
+        let job = self.current_job();
+        let up_to_date = self.destination_check.as_ref().is_some_and(|check| {
+            check.job == job && check.allow_nested_destination == self.allow_nested_destination
+        });
+        if !up_to_date {
+            let config = self.selected_config();
+            self.destination_check = Some(DestinationCheck {
+                job,
+                allow_nested_destination: self.allow_nested_destination,
+                nested: !backup::nested_destinations(&config).is_empty(),
+                valid: backup::check_destination(&config).is_ok(),
+            });
+        }
+        let check = self.destination_check.as_ref().unwrap();
+        (check.nested, check.valid)
+    }
+
+    // Fill the fields of the job with the values of the job at `index`, which becomes the selected one
+    fn load_job(&mut self, index: usize) {
+        let job = self.jobs[index].clone();
+        self.selected_job = index;
+        self.job_name = job.name;
+        self.sources = job.sources.join("\n");
+        self.destination_path = job.destination_path;
+        self.backup_type = job.backup_type;
+        self.extensions_to_backup = job.extensions_to_backup.join(", ");
+        // Without a selection a full-disk backup saves the volume holding the sources
+        self.volumes = job.volumes;
+        if self.backup_type == "full-disk" && self.volumes.is_empty() {
+            let mount_points = job.sources.iter().filter_map(|source| volumes::volume_of(Path::new(source)));
+            self.volumes.extend(mount_points.map(|path| path.display().to_string()));
+            self.volumes.dedup();
+        }
+        self.filters = job.filters.join("\n");
+    }
+
+    // Keep the fields of the selected job in the list and edit the job at `index` instead
+    fn select_job(&mut self, index: usize) {
+        self.jobs[self.selected_job] = self.current_job();
+        self.load_job(index);
+    }
+
+    // Method for saving the configuration file
+    fn save_config(&self, config_file_path: PathBuf) {
+        println!("{:?}", self.backup_type);
+        let config = self.current_config();
         let toml_str = toml::to_string(&config).unwrap();
         let mut file = fs::File::create(config_file_path.join("config.toml")).unwrap();
         file.write_all(toml_str.as_bytes()).unwrap();
@@ -57,10 +215,34 @@ impl ConfigWindow {
     fn read_config(&mut self, config_file_path: PathBuf) {
         let config = fs::read_to_string(config_file_path.join("config.toml")).unwrap();
         let config: Config = toml::from_str(&config).unwrap();
-        self.source_path = config.source_path;
-        self.destination_path = config.destination_path;
-        self.backup_type = config.backup_type;
-        self.extensions_to_backup = config.extensions_to_backup.join(", ");
+        // A configuration without jobs is shown as a single job, named after the configuration
+        self.jobs = config.jobs.clone();
+        if self.jobs.is_empty() {
+            let sources = if config.sources.is_empty() { vec![config.source_path.clone()] } else { config.sources.clone() };
+            self.jobs.push(Job {
+                name: config.name.clone(),
+                sources: sources.into_iter().filter(|source| !source.is_empty()).collect(),
+                destination_path: config.destination_path.clone(),
+                backup_type: config.backup_type.clone(),
+                extensions_to_backup: config.extensions_to_backup.clone(),
+                volumes: config.volumes.clone(),
+                filters: config.filters.clone(),
+            });
+        }
+        self.load_job(0);
+        self.follow_symlinks = config.follow_symlinks;
+        self.allow_nested_destination = config.allow_nested_destination;
+        self.incremental = config.incremental;
+        self.storage_format = config.storage_format.clone();
+        self.concurrency = config.concurrency;
+        self.warn_low_space = config.space_check == "warn";
+        self.compression = config.compression.clone();
+        if let Some(encryption) = &config.encryption {
+            self.encrypt = true;
+            self.passphrase = encryption.passphrase.clone().unwrap_or_default();
+            self.key_file = encryption.key_file.clone().unwrap_or_default();
+        }
+        self.config = config;
     }
 
     // Method for selecting a directory using a file dialog
@@ -96,12 +278,35 @@ impl eframe::App for ConfigWindow {
         // Variable to track errors
         let mut error_message = String::new();
 
+        // A full-disk backup saves the volumes selected instead of the source paths
+        let full_disk = self.backup_type == "full-disk";
+        let job = self.current_job();
+        let source_selected = if full_disk { !self.volumes.is_empty() } else { !job.sources.is_empty() };
+        let source_is_destination = job.sources.contains(&self.destination_path);
+
+        // The paths are compared in their canonical form, to catch a destination inside the source
+        let (nested, accepted) = self.check_destination();
+        let nested_destination = source_selected && nested;
+        let destination_valid = !source_selected || ((full_disk || !source_is_destination) && accepted);
+
+        // The names of the jobs are checked together, and the jobs not shown only for their required fields
+        let jobs_error = self.current_config().job_configs().err();
+        let incomplete_job = self.current_jobs().into_iter().find(|job| {
+            job.destination_path.trim().is_empty()
+                || job.backup_type.is_empty()
+                || (job.sources.is_empty() && (job.backup_type != "full-disk" || job.volumes.is_empty()))
+        });
+
         // Function to check the validity of the fields
-        let is_valid = !self.source_path.trim().is_empty()
+        let is_valid = source_selected
+            && jobs_error.is_none()
+            && incomplete_job.is_none()
+            && (self.backup_type != "image" || job.sources.len() == 1)
             && !self.destination_path.trim().is_empty()
             && !self.backup_type.trim().is_empty()
             && (self.backup_type != "selective" || !self.extensions_to_backup.trim().is_empty())
-            && self.source_path != self.destination_path; // Controllo sui percorsi
+            && (!self.encrypt || !self.passphrase.is_empty() || !self.key_file.trim().is_empty())
+            && destination_valid; // Controllo sui percorsi
 
         CentralPanel::default().show(ctx, |ui| {
             // Spacing and global style
@@ -112,18 +317,81 @@ impl eframe::App for ConfigWindow {
             ui.heading("Backup Configuration");
             ui.add_space(10.0);
 
-            ui.label("Source Path:");
+            // Job selector: each job has its own sources, destination, type and filters
+            ui.label("Job:");
             ui.horizontal(|ui| {
-                // Source path text field
-                ui.text_edit_singleline(&mut self.source_path);
+                let mut selected = self.selected_job;
+                let label = |index: usize, name: &str| if name.is_empty() { format!("Job {}", index + 1) } else { name.to_string() };
+                ComboBox::from_id_salt("job")
+                    .selected_text(label(self.selected_job, &self.job_name))
+                    .show_ui(ui, |ui| {
+                        for (index, job) in self.jobs.iter().enumerate() {
+                            let name = if index == self.selected_job { &self.job_name } else { &job.name };
+                            ui.selectable_value(&mut selected, index, label(index, name));
+                        }
+                    });
+                if selected != self.selected_job {
+                    self.select_job(selected);
+                }
 
-                // File dialog button
-                if ui.button("...").clicked() {
-                    if let Some(path) = ConfigWindow::select_directory() {
-                        self.source_path = path;
-                    }
+                if ui.button("Add").clicked() {
+                    self.jobs[self.selected_job] = self.current_job();
+                    self.jobs.push(Job {
+                        name: format!("Job {}", self.jobs.len() + 1),
+                        backup_type: "directory".to_string(),
+                        ..Job::default()
+                    });
+                    self.load_job(self.jobs.len() - 1);
+                }
+                if ui.add_enabled(self.jobs.len() > 1, egui::Button::new("Remove")).clicked() {
+                    self.jobs.remove(self.selected_job);
+                    self.load_job(self.selected_job.min(self.jobs.len() - 1));
                 }
             });
+            ui.label("Job Name (used as folder of its snapshots):");
+            ui.text_edit_singleline(&mut self.job_name);
+            ui.add_space(5.0);
+
+            if full_disk {
+                // Volumes mounted now, and the ones selected before which are not connected
+                ui.label("Volumes:");
+                let mut choices: Vec<(String, String)> = self.available_volumes.iter()
+                    .map(|volume| (volume.mount_point.display().to_string(), volume.to_string()))
+                    .collect();
+                for mount_point in &self.volumes {
+                    if !choices.iter().any(|(path, _)| path == mount_point) {
+                        choices.push((mount_point.clone(), format!("{} (not connected)", mount_point)));
+                    }
+                }
+                ScrollArea::vertical().id_salt("volumes").max_height(90.0).show(ui, |ui| {
+                    for (mount_point, label) in choices {
+                        let mut selected = self.volumes.contains(&mount_point);
+                        if ui.checkbox(&mut selected, label).changed() {
+                            if selected {
+                                self.volumes.push(mount_point);
+                            } else {
+                                self.volumes.retain(|path| *path != mount_point);
+                            }
+                        }
+                    }
+                });
+            } else {
+                ui.label("Source Paths (one per line):");
+                ui.horizontal(|ui| {
+                    // Source paths text field
+                    ui.add(egui::TextEdit::multiline(&mut self.sources).desired_rows(2));
+
+                    // File dialog button, adding a folder to the sources
+                    if ui.button("...").clicked() {
+                        if let Some(path) = ConfigWindow::select_directory() {
+                            if !self.sources.trim().is_empty() && !self.sources.ends_with('\n') {
+                                self.sources.push('\n');
+                            }
+                            self.sources.push_str(&path);
+                        }
+                    }
+                });
+            }
             ui.add_space(5.0);
 
             ui.label("Destination Path:");
@@ -138,6 +406,10 @@ impl eframe::App for ConfigWindow {
                     }
                 }
             });
+            // Nesting is only offered when it applies, and the destination is then left out
+            if nested_destination {
+                ui.checkbox(&mut self.allow_nested_destination, "Allow destination inside source (left out of the backups)");
+            }
             ui.add_space(5.0);
 
             // Backup type selector
@@ -148,6 +420,7 @@ impl eframe::App for ConfigWindow {
                     ui.selectable_value(&mut self.backup_type, "full-disk".to_string(), "Full Disk");
                     ui.selectable_value(&mut self.backup_type, "directory".to_string(), "Directory");
                     ui.selectable_value(&mut self.backup_type, "selective".to_string(), "Selective");
+                    ui.selectable_value(&mut self.backup_type, "image".to_string(), "Disk Image");
 
                 });
 
@@ -157,9 +430,73 @@ impl eframe::App for ConfigWindow {
                 ui.text_edit_singleline(&mut self.extensions_to_backup);
             }
 
+            // A disk image is read as a whole: the options of the files do not apply to it
+            if self.backup_type == "image" {
+                ui.label("The source is a device, a partition or an image file, saved block by block.");
+            } else {
+                // Gitignore-style patterns, applied to every backup type
+                ui.label("Filters (one pattern per line, e.g. **/target/, *.tmp, !important.log):");
+                ui.add(egui::TextEdit::multiline(&mut self.filters).desired_rows(3));
+
+                // Symlinks are kept as symlinks unless followed
+                ui.checkbox(&mut self.follow_symlinks, "Follow symlinks (back up what they point to)");
+
+                // Incremental backups hard-link the files unchanged since the previous snapshot
+                ui.checkbox(&mut self.incremental, "Incremental (link unchanged files)");
+
+                // Storage format selector
+                ui.label("Storage Format:");
+                ComboBox::from_id_salt("storage_format")
+                    .selected_text(&self.storage_format)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.storage_format, "plain".to_string(), "Plain Copy");
+                        ui.selectable_value(&mut self.storage_format, "repository".to_string(), "Deduplicating Repository");
+                    });
+
+                // Files copied at the same time, only used by the plain format
+                if self.storage_format == "plain" {
+                    ui.horizontal(|ui| {
+                        ui.label("Parallel copies:");
+                        ui.add(egui::DragValue::new(&mut self.concurrency).range(1..=32));
+                    });
+                }
+            }
+
+            // Compression selector, with the zstd level when enabled
+            ui.label("Compression:");
+            ui.horizontal(|ui| {
+                ComboBox::from_id_salt("compression")
+                    .selected_text(&self.compression.mode)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(&mut self.compression.mode, "none".to_string(), "None");
+                        ui.selectable_value(&mut self.compression.mode, "zstd".to_string(), "zstd (per file)");
+                        ui.selectable_value(&mut self.compression.mode, "tar-zst".to_string(), "tar.zst (single archive)");
+                    });
+                if self.compression.mode != "none" {
+                    ui.label("Level:");
+                    ui.add(egui::DragValue::new(&mut self.compression.level).range(1..=22));
+                }
+            });
+
+            // The space needed is checked before each backup
+            ui.checkbox(&mut self.warn_low_space, "Only warn if the destination may be too small");
+
+            // Encryption, with the key derived from a passphrase or a key file
+            ui.checkbox(&mut self.encrypt, "Encrypt backups");
+            if self.encrypt {
+                ui.label("Passphrase:");
+                ui.add(egui::TextEdit::singleline(&mut self.passphrase).password(true));
+                ui.label("Key File (instead of the passphrase):");
+                ui.text_edit_singleline(&mut self.key_file);
+            }
+
             // Check if the fields are valid and set the error message if needed
-            if self.source_path.trim().is_empty() {
+            if full_disk && self.volumes.is_empty() {
+                error_message.push_str("At least one volume is required for full disk backup.\n");
+            } else if !full_disk && job.sources.is_empty() {
                 error_message.push_str("Source path is required.\n");
+            } else if self.backup_type == "image" && job.sources.len() > 1 {
+                error_message.push_str("An image backup reads a single device or image file.\n");
             }
             if self.destination_path.trim().is_empty() {
                 error_message.push_str("Destination path is required.\n");
@@ -167,8 +504,18 @@ impl eframe::App for ConfigWindow {
             if self.backup_type == "selective" && self.extensions_to_backup.trim().is_empty() {
                 error_message.push_str("Extensions are required for selective backup.\n");
             }
-            if self.source_path == self.destination_path {
+            if !full_disk && source_is_destination {
                 error_message.push_str("Source and destination paths cannot be the same.\n");
+            } else if !destination_valid {
+                error_message.push_str("The destination is inside the source.\n");
+            }
+            if self.encrypt && self.passphrase.is_empty() && self.key_file.trim().is_empty() {
+                error_message.push_str("A passphrase or a key file is required for encryption.\n");
+            }
+            if let Some(e) = &jobs_error {
+                error_message.push_str(&format!("{}.\n", e));
+            } else if let Some(job) = &incomplete_job {
+                error_message.push_str(&format!("Job \"{}\" needs a source, a destination and a type.\n", job.name));
             }
 
 
@@ -193,11 +540,32 @@ impl eframe::App for ConfigWindow {
                 );
 
                 // Save and close only if the button is clicked and valid
-                if save_button.clicked() {
+                if save_button.clicked() && is_valid {
                     self.save_config(config_file_path);
                     ctx.send_viewport_cmd(ViewportCommand::Close);
                 }
 
+                // Shows what the backup of the selected job would do, without saving or writing anything
+                if self.planning.is_some() {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Planning...");
+                    });
+                } else if ui.add_enabled(is_valid, egui::Button::new("Dry Run")).clicked() {
+                    let config = self.selected_config();
+                    let (sender, receiver) = mpsc::channel();
+                    thread::spawn(move || {
+                        let plan = match backup::run_job(&config, true) {
+                            Ok(BackupOutcome::Planned(plan)) => Ok(plan),
+                            Ok(BackupOutcome::Completed(_)) => Ok(BackupPlan::default()),
+                            Err(e) => Err(e.to_string()),
+                        };
+                        let _ = sender.send(plan);
+                    });
+                    self.dry_run = None;
+                    self.planning = Some(receiver);
+                }
+
                 // Shows the error message if there are invalid fields
                 if !is_valid {
                     ui.add_space(10.0);
@@ -205,6 +573,39 @@ impl eframe::App for ConfigWindow {
                 }
             });
         });
+
+        // The window is drawn again until the plan is ready
+        if let Some(receiver) = &self.planning {
+            match receiver.try_recv() {
+                Ok(plan) => {
+                    self.dry_run = Some(plan);
+                    self.planning = None;
+                }
+                Err(mpsc::TryRecvError::Empty) => ctx.request_repaint_after(Duration::from_millis(100)),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    self.dry_run = Some(Err("the planning stopped unexpectedly".to_string()));
+                    self.planning = None;
+                }
+            }
+        }
+
+        // Plan of the last dry run, until the window is closed
+        let mut open = self.dry_run.is_some();
+        if let Some(dry_run) = &self.dry_run {
+            Window::new("Dry Run").open(&mut open).show(ctx, |ui| {
+                ScrollArea::vertical().show(ui, |ui| match dry_run {
+                    Ok(plan) => {
+                        ui.label(RichText::new(plan.to_string()).monospace());
+                    }
+                    Err(message) => {
+                        ui.label(RichText::new(format!("Dry run failed: {}", message)).color(Color32::from_rgb(255, 0, 0)));
+                    }
+                });
+            });
+        }
+        if !open {
+            self.dry_run = None;
+        }
     }
 }
 
@@ -219,7 +620,7 @@ pub fn show_gui_if_needed() -> Result<(), eframe::Error> {
     config_file_path = exe_path.parent().unwrap().join("Resources/");
 
     let options = eframe::NativeOptions {
-        viewport: egui::ViewportBuilder::default().with_inner_size([350f32, 325f32]),
+        viewport: egui::ViewportBuilder::default().with_inner_size([350f32, 735f32]),
         ..Default::default()
     };
     eframe::run_native(
@@ -229,6 +630,199 @@ pub fn show_gui_if_needed() -> Result<(), eframe::Error> {
     )
 }
 
+// Window to restore a snapshot of the configured source
+struct RestoreWindow {
+    // Configuration of each job, and the index of the one whose snapshots are shown
+    jobs: Vec<Config>,
+    selected_job: usize,
+    config: Config,
+    snapshots: Vec<String>,
+    snapshot_id: String,
+    target_path: String,
+    paths: String,
+    conflict_policy: ConflictPolicy,
+    // Outcome of the last restore, or its error message
+    results: Option<Result<Vec<RestoreResult>, String>>,
+}
+
+impl RestoreWindow {
+    fn new(config: Config) -> Self {
+        let jobs = config.job_configs().unwrap_or_else(|_| vec![config]);
+        let mut window = Self {
+            jobs,
+            selected_job: 0,
+            config: Config::default(),
+            snapshots: Vec::new(),
+            snapshot_id: String::new(),
+            target_path: String::new(),
+            paths: String::new(),
+            conflict_policy: ConflictPolicy::Skip,
+            results: None,
+        };
+        window.select_job(0);
+        window
+    }
+
+    // Show the snapshots of the job at `index`, the latest one being selected
+    fn select_job(&mut self, index: usize) {
+        self.selected_job = index;
+        self.config = self.jobs[index].clone();
+        // Nothing to restore from a destination drive that is not connected
+        let Ok(series_path) = backup::series_path(&self.config) else {
+            self.snapshots = Vec::new();
+            self.snapshot_id = String::new();
+            self.results = None;
+            return;
+        };
+        // The snapshots of a remote destination are listed even when missing from the working copy
+        self.snapshots = storage::list_snapshots(&self.config)
+            .unwrap_or_else(|_| backup::list_snapshots(series_path.as_path()));
+        self.snapshot_id = backup::latest_snapshot(series_path.as_path())
+            .or_else(|| self.snapshots.last().cloned())
+            .unwrap_or_default();
+        self.results = None;
+    }
+
+    fn restore(&mut self) {
+        let options = RestoreOptions {
+            snapshot_id: Some(self.snapshot_id.clone()),
+            paths: self.paths
+                .split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect(),
+            target_path: Some(self.target_path.trim())
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            conflict_policy: self.conflict_policy,
+        };
+
+        self.results = Some(restore::restore_snapshot(&self.config, &options).map_err(|e| e.to_string()));
+    }
+}
+
+impl eframe::App for RestoreWindow {
+    fn update(&mut self, ctx: &Context, _frame: &mut Frame) {
+        CentralPanel::default().show(ctx, |ui| {
+            let spacing = ui.spacing_mut();
+            spacing.item_spacing = egui::Vec2::new(5.0, 7.0);
+            spacing.text_edit_width = 300.0;
+
+            ui.heading("Restore Backup");
+            ui.add_space(10.0);
+
+            // Only shown when there is a choice
+            if self.jobs.len() > 1 {
+                ui.label("Job:");
+                let mut selected = self.selected_job;
+                ComboBox::from_id_salt("job")
+                    .selected_text(&self.config.name)
+                    .show_ui(ui, |ui| {
+                        for (index, job) in self.jobs.iter().enumerate() {
+                            ui.selectable_value(&mut selected, index, &job.name);
+                        }
+                    });
+                if selected != self.selected_job {
+                    self.select_job(selected);
+                }
+            }
+
+            ui.label("Snapshot:");
+            ComboBox::from_id_salt("snapshot")
+                .selected_text(&self.snapshot_id)
+                .show_ui(ui, |ui| {
+                    for snapshot_id in self.snapshots.iter().rev() {
+                        ui.selectable_value(&mut self.snapshot_id, snapshot_id.clone(), snapshot_id);
+                    }
+                });
+
+            ui.label("Restore To (empty for the original location):");
+            ui.horizontal(|ui| {
+                ui.text_edit_singleline(&mut self.target_path);
+                if ui.button("...").clicked() {
+                    if let Some(path) = ConfigWindow::select_directory() {
+                        self.target_path = path;
+                    }
+                }
+            });
+
+            ui.label("Paths to Restore (comma separated, empty for everything):");
+            ui.text_edit_singleline(&mut self.paths);
+
+            ui.label("If a File Already Exists:");
+            ComboBox::from_id_salt("conflict_policy")
+                .selected_text(self.conflict_policy.as_str())
+                .show_ui(ui, |ui| {
+                    for policy in ConflictPolicy::ALL {
+                        ui.selectable_value(&mut self.conflict_policy, policy, policy.as_str());
+                    }
+                });
+
+            ui.add_space(10.0);
+
+            ui.with_layout(Layout::top_down(Align::Center), |ui| {
+                let restore_button = ui.add_enabled(
+                    !self.snapshot_id.is_empty(),
+                    egui::Button::new(RichText::new("Restore").strong().size(15.0)).min_size([120.0, 30.0].into()),
+                );
+                if restore_button.clicked() {
+                    self.restore();
+                }
+            });
+
+            // Per-file results of the last restore
+            match &self.results {
+                Some(Ok(results)) => {
+                    let failed = results.iter().filter(|r| matches!(r.outcome, RestoreOutcome::Failed(_))).count();
+                    let skipped = results.iter().filter(|r| matches!(r.outcome, RestoreOutcome::Skipped)).count();
+                    ui.label(format!(
+                        "{} files restored, {} skipped, {} failed",
+                        results.len() - failed - skipped,
+                        skipped,
+                        failed
+                    ));
+                    ScrollArea::vertical().show(ui, |ui| {
+                        for result in results {
+                            let text = RichText::new(result.to_string()).monospace();
+                            if matches!(result.outcome, RestoreOutcome::Failed(_)) {
+                                ui.label(text.color(Color32::from_rgb(255, 0, 0)));
+                            } else {
+                                ui.label(text);
+                            }
+                        }
+                    });
+                }
+                Some(Err(message)) => {
+                    ui.label(RichText::new(format!("Restore failed: {}", message)).color(Color32::from_rgb(255, 0, 0)));
+                }
+                None => {}
+            }
+        });
+    }
+}
+
+// Funzione per mostrare la finestra di ripristino
+pub fn show_restore_gui() -> Result<(), eframe::Error> {
+    let exe_path: PathBuf = PathBuf::from(env::current_exe().unwrap().parent().unwrap());
+    let config_file_path = exe_path.parent().unwrap().join("Resources/config.toml");
+
+    if !config_file_path.exists() {
+        eprintln!("Configuration file not found, nothing to restore.");
+        return Ok(());
+    }
+    let config = backup::read_config(config_file_path.to_str().unwrap());
+
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default().with_inner_size([400f32, 500f32]),
+        ..Default::default()
+    };
+    eframe::run_native(
+        "BackMeUp - Restore",
+        options,
+        Box::new(|_cc| Ok(Box::new(RestoreWindow::new(config)))),
+    )
+}
+
 #[derive(Default)]
 struct BackupWindow{
     should_close: Arc<Mutex<bool>>
@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::{env, fs, thread};
+use chrono::{Local, NaiveDateTime};
+use crate::audio::play_sound;
+use crate::backup::{self, Config};
+use crate::mouse_tracker::{self, PendingBackup};
+use crate::volumes;
+
+// Time between two looks at the mounted drives
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Watch the drives of the jobs with a `UUID=` or `LABEL=` destination, and back up a job when its
+/// drive is plugged in, if `[auto_backup]` is set and its latest snapshot is old enough.
+/// The backup waits for the confirmation gesture, unless `auto_confirm` is set.
+pub fn watch_drives(pending: Arc<Mutex<PendingBackup>>) {
+    let exe_path: PathBuf = PathBuf::from(env::current_exe().unwrap().parent().unwrap());
+    let config_file_path: PathBuf = exe_path.parent().unwrap().join("Resources/");
+
+    thread::spawn(move || {
+        let mut drives = Drives::new(load_config(&config_file_path).map(|config| connected_jobs(&config)).unwrap_or_default());
+        loop {
+            thread::sleep(POLL_INTERVAL);
+            let Some(config) = load_config(&config_file_path) else {
+                continue;
+            };
+            let now_connected = connected_jobs(&config);
+
+            let mut pending_ref = pending.lock().unwrap();
+            // Another backup is waiting for confirmation or running
+            let busy = pending_ref.enabled || pending_ref.running;
+            let auto_backup = config.auto_backup.clone();
+            let jobs = drives.look(now_connected, auto_backup.is_some(), busy, |job| {
+                auto_backup.as_ref().is_some_and(|auto_backup| is_due(&config, job, auto_backup.min_interval_hours))
+            });
+            let Some(auto_backup) = auto_backup.filter(|_| !jobs.is_empty()) else {
+                continue;
+            };
+            println!("Destination drive plugged in, backup of: {}", jobs.join(", "));
+
+            play_sound(0);
+            if auto_backup.auto_confirm {
+                pending_ref.running = true;
+                drop(pending_ref);
+                mouse_tracker::run_backup(config_file_path.as_path(), Some(&jobs));
+                pending.lock().unwrap().running = false;
+            } else {
+                pending_ref.enabled = true;
+                pending_ref.jobs = Some(jobs);
+                if let Err(e) = Command::new(exe_path.join("config_program")).arg("backup").spawn() {
+                    eprintln!("Failed to spawn process: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// Jobs whose drive was connected at the previous look, telling the drives plugged in since then
+struct Drives {
+    connected: HashSet<String>,
+}
+
+impl Drives {
+    /// The drives connected when the program starts are not backed up, only the ones plugged in
+    /// afterwards
+    fn new(connected: HashSet<String>) -> Self {
+        Drives { connected }
+    }
+
+    /// Jobs to back up, sorted, given the jobs whose drive is now connected: the ones whose drive
+    /// was plugged in since the previous look and that are due.
+    /// While another backup is `busy`, nothing is backed up and the drives plugged in stay new, to
+    /// be looked at again later. Without `auto_backup`, the drives plugged in are only recorded.
+    fn look(&mut self, now_connected: HashSet<String>, auto_backup: bool, busy: bool, is_due: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut plugged: Vec<String> = now_connected.iter()
+            .filter(|job| !self.connected.contains(*job))
+            .cloned()
+            .collect();
+        if !auto_backup || plugged.is_empty() {
+            self.connected = now_connected;
+            return Vec::new();
+        }
+        if busy {
+            return Vec::new();
+        }
+        self.connected = now_connected;
+        plugged.retain(|job| is_due(job));
+        plugged.sort();
+        plugged
+    }
+}
+
+/// Configuration of the jobs, `None` if missing or being saved by the configuration window
+fn load_config(config_file_path: &Path) -> Option<Config> {
+    fs::read_to_string(config_file_path.join("config.toml"))
+        .ok()
+        .and_then(|contents| toml::from_str::<Config>(&contents).ok())
+}
+
+/// Names of the jobs whose destination is on a drive connected and mounted
+fn connected_jobs(config: &Config) -> HashSet<String> {
+    let Ok(configs) = config.job_configs() else {
+        return HashSet::new();
+    };
+    configs.into_iter()
+        .filter(|config| {
+            volumes::parse_drive(&config.destination_path)
+                .is_some_and(|(drive, _)| matches!(volumes::drive_mount_point(&drive), Ok(Some(_))))
+        })
+        .map(|config| config.name)
+        .collect()
+}
+
+/// Whether the latest snapshot of the job is older than `min_interval_hours`, or missing
+fn is_due(config: &Config, job: &str, min_interval_hours: u32) -> bool {
+    let Ok(series_path) = config.job_config(Some(job).filter(|job| !job.is_empty()))
+        .and_then(|config| backup::series_path(&config)) else {
+        return false;
+    };
+    let Some(latest) = backup::latest_snapshot(series_path.as_path()).and_then(|id| backup::snapshot_time(&id)) else {
+        return true;
+    };
+    if !is_old_enough(latest, Local::now().naive_local(), min_interval_hours) {
+        println!("Backup of {} skipped: the latest one is from {}", if job.is_empty() { "the source" } else { job }, latest);
+        return false;
+    }
+    true
+}
+
+/// Whether a snapshot made at `latest` is at least `min_interval_hours` old at `now`
+fn is_old_enough(latest: NaiveDateTime, now: NaiveDateTime, min_interval_hours: u32) -> bool {
+    now - latest >= chrono::Duration::hours(min_interval_hours as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn drives_connected_at_startup_are_not_backed_up() {
+        let mut drives = Drives::new(jobs(&["laptop"]));
+        assert!(drives.look(jobs(&["laptop"]), true, false, |_| true).is_empty());
+
+        // Unplugged, then plugged in again
+        assert!(drives.look(jobs(&[]), true, false, |_| true).is_empty());
+        assert_eq!(drives.look(jobs(&["laptop"]), true, false, |_| true), ["laptop"]);
+        assert!(drives.look(jobs(&["laptop"]), true, false, |_| true).is_empty());
+    }
+
+    #[test]
+    fn only_plugged_drives_of_due_jobs_are_backed_up() {
+        let mut drives = Drives::new(jobs(&["photos"]));
+        let backed_up = drives.look(jobs(&["photos", "music", "laptop", "documents"]), true, false, |job| job != "music");
+        assert_eq!(backed_up, ["documents", "laptop"]);
+        // A job that was not due is not looked at again until its drive is plugged in again
+        assert!(drives.look(jobs(&["photos", "music", "laptop", "documents"]), true, false, |_| true).is_empty());
+    }
+
+    #[test]
+    fn drives_plugged_during_another_backup_wait_for_it() {
+        let mut drives = Drives::new(jobs(&[]));
+        assert!(drives.look(jobs(&["laptop"]), true, true, |_| true).is_empty());
+        assert_eq!(drives.look(jobs(&["laptop"]), true, false, |_| true), ["laptop"]);
+    }
+
+    #[test]
+    fn drives_plugged_without_auto_backup_are_only_recorded() {
+        let mut drives = Drives::new(jobs(&[]));
+        assert!(drives.look(jobs(&["laptop"]), false, false, |_| true).is_empty());
+        // Turning auto backup on does not back up the drive already there
+        assert!(drives.look(jobs(&["laptop"]), true, false, |_| true).is_empty());
+    }
+
+    #[test]
+    fn snapshots_are_due_after_the_interval() {
+        let latest = NaiveDateTime::parse_from_str("2024-05-01 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let hours = |hours: i64| latest + chrono::Duration::hours(hours);
+        assert!(!is_old_enough(latest, hours(23), 24));
+        assert!(is_old_enough(latest, hours(24), 24));
+        assert!(is_old_enough(latest, latest, 0));
+    }
+}
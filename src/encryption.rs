@@ -0,0 +1,551 @@
+use std::{fs, io};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, AeadCore, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use crate::backup::{BackupError, Config, SNAPSHOT_METADATA_DIR};
+
+// Key parameters of an encrypted series, saved next to its snapshots
+pub(crate) const KEY_FILE: &str = "key.json";
+
+// Marker written in the metadata folder of each encrypted snapshot
+const ENCRYPTED_MARKER: &str = "encrypted";
+
+// Known plaintext stored encrypted in the key file, to tell a wrong key before reading any data
+const KEY_CHECK: &[u8] = b"BackMeUp key check";
+
+// Files are encrypted in segments, each authenticated on its own
+const SEGMENT_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+// Random part of the nonces of a file, followed by the segment counter and the last-segment flag
+const NONCE_PREFIX_SIZE: usize = 19;
+
+// Longest encrypted name stored as is, leaving room for the `.` and `.zst` around compressed files
+// within the 255 bytes most filesystems allow. Longer ones are stored as a keyed hash.
+const MAX_STORED_NAME: usize = 240;
+// Start of the hashes standing for long names, a character encrypted names never contain
+const LONG_NAME_PREFIX: char = '~';
+
+/// `[encryption]` section of config.toml.
+/// The key is derived from the passphrase, or from the content of the key file if one is given.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct EncryptionConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+}
+
+/// Argon2id parameters of a series, the key itself is never saved
+#[derive(serde::Serialize, serde::Deserialize)]
+struct KeyParameters {
+    salt: String,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+    // KEY_CHECK encrypted with the key
+    check: String,
+}
+
+/// Keys of an encrypted series.
+/// Contents are encrypted with XChaCha20-Poly1305 and random nonces. Names and chunk ids must be
+/// the same in every snapshot to be linked or deduplicated, so they use separate keys and nonces
+/// derived from the plaintext.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+    name_key: [u8; 32],
+    id_key: [u8; 32],
+}
+
+impl Cipher {
+    fn new(master_key: &[u8; 32]) -> Self {
+        let content_key = blake3::derive_key("BackMeUp content key", master_key);
+        Cipher {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&content_key)),
+            name_key: blake3::derive_key("BackMeUp name key", master_key),
+            id_key: blake3::derive_key("BackMeUp chunk id key", master_key),
+        }
+    }
+
+    /// Encrypt a small piece of data as a whole: nonce followed by the ciphertext
+    pub(crate) fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self.aead.encrypt(&nonce, data).map_err(|_| encryption_failed())?;
+        Ok([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub(crate) fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < NONCE_SIZE {
+            return Err(decryption_failed());
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        self.aead.decrypt(XNonce::from_slice(nonce), ciphertext).map_err(|_| decryption_failed())
+    }
+
+    /// Encrypt a file or folder name. The result only uses `A-Z a-z 0-9 - _`, or for a name
+    /// too long once encrypted, is `~` followed by a keyed hash of the name, which only the
+    /// manifest of the snapshot turns back into the name.
+    pub(crate) fn encrypt_name(&self, name: &str) -> String {
+        let nonce_bytes = blake3::keyed_hash(&self.name_key, name.as_bytes());
+        if is_long_name(name) {
+            return format!("{}{}", LONG_NAME_PREFIX, URL_SAFE_NO_PAD.encode(nonce_bytes.as_bytes()));
+        }
+        let nonce = XNonce::from_slice(&nonce_bytes.as_bytes()[..NONCE_SIZE]);
+        let ciphertext = self.aead.encrypt(nonce, name.as_bytes()).expect("encryption of a name");
+        URL_SAFE_NO_PAD.encode([nonce.as_slice(), &ciphertext].concat())
+    }
+
+    pub(crate) fn decrypt_name(&self, name: &str) -> io::Result<String> {
+        let data = URL_SAFE_NO_PAD.decode(name).map_err(|_| decryption_failed())?;
+        String::from_utf8(self.decrypt(&data)?).map_err(|_| decryption_failed())
+    }
+
+    /// Decrypt a stored path, with `/` as separator, back to the original relative path
+    pub(crate) fn decrypt_path(&self, stored_path: &str) -> io::Result<String> {
+        let names = stored_path.split('/')
+            .filter(|name| !name.is_empty())
+            .map(|name| self.decrypt_name(name))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(names.join("/"))
+    }
+
+    /// Id of a chunk in the store, which does not reveal the hash of its content
+    pub(crate) fn chunk_id(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.id_key, data).to_hex().to_string()
+    }
+}
+
+/// Whether a name is stored as a hash, its encrypted form being too long
+fn is_long_name(name: &str) -> bool {
+    let encrypted_size = NONCE_SIZE + name.len() + TAG_SIZE;
+    (encrypted_size * 4).div_ceil(3) > MAX_STORED_NAME
+}
+
+/// Paths of a snapshot holding long names, and the folders leading to them, by the path they are
+/// stored at. These names cannot be decrypted, so they are found again from the paths listed in
+/// the manifest.
+pub(crate) fn long_name_paths<'a>(paths: impl IntoIterator<Item = &'a str>, cipher: &Cipher) -> HashMap<String, String> {
+    let mut long_paths = HashMap::new();
+    for path in paths.into_iter().filter(|path| path.split('/').any(is_long_name)) {
+        let mut original: Vec<&str> = Vec::new();
+        let mut stored: Vec<String> = Vec::new();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            original.push(name);
+            stored.push(cipher.encrypt_name(name));
+            if original.iter().any(|name| is_long_name(name)) {
+                long_paths.insert(stored.join("/"), original.join("/"));
+            }
+        }
+    }
+    long_paths
+}
+
+fn encryption_failed() -> io::Error {
+    io::Error::other("encryption failed")
+}
+
+fn decryption_failed() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "decryption failed: wrong key or corrupted data")
+}
+
+/// Key of the series of the configured source, if encryption is enabled.
+/// The parameters of the key are created on the first encrypted backup (`create`); afterwards the
+/// key is checked against them, so that a wrong passphrase is reported before touching any data.
+pub fn load_cipher(config: &Config, series_path: &Path, create: bool) -> Result<Option<Cipher>, BackupError> {
+    let Some(encryption) = &config.encryption else {
+        return Ok(None);
+    };
+
+    let secret = match (&encryption.key_file, &encryption.passphrase) {
+        (Some(key_file), _) if !key_file.trim().is_empty() => fs::read(key_file.trim())?,
+        (_, Some(passphrase)) if !passphrase.is_empty() => passphrase.as_bytes().to_vec(),
+        _ => return Err(BackupError::MissingEncryptionKey),
+    };
+
+    let key_path = series_path.join(KEY_FILE);
+    if key_path.exists() {
+        let contents = fs::read_to_string(&key_path)?;
+        let parameters: KeyParameters = serde_json::from_str(&contents).map_err(io::Error::other)?;
+        let cipher = Cipher::new(&derive_key(&secret, &parameters)?);
+
+        let check = URL_SAFE_NO_PAD.decode(&parameters.check).map_err(io::Error::other)?;
+        return match cipher.decrypt(&check) {
+            Ok(check) if check == KEY_CHECK => Ok(Some(cipher)),
+            _ => Err(BackupError::WrongEncryptionKey),
+        };
+    }
+
+    if !create {
+        // Nothing has been encrypted in this series yet
+        return Ok(None);
+    }
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut parameters = KeyParameters {
+        salt: URL_SAFE_NO_PAD.encode(salt),
+        memory_kib: Params::DEFAULT_M_COST,
+        iterations: Params::DEFAULT_T_COST,
+        parallelism: Params::DEFAULT_P_COST,
+        check: String::new(),
+    };
+    let cipher = Cipher::new(&derive_key(&secret, &parameters)?);
+    parameters.check = URL_SAFE_NO_PAD.encode(cipher.encrypt(KEY_CHECK)?);
+
+    fs::create_dir_all(series_path)?;
+    let contents = serde_json::to_string_pretty(&parameters).map_err(io::Error::other)?;
+    fs::write(&key_path, contents)?;
+    Ok(Some(cipher))
+}
+
+fn derive_key(secret: &[u8], parameters: &KeyParameters) -> io::Result<[u8; 32]> {
+    let salt = URL_SAFE_NO_PAD.decode(&parameters.salt).map_err(io::Error::other)?;
+    let params = Params::new(parameters.memory_kib, parameters.iterations, parameters.parallelism, Some(32))
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(secret, &salt, &mut key)
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(key)
+}
+
+/// Check whether a snapshot folder holds an encrypted snapshot
+pub(crate) fn is_encrypted_snapshot(snapshot_path: &Path) -> bool {
+    snapshot_path.join(SNAPSHOT_METADATA_DIR).join(ENCRYPTED_MARKER).is_file()
+}
+
+pub(crate) fn mark_encrypted(snapshot_path: &Path) -> io::Result<()> {
+    let metadata_path = snapshot_path.join(SNAPSHOT_METADATA_DIR);
+    fs::create_dir_all(&metadata_path)?;
+    fs::write(metadata_path.join(ENCRYPTED_MARKER), "")
+}
+
+/// Key to read a snapshot with: encrypted snapshots need one, the others are read as they are
+pub(crate) fn snapshot_cipher<'a>(snapshot_path: &Path, cipher: Option<&'a Cipher>) -> Result<Option<&'a Cipher>, BackupError> {
+    if !is_encrypted_snapshot(snapshot_path) {
+        return Ok(None);
+    }
+    cipher.map(Some).ok_or(BackupError::MissingEncryptionKey)
+}
+
+/// Path a file or folder is stored at inside a snapshot, with each name encrypted if needed
+pub(crate) fn stored_name(relative_path: &str, cipher: Option<&Cipher>) -> PathBuf {
+    match cipher {
+        Some(cipher) => relative_path.split('/')
+            .filter(|name| !name.is_empty())
+            .map(|name| cipher.encrypt_name(name))
+            .collect(),
+        None => PathBuf::from(relative_path),
+    }
+}
+
+/// Write a small file of a snapshot, such as its manifest, encrypted if needed
+pub(crate) fn write_file(path: &Path, contents: &[u8], cipher: Option<&Cipher>) -> io::Result<()> {
+    match cipher {
+        Some(cipher) => fs::write(path, cipher.encrypt(contents)?),
+        None => fs::write(path, contents),
+    }
+}
+
+pub(crate) fn read_file(path: &Path, cipher: Option<&Cipher>) -> io::Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.decrypt(&fs::read(path)?),
+        None => fs::read(path),
+    }
+}
+
+/// Read a file of a snapshot, decrypting it if needed
+pub(crate) fn decrypt_reader<'a>(reader: Box<dyn Read + 'a>, cipher: Option<&Cipher>) -> io::Result<Box<dyn Read + 'a>> {
+    match cipher {
+        Some(cipher) => Ok(Box::new(DecryptReader::new(reader, cipher)?)),
+        None => Ok(reader),
+    }
+}
+
+/// File of a snapshot being written, encrypted if needed: a file of the staged snapshot, or any
+/// writer such as an object sent to a remote destination.
+/// `finish` must be called to write the end of an encrypted file.
+pub(crate) enum StoredFile<W: Write> {
+    Plain(W),
+    Encrypted(EncryptWriter<W>),
+}
+
+impl<W: Write> StoredFile<W> {
+    pub(crate) fn new(writer: W, cipher: Option<&Cipher>) -> io::Result<Self> {
+        match cipher {
+            Some(cipher) => Ok(StoredFile::Encrypted(EncryptWriter::new(writer, cipher)?)),
+            None => Ok(StoredFile::Plain(writer)),
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            StoredFile::Plain(writer) => Ok(writer),
+            StoredFile::Encrypted(writer) => writer.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for StoredFile<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            StoredFile::Plain(writer) => writer.write(buf),
+            StoredFile::Encrypted(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            StoredFile::Plain(writer) => writer.flush(),
+            StoredFile::Encrypted(writer) => writer.flush(),
+        }
+    }
+}
+
+/// Size of a file of `size` bytes once stored, encrypted if a key is given: the nonce prefix,
+/// then each segment with its tag, an empty file still having its last segment
+pub(crate) fn stored_size(size: u64, cipher: Option<&Cipher>) -> u64 {
+    match cipher {
+        Some(_) => {
+            let segments = size.div_ceil(SEGMENT_SIZE as u64).max(1);
+            NONCE_PREFIX_SIZE as u64 + size + segments * TAG_SIZE as u64
+        }
+        None => size,
+    }
+}
+
+fn segment_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_SIZE - 1].copy_from_slice(&counter.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    *XNonce::from_slice(&nonce)
+}
+
+/// Writer encrypting a stream in segments of SEGMENT_SIZE bytes.
+/// Each nonce holds the position of its segment and whether it is the last one, so that
+/// segments cannot be reordered and a truncated file is detected.
+pub(crate) struct EncryptWriter<W: Write> {
+    inner: W,
+    aead: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    pub(crate) fn new(mut inner: W, cipher: &Cipher) -> io::Result<Self> {
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        OsRng.fill_bytes(&mut prefix);
+        inner.write_all(&prefix)?;
+        Ok(EncryptWriter { inner, aead: cipher.aead.clone(), prefix, counter: 0, buffer: Vec::new() })
+    }
+
+    fn write_segment(&mut self, segment: &[u8], last: bool) -> io::Result<()> {
+        let nonce = segment_nonce(&self.prefix, self.counter, last);
+        let ciphertext = self.aead.encrypt(&nonce, segment).map_err(|_| encryption_failed())?;
+        self.inner.write_all(&ciphertext)?;
+        self.counter = self.counter.checked_add(1).ok_or_else(encryption_failed)?;
+        Ok(())
+    }
+
+    /// Write the last segment and return the inner writer
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        let segment = std::mem::take(&mut self.buffer);
+        self.write_segment(&segment, true)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // A full segment is only written once more data follows, the last one is written by finish
+        while self.buffer.len() > SEGMENT_SIZE {
+            let rest = self.buffer.split_off(SEGMENT_SIZE);
+            let segment = std::mem::replace(&mut self.buffer, rest);
+            self.write_segment(&segment, false)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader decrypting a stream written by EncryptWriter
+pub(crate) struct DecryptReader<R: Read> {
+    inner: R,
+    aead: XChaCha20Poly1305,
+    prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    // Ciphertext read ahead, to know whether the current segment is the last one
+    pending: Vec<u8>,
+    current: io::Cursor<Vec<u8>>,
+    done: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    pub(crate) fn new(mut inner: R, cipher: &Cipher) -> io::Result<Self> {
+        let mut prefix = [0u8; NONCE_PREFIX_SIZE];
+        inner.read_exact(&mut prefix).map_err(|_| decryption_failed())?;
+        Ok(DecryptReader {
+            inner,
+            aead: cipher.aead.clone(),
+            prefix,
+            counter: 0,
+            pending: Vec::new(),
+            current: io::Cursor::new(Vec::new()),
+            done: false,
+        })
+    }
+
+    fn next_segment(&mut self) -> io::Result<()> {
+        let segment_size = SEGMENT_SIZE + TAG_SIZE;
+        let mut buffer = [0u8; 8192];
+        while self.pending.len() <= segment_size {
+            let read = self.inner.read(&mut buffer[..(segment_size + 1 - self.pending.len()).min(8192)])?;
+            if read == 0 {
+                break;
+            }
+            self.pending.extend_from_slice(&buffer[..read]);
+        }
+
+        let last = self.pending.len() <= segment_size;
+        let rest = if last { Vec::new() } else { self.pending.split_off(segment_size) };
+        let segment = std::mem::replace(&mut self.pending, rest);
+
+        let nonce = segment_nonce(&self.prefix, self.counter, last);
+        let plaintext = self.aead.decrypt(&nonce, segment.as_slice()).map_err(|_| decryption_failed())?;
+        self.current = io::Cursor::new(plaintext);
+        self.counter = self.counter.checked_add(1).ok_or_else(decryption_failed)?;
+        self.done = last;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() || self.done {
+                return Ok(read);
+            }
+            self.next_segment()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt_stream(data: &[u8], cipher: &Cipher) -> Vec<u8> {
+        let mut writer = EncryptWriter::new(Vec::new(), cipher).unwrap();
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt_stream(data: &[u8], cipher: &Cipher) -> io::Result<Vec<u8>> {
+        let mut plaintext = Vec::new();
+        DecryptReader::new(data, cipher)?.read_to_end(&mut plaintext)?;
+        Ok(plaintext)
+    }
+
+    #[test]
+    fn contents_round_trip() {
+        let cipher = Cipher::new(&[7; 32]);
+        assert_eq!(cipher.decrypt(&cipher.encrypt(b"manifest").unwrap()).unwrap(), b"manifest");
+
+        for size in [0, 1, SEGMENT_SIZE - 1, SEGMENT_SIZE, SEGMENT_SIZE + 1, 3 * SEGMENT_SIZE] {
+            let data: Vec<u8> = (0..size).map(|i| (i * 31 % 251) as u8).collect();
+            let stored = encrypt_stream(&data, &cipher);
+            assert_eq!(decrypt_stream(&stored, &cipher).unwrap(), data, "{} bytes", size);
+            // Random nonces: the same content is stored differently each time
+            assert_ne!(encrypt_stream(&data, &cipher), stored);
+        }
+    }
+
+    #[test]
+    fn tampered_contents_are_rejected() {
+        let cipher = Cipher::new(&[7; 32]);
+        let data = vec![5u8; 2 * SEGMENT_SIZE + 10];
+        let stored = encrypt_stream(&data, &cipher);
+
+        let mut flipped = stored.clone();
+        flipped[NONCE_PREFIX_SIZE + SEGMENT_SIZE + 3] ^= 1;
+        assert_eq!(decrypt_stream(&flipped, &cipher).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // Dropping whole segments is detected too, the last one being marked as such
+        let truncated = &stored[..NONCE_PREFIX_SIZE + SEGMENT_SIZE + TAG_SIZE];
+        assert_eq!(decrypt_stream(truncated, &cipher).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let other = Cipher::new(&[8; 32]);
+        assert!(decrypt_stream(&stored, &other).is_err());
+        assert!(other.decrypt(&cipher.encrypt(b"manifest").unwrap()).is_err());
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        let series_path = std::env::temp_dir().join(format!("backmeup-encryption-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&series_path);
+        let config_with = |passphrase: &str| Config {
+            encryption: Some(EncryptionConfig { passphrase: Some(passphrase.to_string()), key_file: None }),
+            ..Default::default()
+        };
+
+        assert!(load_cipher(&config_with("right"), &series_path, false).unwrap().is_none());
+        let cipher = load_cipher(&config_with("right"), &series_path, true).unwrap().unwrap();
+        let stored = cipher.encrypt(b"data").unwrap();
+
+        assert!(matches!(load_cipher(&config_with("wrong"), &series_path, true), Err(BackupError::WrongEncryptionKey)));
+        let cipher = load_cipher(&config_with("right"), &series_path, false).unwrap().unwrap();
+        assert_eq!(cipher.decrypt(&stored).unwrap(), b"data");
+
+        assert!(matches!(load_cipher(&config_with(""), &series_path, false), Err(BackupError::MissingEncryptionKey)));
+        fs::remove_dir_all(series_path).unwrap();
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let cipher = Cipher::new(&[7; 32]);
+        for name in ["a", "photo.jpg", "é🙂 spaced name", &"n".repeat(100)] {
+            let stored = cipher.encrypt_name(name);
+            assert_eq!(stored, cipher.encrypt_name(name));
+            assert_eq!(cipher.decrypt_name(&stored).unwrap(), name);
+        }
+    }
+
+    #[test]
+    fn long_names_fit_in_a_file_name() {
+        let cipher = Cipher::new(&[7; 32]);
+        for size in [150, 160, 200, 255] {
+            let name = "n".repeat(size);
+            let stored = cipher.encrypt_name(&name);
+            assert!(stored.len() + 4 <= 255, "{} bytes stored as {} bytes", size, stored.len());
+        }
+        assert!(!cipher.encrypt_name(&"n".repeat(100)).starts_with(LONG_NAME_PREFIX));
+        assert!(cipher.encrypt_name(&"n".repeat(200)).starts_with(LONG_NAME_PREFIX));
+    }
+
+    #[test]
+    fn long_names_are_found_from_the_manifest() {
+        let cipher = Cipher::new(&[7; 32]);
+        let folder = "f".repeat(200);
+        let file = format!("{}/short.txt", folder);
+        let paths = long_name_paths([file.as_str(), "plain/short.txt"], &cipher);
+        let stored_folder = cipher.encrypt_name(&folder);
+        assert_eq!(paths.get(&stored_folder), Some(&folder));
+        assert_eq!(paths.get(&format!("{}/{}", stored_folder, cipher.encrypt_name("short.txt"))), Some(&file));
+        assert_eq!(paths.len(), 2);
+    }
+}
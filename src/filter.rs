@@ -0,0 +1,139 @@
+use std::path::{Path, PathBuf};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use walkdir::DirEntry;
+use crate::backup::{self, BackupError, Config};
+use crate::volumes;
+
+/// Entries of a source folder to leave out of a backup, built from the backup type and the
+/// gitignore-style `filters` of the configuration, along with the destination when it is allowed
+/// inside the folder.
+/// Patterns are checked in order and the last one matching an entry decides: a pattern excludes
+/// what it matches, `!pattern` includes it again, and a trailing `/` only matches directories.
+pub(crate) struct EntryFilter {
+    matcher: Gitignore,
+    // Destination folder nested in the source, as met while walking it
+    destination: Option<PathBuf>,
+}
+
+impl EntryFilter {
+    /// Filter of `source`, the source folder or a volume of a full-disk backup: patterns starting
+    /// with `/` are relative to it.
+    pub(crate) fn new(config: &Config, source: &Path) -> Result<Self, BackupError> {
+        let mut builder = GitignoreBuilder::new(source);
+        let mut add = |pattern: &str| {
+            builder.add_line(None, pattern)
+                .map(|_| ())
+                .map_err(|e| BackupError::InvalidFilter(e.to_string()))
+        };
+
+        // Selective backups keep the folders and only the files with the selected extensions
+        if config.backup_type == "selective" {
+            add("*")?;
+            add("!*/")?;
+            for extension in &config.extensions_to_backup {
+                let extension = extension.trim().trim_start_matches('.');
+                if !extension.is_empty() {
+                    add(&format!("!*.{}", extension))?;
+                }
+            }
+        }
+
+        // Full-disk backups leave out swap, caches and trash unless the filters include them again
+        if config.backup_type == "full-disk" {
+            for pattern in volumes::DEFAULT_EXCLUDES {
+                add(pattern)?;
+            }
+        }
+
+        for pattern in &config.filters {
+            add(pattern)?;
+        }
+
+        let matcher = builder.build().map_err(|e| BackupError::InvalidFilter(e.to_string()))?;
+
+        let destination = backup::nested_destination(config, source)
+            .filter(|nested| config.allow_nested_destination && !nested.as_os_str().is_empty())
+            .map(|nested| source.join(nested));
+
+        Ok(EntryFilter { matcher, destination })
+    }
+
+    /// Check whether an entry met while walking the source is left out.
+    /// Used in `filter_entry`, so that excluded folders are not walked at all.
+    pub(crate) fn is_excluded(&self, entry: &DirEntry) -> bool {
+        self.is_destination(entry)
+            || (entry.depth() > 0 && self.matcher.matched(entry.path(), entry.file_type().is_dir()).is_ignore())
+    }
+
+    /// Check whether an entry is the destination of the backups, nested in the source
+    pub(crate) fn is_destination(&self, entry: &DirEntry) -> bool {
+        self.destination.as_deref() == Some(entry.path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use walkdir::WalkDir;
+
+    /// Paths of `files` kept by the filter of `config`, with `/` as separator
+    fn kept(name: &str, config: &Config, files: &[&str]) -> Vec<String> {
+        let source = env::temp_dir().join(format!("backmeup-filter-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&source);
+        for file in files {
+            let path = source.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+
+        let filter = EntryFilter::new(config, &source).unwrap();
+        let mut kept: Vec<String> = WalkDir::new(&source)
+            .into_iter()
+            .filter_entry(|entry| !filter.is_excluded(entry))
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.path().strip_prefix(&source).unwrap().to_string_lossy().replace('\\', "/"))
+            .collect();
+        kept.sort();
+        fs::remove_dir_all(&source).unwrap();
+        kept
+    }
+
+    fn config(backup_type: &str, extensions: &[&str], filters: &[&str]) -> Config {
+        Config {
+            backup_type: backup_type.to_string(),
+            extensions_to_backup: extensions.iter().map(|extension| extension.to_string()).collect(),
+            filters: filters.iter().map(|filter| filter.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn last_matching_pattern_decides() {
+        let files = ["a.txt", "a.tmp", "important.tmp", "app/target/out.bin", "target", "build/x.o", "src/build/y.rs"];
+        let config = config("directory", &[], &["**/target/", "*.tmp", "!important.tmp", "/build/"]);
+        // A trailing `/` only matches folders, and a leading `/` only the top of the source
+        assert_eq!(kept("ordered", &config, &files), ["a.txt", "important.tmp", "src/build/y.rs", "target"]);
+    }
+
+    #[test]
+    fn selective_backups_keep_the_selected_extensions() {
+        let files = ["a.txt", "b.md", "c.jpg", "notes/d.txt", "notes/drafts/e.md", "notes/f.bin", "drafts/g.txt"];
+        let config = config("selective", &["txt", " .md "], &["drafts/", "!f.bin"]);
+        assert_eq!(kept("selective", &config, &files), ["a.txt", "b.md", "notes/d.txt", "notes/f.bin"]);
+    }
+
+    #[test]
+    fn full_disk_backups_leave_out_caches_unless_included() {
+        let files = ["home/me/a.txt", "swapfile", "tmp/x", "var/cache/y", "var/log/z"];
+        assert_eq!(kept("full-disk", &config("full-disk", &[], &[]), &files), ["home/me/a.txt", "var/log/z"]);
+        assert_eq!(kept("full-disk-included", &config("full-disk", &[], &["!/var/cache/"]), &files), ["home/me/a.txt", "var/cache/y", "var/log/z"]);
+    }
+
+    #[test]
+    fn invalid_pattern_is_refused() {
+        let config = config("directory", &[], &["*.{txt"]);
+        assert!(matches!(EntryFilter::new(&config, Path::new("source")), Err(BackupError::InvalidFilter(_))));
+    }
+}
@@ -0,0 +1,308 @@
+use std::{fs, io};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use crate::backup::{BackupPlan, BackupReport};
+use crate::compression::{self, CompressionConfig};
+use crate::encryption::{self, Cipher, StoredFile};
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::storage::{RemoteSnapshot, SnapshotFile};
+
+/// Name of the image in the snapshot folder of an "image" backup
+pub(crate) const IMAGE_FILE: &str = "image.img";
+
+// The image is read and written by blocks of this size, and only the blocks holding data are stored
+const BLOCK_SIZE: usize = 1024 * 1024;
+
+// Progress is printed each time this percentage of the image is done
+const PROGRESS_STEP: u64 = 5;
+
+/// Image saved by an "image" backup, recorded in the manifest of its snapshot
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ImageInfo {
+    pub size: u64,
+    // Bytes in all-zero blocks, which take no space in the snapshot
+    pub zero_bytes: u64,
+}
+
+/// Size of a device, a partition or an image file. The metadata of a device gives no size, so
+/// the end of the file is looked for instead.
+fn image_size(file: &mut fs::File) -> io::Result<u64> {
+    let size = file.seek(SeekFrom::End(0))?;
+    file.rewind()?;
+    Ok(size)
+}
+
+/// Check whether a path is a block device, which is written in place on restore
+#[cfg(unix)]
+pub(crate) fn is_device(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_block_device())
+}
+
+/// Devices are opened by name (`\\.\PhysicalDrive0`, `\\.\C:`) and have no regular metadata
+#[cfg(not(unix))]
+pub(crate) fn is_device(path: &Path) -> bool {
+    path.to_string_lossy().starts_with(r"\\.\")
+}
+
+/// Plan of an image backup: the whole device is read, and the space needed is its size, as
+/// the zero blocks are only found while reading it
+pub(crate) fn plan_image(source: &Path, plan: &mut BackupPlan) -> io::Result<()> {
+    let size = image_size(&mut fs::File::open(source)?)?;
+    plan.files = 1;
+    plan.bytes = size;
+    plan.needed_bytes = size;
+    plan.largest_files.push((IMAGE_FILE.to_string(), size));
+    Ok(())
+}
+
+/// Back up a device, a partition or an image file as a single image, read sequentially.
+/// Without compression or encryption the image is a sparse file, with the all-zero blocks left
+/// as holes, that can be attached as it is (`losetup`, `hdiutil`). Otherwise it is a zstd stream,
+/// compressed at the configured level even when compression is off, so that the zero blocks take
+/// almost no space: an encrypted file cannot have holes, and neither can the object a remote
+/// destination receives.
+pub(crate) fn backup_image(
+    source: &Path,
+    snapshot_path: &Path,
+    remote: Option<&RemoteSnapshot>,
+    compression: &CompressionConfig,
+    cipher: Option<&Cipher>,
+    manifest: &mut Manifest,
+    report: &mut BackupReport,
+) -> io::Result<()> {
+    let mut reader = fs::File::open(source)?;
+    let metadata = reader.metadata()?;
+    if metadata.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Source is not a device or an image file: {}", source.display()),
+        ));
+    }
+    let size = image_size(&mut reader)?;
+    let image_path = encryption::stored_name(IMAGE_FILE, cipher);
+
+    let level = (compression.mode != "none" || cipher.is_some() || remote.is_some()).then_some(compression.level);
+    let (hash, zero_bytes, stored) = match level {
+        None => {
+            let mut file = fs::File::create(snapshot_path.join(&image_path))?;
+            let mut written = 0;
+            let (hash, zero_bytes) = copy_blocks(&mut reader, size, "Imaged", |block, zero| {
+                if zero {
+                    file.seek(SeekFrom::Current(block.len() as i64))?;
+                } else {
+                    file.write_all(block)?;
+                    written += block.len() as u64;
+                }
+                Ok(())
+            })?;
+            // The holes at the end only exist once the length is set
+            file.set_len(size)?;
+            (hash, zero_bytes, written)
+        }
+        Some(level) => {
+            let image_file = SnapshotFile::create(snapshot_path, &compression::compressed_file_path(&image_path), remote, None)?;
+            let mut encoder = zstd::Encoder::new(StoredFile::new(image_file, cipher)?, level)?;
+            let (hash, zero_bytes) = copy_blocks(&mut reader, size, "Imaged", |block, _| encoder.write_all(block))?;
+            let stored = encoder.finish()?.finish()?.finish()?;
+            (hash, zero_bytes, stored)
+        }
+    };
+
+    report.add_file(size, stored);
+    manifest.entries.push(ManifestEntry {
+        size,
+        compressed: level.is_some(),
+        ..ManifestEntry::new(IMAGE_FILE.to_string(), &metadata, hash)
+    });
+    manifest.image = Some(ImageInfo { size, zero_bytes });
+    Ok(())
+}
+
+/// Write an image read from a snapshot into `file`, from its start, checking it against the hash
+/// of its manifest entry. With `sparse` the zero blocks are left as holes, which a device cannot
+/// have: its previous content would remain there.
+pub(crate) fn write_image(reader: &mut dyn Read, file: &mut fs::File, entry: &ManifestEntry, sparse: bool) -> io::Result<()> {
+    let (hash, _) = copy_blocks(reader, entry.size, "Restored", |block, zero| {
+        if zero && sparse {
+            file.seek(SeekFrom::Current(block.len() as i64)).map(|_| ())
+        } else {
+            file.write_all(block)
+        }
+    })?;
+    if sparse {
+        file.set_len(entry.size)?;
+    }
+    file.sync_all()?;
+
+    if hash != entry.hash {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the image does not match its checksum"));
+    }
+    Ok(())
+}
+
+/// Hash of the current content of a device or an image file, to compare it with a snapshot
+pub fn hash_image(source: &Path) -> io::Result<String> {
+    let mut reader = fs::File::open(source)?;
+    let size = image_size(&mut reader)?;
+    let (hash, _) = copy_blocks(&mut reader, size, "Hashed", |_, _| Ok(()))?;
+    Ok(hash)
+}
+
+/// Read `size` bytes of an image block by block, handing each block to `write` along with
+/// whether it is all zeros, and printing the progress along the way.
+/// Returns the hash of the image and the bytes of its zero blocks.
+fn copy_blocks(
+    reader: &mut dyn Read,
+    size: u64,
+    action: &str,
+    mut write: impl FnMut(&[u8], bool) -> io::Result<()>,
+) -> io::Result<(String, u64)> {
+    let mut buffer = vec![0; BLOCK_SIZE];
+    let mut hasher = blake3::Hasher::new();
+    let mut done = 0;
+    let mut zero_bytes = 0;
+    let mut reported = 0;
+
+    while done < size {
+        let length = (size - done).min(BLOCK_SIZE as u64) as usize;
+        let block = &mut buffer[..length];
+        reader.read_exact(block)?;
+        hasher.update(block);
+
+        let zero = block.iter().all(|&byte| byte == 0);
+        if zero {
+            zero_bytes += length as u64;
+        }
+        write(block, zero)?;
+
+        done += length as u64;
+        let percent = done * 100 / size;
+        if percent >= reported + PROGRESS_STEP || done == size {
+            let mebibytes = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+            println!("{}: {:.1} of {:.1} MiB ({}%)", action, mebibytes(done), mebibytes(size), percent);
+            reported = percent;
+        }
+    }
+
+    Ok((hasher.finalize().to_hex().to_string(), zero_bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::backup::{self, BackupOutcome, Config};
+    use crate::backup::tests::{test_config, test_folder};
+    use crate::encryption::EncryptionConfig;
+    use crate::manifest::{self, IssueKind};
+    use crate::restore::{self, ConflictPolicy, RestoreOptions, RestoreOutcome};
+    use crate::storage::tests::test_bytes;
+
+    /// Image file of two data blocks, two zero blocks and a partial data block at the end
+    fn test_image() -> Vec<u8> {
+        let mut image = test_bytes(BLOCK_SIZE);
+        image.extend(vec![0; BLOCK_SIZE]);
+        image.extend(test_bytes(BLOCK_SIZE + 3).split_off(3));
+        image.extend(vec![0; BLOCK_SIZE]);
+        image.extend(test_bytes(1000));
+        image
+    }
+
+    /// Image backup of `test_image`, returning its folder, its configuration and its report
+    fn backed_up_image(name: &str, compression: &str, encryption: Option<EncryptionConfig>) -> (PathBuf, Config, BackupReport) {
+        let folder = test_folder(&format!("image-{}", name), &[("disk.img", test_image())]);
+        let config = Config {
+            source_path: folder.join("source/disk.img").display().to_string(),
+            backup_type: "image".to_string(),
+            compression: CompressionConfig { mode: compression.to_string(), level: 3 },
+            encryption,
+            ..test_config(&folder)
+        };
+        let BackupOutcome::Completed(report) = backup::run_job(&config, false).unwrap() else {
+            panic!("no backup was made");
+        };
+        (folder, config, report)
+    }
+
+    /// Restore the latest image into `restored.img`
+    fn restored_image(folder: &Path, config: &Config) -> RestoreOutcome {
+        let options = RestoreOptions {
+            snapshot_id: None,
+            paths: Vec::new(),
+            target_path: Some(folder.join("restored.img")),
+            conflict_policy: ConflictPolicy::Skip,
+        };
+        let mut results = restore::restore_snapshot(config, &options).unwrap();
+        assert_eq!(results.len(), 1);
+        results.remove(0).outcome
+    }
+
+    #[test]
+    fn zero_blocks_are_left_as_holes_and_restored() {
+        let (folder, config, report) = backed_up_image("sparse", "none", None);
+        let size = test_image().len() as u64;
+        assert_eq!((report.copied, report.bytes, report.stored_bytes), (1, size, size - 2 * BLOCK_SIZE as u64));
+
+        let series_path = backup::series_path(&config).unwrap();
+        let snapshot_path = series_path.join(&report.snapshot_id);
+        let image = snapshot_path.join(IMAGE_FILE);
+        assert_eq!(fs::metadata(&image).unwrap().len(), size);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert!(fs::metadata(&image).unwrap().blocks() * 512 < size - BLOCK_SIZE as u64);
+        }
+        let info = manifest::read_manifest(&snapshot_path, None).unwrap().image.unwrap();
+        assert_eq!((info.size, info.zero_bytes), (size, 2 * BLOCK_SIZE as u64));
+
+        assert!(matches!(restored_image(&folder, &config), RestoreOutcome::Restored));
+        assert!(fs::read(folder.join("restored.img")).unwrap() == test_image());
+        let report = manifest::verify_snapshot(&config, None, true).unwrap();
+        assert_eq!((report.verified, report.issues.len()), (1, 0), "{:?}", report.issues);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn compressed_and_encrypted_images_are_restored() {
+        let encryption = EncryptionConfig { passphrase: Some("secret".to_string()), key_file: None };
+        for (name, compression, encryption) in [("compressed", "zstd", None), ("encrypted", "none", Some(encryption))] {
+            let (folder, config, report) = backed_up_image(name, compression, encryption.clone());
+            // The zero blocks are compressed away, there being no holes in a stream
+            assert!(report.stored_bytes < report.bytes - 2 * BLOCK_SIZE as u64, "{}", name);
+            let snapshot_path = backup::series_path(&config).unwrap().join(&report.snapshot_id);
+            assert!(!snapshot_path.join(IMAGE_FILE).exists(), "{}", name);
+            if encryption.is_none() {
+                assert!(snapshot_path.join(compression::compressed_file_path(Path::new(IMAGE_FILE))).exists());
+            }
+
+            assert!(matches!(restored_image(&folder, &config), RestoreOutcome::Restored), "{}", name);
+            assert!(fs::read(folder.join("restored.img")).unwrap() == test_image(), "{}", name);
+            fs::remove_dir_all(folder).unwrap();
+        }
+    }
+
+    #[test]
+    fn corrupted_image_fails_its_checksum() {
+        let (folder, config, report) = backed_up_image("corrupted", "none", None);
+        let image = backup::series_path(&config).unwrap().join(&report.snapshot_id).join(IMAGE_FILE);
+        let mut file = fs::OpenOptions::new().write(true).open(&image).unwrap();
+        file.seek(SeekFrom::Start(2 * BLOCK_SIZE as u64 + 10)).unwrap();
+        file.write_all(b"corrupted").unwrap();
+        drop(file);
+
+        match restored_image(&folder, &config) {
+            RestoreOutcome::Failed(e) => assert_eq!(e.kind(), io::ErrorKind::InvalidData),
+            outcome => panic!("unexpected outcome: {:?}", outcome),
+        }
+        // Neither the image nor its temporary file are left behind
+        let mut names: Vec<_> = fs::read_dir(&folder).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        names.sort();
+        assert_eq!(names, ["destination", "source"]);
+
+        let report = manifest::verify_snapshot(&config, None, false).unwrap();
+        assert_eq!(report.verified, 0);
+        assert_eq!((report.issues.len(), &report.issues[0].kind), (1, &IssueKind::Corrupted));
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+// Backup engine and windows shared by the programs
+
+pub mod backup;
+pub mod compression;
+pub mod display_window;
+pub mod encryption;
+pub mod filter;
+pub mod image;
+pub mod lock;
+pub mod manifest;
+pub mod metadata;
+pub mod repository;
+pub mod restore;
+pub mod retention;
+pub mod s3;
+pub mod sftp;
+pub mod staging;
+pub mod storage;
+pub mod volumes;
+pub mod webdav;
@@ -0,0 +1,131 @@
+use std::{fmt, fs, io};
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use chrono::{DateTime, Local};
+use sysinfo::System;
+use crate::backup::{self, BackupError};
+
+/// Lock file in the series folder, held while a backup or a prune writes to it
+const LOCK_FILE: &str = "backup.lock";
+
+/// Process holding the lock of a destination
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LockOwner {
+    pub host: String,
+    pub pid: u32,
+    // Seconds since the Unix epoch
+    pub started: i64,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        LockOwner {
+            host: System::host_name().unwrap_or_default(),
+            pid: std::process::id(),
+            started: backup::unix_seconds(SystemTime::now()),
+        }
+    }
+
+    // Owner of a lock still being written, or that the system does not let read
+    fn unknown() -> Self {
+        LockOwner { host: "unknown host".to_string(), pid: 0, started: 0 }
+    }
+}
+
+impl fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let started = DateTime::from_timestamp(self.started, 0)
+            .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_default();
+        write!(f, "process {} on {}, started at {}", self.pid, self.host, started)
+    }
+}
+
+/// Lock of a series folder, released when dropped.
+/// Only one backup at a time can write to a destination, whatever machine or program runs it.
+/// The file is locked by the operating system, which releases it when its process stops, even
+/// without dropping it; its contents only tell the other processes who holds it.
+pub struct DestinationLock {
+    file: fs::File,
+}
+
+impl DestinationLock {
+    /// Take the lock of a series folder
+    pub fn acquire(series_path: &Path) -> Result<Self, BackupError> {
+        fs::create_dir_all(series_path)?;
+        let path = series_path.join(LOCK_FILE);
+        // Never truncated before being locked, so that the owner can be read by the others
+        let mut file = fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(fs::TryLockError::WouldBlock) => {
+                return Err(BackupError::DestinationBusy(read_owner(&path).unwrap_or_else(LockOwner::unknown)));
+            }
+            Err(fs::TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        let contents = serde_json::to_vec_pretty(&LockOwner::current()).map_err(io::Error::other)?;
+        file.set_len(0)?;
+        file.write_all(&contents)?;
+        Ok(DestinationLock { file })
+    }
+}
+
+impl Drop for DestinationLock {
+    // The file is kept: removing it would let a process lock the removed file while another one
+    // locks a new file at the same path
+    fn drop(&mut self) {
+        if let Err(e) = self.file.set_len(0) {
+            eprintln!("Failed to clear the owner of the lock: {}", e);
+        }
+    }
+}
+
+fn read_owner(path: &Path) -> Option<LockOwner> {
+    serde_json::from_slice(&fs::read(path).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::tests::{test_config, test_folder};
+    use crate::backup::BackupOutcome;
+    use crate::storage::tests::temp_folder;
+
+    #[test]
+    fn second_lock_is_refused_until_the_first_is_released() {
+        let folder = temp_folder("lock");
+        let lock = DestinationLock::acquire(&folder).unwrap();
+        match DestinationLock::acquire(&folder) {
+            Err(BackupError::DestinationBusy(owner)) => {
+                assert_eq!(owner.pid, std::process::id());
+                assert_eq!(owner.host, System::host_name().unwrap_or_default());
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("the destination was locked twice"),
+        }
+
+        drop(lock);
+        assert_eq!(fs::metadata(folder.join(LOCK_FILE)).unwrap().len(), 0);
+        drop(DestinationLock::acquire(&folder).unwrap());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn backup_of_a_locked_destination_is_refused() {
+        let folder = test_folder("lock", &[("a.txt", "file")]);
+        let config = test_config(&folder);
+        let series_path = backup::series_path(&config).unwrap();
+
+        let lock = DestinationLock::acquire(&series_path).unwrap();
+        assert!(matches!(backup::run_job(&config, false), Err(BackupError::DestinationBusy(_))));
+        assert!(backup::list_snapshots(&series_path).is_empty());
+        // A dry run writes nothing, and does not need the lock
+        assert!(matches!(backup::run_job(&config, true), Ok(BackupOutcome::Planned(_))));
+
+        drop(lock);
+        assert!(matches!(backup::run_job(&config, false), Ok(BackupOutcome::Completed(_))));
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
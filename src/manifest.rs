@@ -0,0 +1,439 @@
+use std::{fmt, fs, io};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::Path;
+use crate::backup::{self, BackupError, Config, SourceRoot, SourceWalk, SNAPSHOT_METADATA_DIR};
+use crate::compression;
+use crate::encryption::{self, Cipher};
+use crate::image::{self, ImageInfo};
+use crate::metadata::EntryMetadata;
+use crate::repository;
+use crate::storage::{self, SeriesFiles};
+
+// Manifest of a snapshot, saved in its metadata folder
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Checksums of all the files of a snapshot, written at the end of each backup
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Manifest {
+    pub snapshot_id: String,
+    pub source_path: String,
+    pub entries: Vec<ManifestEntry>,
+    // Metadata of every file, folder and symlink, reapplied on restore
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub metadata: Vec<EntryMetadata>,
+    // Original location of each source or volume of a snapshot of several folders, by the folder it is saved in
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roots: BTreeMap<String, String>,
+    // Image of an "image" snapshot, whose only entry it is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<ImageInfo>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct ManifestEntry {
+    // Path relative to the source, with `/` as separator
+    pub path: String,
+    pub size: u64,
+    pub modified: i64,
+    // Unix permission bits, 0 where not available
+    pub mode: u32,
+    // BLAKE3 hash of the content
+    pub hash: String,
+    // Stored compressed with zstd, as `.<name>.zst` in the folder of the file
+    #[serde(default)]
+    pub compressed: bool,
+}
+
+impl Manifest {
+    pub(crate) fn new(snapshot_id: &str, source_path: &str) -> Self {
+        Manifest {
+            snapshot_id: snapshot_id.to_string(),
+            source_path: source_path.to_string(),
+            entries: Vec::new(),
+            metadata: Vec::new(),
+            roots: BTreeMap::new(),
+            image: None,
+        }
+    }
+}
+
+impl ManifestEntry {
+    pub(crate) fn new(path: String, metadata: &fs::Metadata, hash: String) -> Self {
+        ManifestEntry {
+            path,
+            size: metadata.len(),
+            modified: metadata.modified().map(backup::unix_seconds).unwrap_or(0),
+            mode: file_mode(metadata),
+            hash,
+            compressed: false,
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0
+}
+
+pub(crate) fn write_manifest(snapshot_path: &Path, manifest: &Manifest, cipher: Option<&Cipher>) -> io::Result<()> {
+    let metadata_path = snapshot_path.join(SNAPSHOT_METADATA_DIR);
+    fs::create_dir_all(&metadata_path)?;
+    let contents = serde_json::to_vec_pretty(manifest).map_err(io::Error::other)?;
+    encryption::write_file(&metadata_path.join(MANIFEST_FILE), &contents, cipher)
+}
+
+pub(crate) fn read_manifest(snapshot_path: &Path, cipher: Option<&Cipher>) -> io::Result<Manifest> {
+    let contents = encryption::read_file(&snapshot_path.join(SNAPSHOT_METADATA_DIR).join(MANIFEST_FILE), cipher)?;
+    serde_json::from_slice(&contents).map_err(io::Error::other)
+}
+
+/// Entries of the manifest of a snapshot by path, empty if the snapshot has no manifest
+pub(crate) fn manifest_entries(snapshot_path: &Path, cipher: Option<&Cipher>) -> HashMap<String, ManifestEntry> {
+    read_manifest(snapshot_path, cipher)
+        .map(|manifest| manifest.entries.into_iter().map(|e| (e.path.clone(), e)).collect())
+        .unwrap_or_default()
+}
+
+/// BLAKE3 hash of everything `reader` returns
+pub(crate) fn hash_reader<R: Read>(mut reader: R) -> io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; 1024 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    hash_reader(fs::File::open(path)?)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IssueKind {
+    // In the manifest, but not in the checked location
+    Missing,
+    // Content differs from the manifest
+    Corrupted,
+    // In the checked location, but not in the manifest
+    Extra,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum IssueLocation {
+    Destination,
+    Source,
+}
+
+#[derive(Debug)]
+pub struct VerifyIssue {
+    pub path: String,
+    pub kind: IssueKind,
+    pub location: IssueLocation,
+    pub detail: Option<String>,
+}
+
+impl fmt::Display for VerifyIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match (&self.kind, &self.location) {
+            (IssueKind::Missing, IssueLocation::Destination) => "missing",
+            (IssueKind::Corrupted, IssueLocation::Destination) => "corrupted",
+            (IssueKind::Extra, IssueLocation::Destination) => "extra",
+            (IssueKind::Missing, IssueLocation::Source) => "missing in source",
+            (IssueKind::Corrupted, IssueLocation::Source) => "changed in source",
+            (IssueKind::Extra, IssueLocation::Source) => "new in source",
+        };
+        write!(f, "{:<18} {}", kind, self.path)?;
+        if let Some(detail) = &self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    pub snapshot_id: String,
+    // Files found intact in the destination
+    pub verified: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+/// Re-hash the files of a snapshot and compare them with its manifest.
+/// With `check_source`, the live source is compared with the manifest too, to find the files
+/// changed, deleted or created since the backup.
+pub fn verify_snapshot(config: &Config, snapshot_id: Option<&str>, check_source: bool) -> Result<VerifyReport, BackupError> {
+    let series_path = backup::series_path(config)?;
+    // The files of a remote destination are read from it, and the metadata from the working copy
+    let backend = storage::open_backend(config)?;
+    if let Some(backend) = backend.as_deref() {
+        storage::fetch_snapshot(backend, series_path.as_path(), snapshot_id)?;
+    }
+    let files = SeriesFiles::new(series_path.as_path(), backend.as_deref());
+    let snapshot_id = match snapshot_id {
+        Some(snapshot_id) => snapshot_id.to_string(),
+        None => backup::latest_snapshot(series_path.as_path()).ok_or(BackupError::SnapshotNotFound)?,
+    };
+    let snapshot_path = series_path.join(&snapshot_id);
+    if !snapshot_path.is_dir() {
+        return Err(BackupError::SnapshotNotFound);
+    }
+
+    let cipher = encryption::load_cipher(config, series_path.as_path(), false)?;
+    let cipher = encryption::snapshot_cipher(snapshot_path.as_path(), cipher.as_ref())?;
+
+    let manifest = read_manifest(snapshot_path.as_path(), cipher)?;
+    let mut report = VerifyReport { snapshot_id, verified: 0, issues: Vec::new() };
+
+    if repository::is_repository_snapshot(snapshot_path.as_path()) {
+        verify_repository(&files, snapshot_path.as_path(), &manifest, cipher, &mut report)?;
+    } else if compression::is_archive_snapshot(&files, &report.snapshot_id) {
+        let snapshot_id = report.snapshot_id.clone();
+        verify_archive(&files, &snapshot_id, &manifest, cipher, &mut report)?;
+    } else {
+        let snapshot_id = report.snapshot_id.clone();
+        verify_plain(&files, &snapshot_id, &manifest, cipher, &mut report)?;
+    }
+
+    if check_source {
+        match manifest.image {
+            Some(_) => verify_image_source(config, &manifest, &mut report),
+            None => verify_source(config, &backup::source_roots(config)?, &manifest, &mut report),
+        }
+    }
+
+    Ok(report)
+}
+
+fn verify_plain(files: &SeriesFiles, snapshot_id: &str, manifest: &Manifest, cipher: Option<&Cipher>, report: &mut VerifyReport) -> io::Result<()> {
+    for entry in &manifest.entries {
+        let path = compression::stored_path(Path::new(snapshot_id), entry, cipher);
+        match compression::open_stored(files, &path, entry.compressed, cipher).and_then(hash_reader) {
+            Ok(hash) if hash == entry.hash => report.verified += 1,
+            Ok(_) => report.issues.push(issue(&entry.path, IssueKind::Corrupted, IssueLocation::Destination, None)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                report.issues.push(issue(&entry.path, IssueKind::Missing, IssueLocation::Destination, None))
+            }
+            Err(e) => report.issues.push(issue(&entry.path, IssueKind::Corrupted, IssueLocation::Destination, Some(e.to_string()))),
+        }
+    }
+
+    // Names of the stored copies, compressed and encrypted ones included
+    let known: HashMap<String, ()> = manifest.entries.iter()
+        .map(|e| (repository::index_path(&compression::stored_path(Path::new(""), e, cipher)), ()))
+        .collect();
+    for path in files.snapshot_files(snapshot_id)? {
+        if !known.contains_key(&path) {
+            // Extra files of an encrypted snapshot are reported by their stored name
+            report.issues.push(issue(&path, IssueKind::Extra, IssueLocation::Destination, None));
+        }
+    }
+    Ok(())
+}
+
+/// Rebuild each file from its chunks, checking the chunks against their hash along the way
+fn verify_repository(
+    files: &SeriesFiles,
+    snapshot_path: &Path,
+    manifest: &Manifest,
+    cipher: Option<&Cipher>,
+    report: &mut VerifyReport,
+) -> io::Result<()> {
+    let index = repository::read_index(snapshot_path, cipher)?;
+    let chunks: HashMap<&str, &Vec<String>> = index.entries.iter()
+        .filter(|e| !e.is_dir)
+        .map(|e| (e.path.as_str(), &e.chunks))
+        .collect();
+
+    for entry in &manifest.entries {
+        let Some(file_chunks) = chunks.get(entry.path.as_str()) else {
+            report.issues.push(issue(&entry.path, IssueKind::Missing, IssueLocation::Destination, None));
+            continue;
+        };
+
+        let mut hasher = blake3::Hasher::new();
+        let mut problem = None;
+        for hash in file_chunks.iter() {
+            match repository::read_chunk(files, hash, cipher) {
+                Ok(data) if repository::chunk_id(&data, cipher) == *hash => {
+                    hasher.update(&data);
+                }
+                Ok(_) => problem = Some(format!("chunk {} is corrupted", hash)),
+                Err(_) => problem = Some(format!("chunk {} is missing", hash)),
+            }
+            if problem.is_some() {
+                break;
+            }
+        }
+
+        if problem.is_none() && hasher.finalize().to_hex().as_str() != entry.hash {
+            problem = Some("content does not match".to_string());
+        }
+
+        match problem {
+            None => report.verified += 1,
+            Some(detail) => report.issues.push(issue(&entry.path, IssueKind::Corrupted, IssueLocation::Destination, Some(detail))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Hash each file of the archive while reading it in sequence
+fn verify_archive(files: &SeriesFiles, snapshot_id: &str, manifest: &Manifest, cipher: Option<&Cipher>, report: &mut VerifyReport) -> io::Result<()> {
+    let mut remaining: HashMap<&str, &ManifestEntry> = manifest.entries.iter().map(|e| (e.path.as_str(), e)).collect();
+    let mut archive = compression::open_archive(files, snapshot_id, cipher)?;
+
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = repository::index_path(&entry.path()?);
+
+        match remaining.remove(path.as_str()) {
+            // A file that failed while being archived, left out of the restores too
+            None => continue,
+            Some(expected) => match hash_reader(entry) {
+                Ok(hash) if hash == expected.hash => report.verified += 1,
+                Ok(_) => report.issues.push(issue(&path, IssueKind::Corrupted, IssueLocation::Destination, None)),
+                Err(e) => report.issues.push(issue(&path, IssueKind::Corrupted, IssueLocation::Destination, Some(e.to_string()))),
+            },
+        }
+    }
+
+    for path in remaining.into_keys() {
+        report.issues.push(issue(path, IssueKind::Missing, IssueLocation::Destination, None));
+    }
+    Ok(())
+}
+
+fn verify_source(config: &Config, roots: &[SourceRoot], manifest: &Manifest, report: &mut VerifyReport) {
+    let mut remaining: HashMap<&str, &ManifestEntry> = manifest.entries.iter().map(|e| (e.path.as_str(), e)).collect();
+
+    for (root, entry) in SourceWalk::new(roots, config.follow_symlinks, false)
+        .filter_map(|(root, e)| e.ok().map(|e| (root, e)))
+        .filter(|(_, e)| e.file_type().is_file())
+    {
+        let path = root.manifest_path(entry.path());
+
+        match remaining.remove(path.as_str()) {
+            None => report.issues.push(issue(&path, IssueKind::Extra, IssueLocation::Source, None)),
+            Some(expected) => match hash_file(entry.path()) {
+                Ok(hash) if hash == expected.hash => {}
+                Ok(_) => report.issues.push(issue(&path, IssueKind::Corrupted, IssueLocation::Source, None)),
+                Err(e) => report.issues.push(issue(&path, IssueKind::Corrupted, IssueLocation::Source, Some(e.to_string()))),
+            },
+        }
+    }
+
+    for path in remaining.into_keys() {
+        report.issues.push(issue(path, IssueKind::Missing, IssueLocation::Source, None));
+    }
+}
+
+/// Hash the device or the image file an image was read from, compared as a whole
+fn verify_image_source(config: &Config, manifest: &Manifest, report: &mut VerifyReport) {
+    let Some(expected) = manifest.entries.first() else { return };
+    match image::hash_image(Path::new(&config.source_path)) {
+        Ok(hash) if hash == expected.hash => {}
+        Ok(_) => report.issues.push(issue(&expected.path, IssueKind::Corrupted, IssueLocation::Source, None)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            report.issues.push(issue(&expected.path, IssueKind::Missing, IssueLocation::Source, None))
+        }
+        Err(e) => report.issues.push(issue(&expected.path, IssueKind::Corrupted, IssueLocation::Source, Some(e.to_string()))),
+    }
+}
+
+fn issue(path: &str, kind: IssueKind, location: IssueLocation, detail: Option<String>) -> VerifyIssue {
+    VerifyIssue { path: path.to_string(), kind, location, detail }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::tests::{test_config, test_folder};
+    use std::path::PathBuf;
+
+    fn backed_up_folder(name: &str, storage_format: &str) -> (PathBuf, Config) {
+        let folder = test_folder(&format!("verify-{}", name), &[("a.txt", "first file"), ("folder/b.txt", "second file")]);
+        let config = Config { storage_format: storage_format.to_string(), ..test_config(&folder) };
+        backup::run_job(&config, false).unwrap();
+        (folder, config)
+    }
+
+    fn flip_byte(path: &Path) {
+        let mut data = fs::read(path).unwrap();
+        data[3] ^= 1;
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn flipped_byte_in_a_plain_snapshot_is_reported() {
+        let (folder, config) = backed_up_folder("plain", "plain");
+        assert_eq!(verify_snapshot(&config, None, true).unwrap().verified, 2);
+
+        let series_path = backup::series_path(&config).unwrap();
+        let snapshot_path = series_path.join(backup::latest_snapshot(&series_path).unwrap());
+        flip_byte(&snapshot_path.join("folder/b.txt"));
+
+        let report = verify_snapshot(&config, None, false).unwrap();
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!((report.issues[0].path.as_str(), &report.issues[0].kind), ("folder/b.txt", &IssueKind::Corrupted));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn flipped_byte_in_a_chunk_is_reported() {
+        let (folder, config) = backed_up_folder("repository", "repository");
+        assert_eq!(verify_snapshot(&config, None, true).unwrap().verified, 2);
+
+        let series_path = backup::series_path(&config).unwrap();
+        let chunk = repository::chunk_id(b"first file", None);
+        flip_byte(&series_path.join(repository::CHUNKS_DIR).join(&chunk[..2]).join(&chunk));
+
+        let report = verify_snapshot(&config, None, false).unwrap();
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.issues.len(), 1);
+        assert_eq!((report.issues[0].path.as_str(), &report.issues[0].kind), ("a.txt", &IssueKind::Corrupted));
+        assert_eq!(report.issues[0].detail.as_deref(), Some(format!("chunk {} is corrupted", chunk).as_str()));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn source_changes_since_the_backup_are_reported() {
+        let (folder, config) = backed_up_folder("source", "plain");
+        fs::write(folder.join("source/a.txt"), "first file, changed").unwrap();
+        fs::remove_file(folder.join("source/folder/b.txt")).unwrap();
+        fs::write(folder.join("source/folder/c.txt"), "new file").unwrap();
+
+        // The snapshot itself is intact
+        let report = verify_snapshot(&config, None, false).unwrap();
+        assert_eq!((report.verified, report.issues.len()), (2, 0), "{:?}", report.issues);
+
+        let report = verify_snapshot(&config, None, true).unwrap();
+        assert_eq!(report.verified, 2);
+        let mut issues: Vec<(&str, &IssueKind, &IssueLocation)> = report.issues.iter()
+            .map(|issue| (issue.path.as_str(), &issue.kind, &issue.location))
+            .collect();
+        issues.sort_by_key(|(path, _, _)| *path);
+        assert_eq!(issues, [
+            ("a.txt", &IssueKind::Corrupted, &IssueLocation::Source),
+            ("folder/b.txt", &IssueKind::Missing, &IssueLocation::Source),
+            ("folder/c.txt", &IssueKind::Extra, &IssueLocation::Source),
+        ]);
+        assert_eq!(report.issues.iter().find(|issue| issue.path == "a.txt").unwrap().to_string(), format!("{:<18} a.txt", "changed in source"));
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use std::{fs, io};
+use std::collections::BTreeMap;
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use filetime::FileTime;
+
+/// Type of an entry of the source
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// Seconds and nanoseconds since the Unix epoch
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+pub(crate) struct Timestamp {
+    pub seconds: i64,
+    pub nanos: u32,
+}
+
+impl From<FileTime> for Timestamp {
+    fn from(time: FileTime) -> Self {
+        Timestamp { seconds: time.unix_seconds(), nanos: time.nanoseconds() }
+    }
+}
+
+impl From<Timestamp> for FileTime {
+    fn from(time: Timestamp) -> Self {
+        FileTime::from_unix_time(time.seconds, time.nanos)
+    }
+}
+
+/// Metadata of a file, folder or symlink of the source, saved in the manifest of the snapshot
+/// and reapplied when the entry is restored.
+/// POSIX ACLs are stored by Linux as the `system.posix_acl_access` and `system.posix_acl_default`
+/// extended attributes, so they are saved along with the other attributes.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct EntryMetadata {
+    // Path relative to the source, with `/` as separator
+    pub path: String,
+    pub kind: EntryKind,
+    // Target of a symlink, as written in the link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_target: Option<String>,
+    pub modified: Timestamp,
+    pub accessed: Timestamp,
+    // Unix permission bits, 0 where not available
+    pub mode: u32,
+    // Owner, only restored when running as root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    // Extended attributes, with their value in base64
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl EntryMetadata {
+    /// Read the metadata of an entry of the source.
+    /// With `follow_symlinks` a symlink is read as the file or folder it points to. The extended
+    /// attributes that cannot be read are left out, with a message added to `warnings`.
+    pub(crate) fn read(path: &Path, relative_path: &str, follow_symlinks: bool, warnings: &mut Vec<String>) -> io::Result<Self> {
+        let metadata = if follow_symlinks { fs::metadata(path)? } else { fs::symlink_metadata(path)? };
+
+        let kind = if metadata.is_dir() {
+            EntryKind::Directory
+        } else if metadata.file_type().is_symlink() {
+            EntryKind::Symlink
+        } else {
+            EntryKind::File
+        };
+        let link_target = match kind {
+            EntryKind::Symlink => Some(fs::read_link(path)?.to_string_lossy().to_string()),
+            _ => None,
+        };
+
+        let (mode, uid, gid) = owner_and_mode(&metadata);
+
+        Ok(EntryMetadata {
+            path: relative_path.to_string(),
+            kind,
+            link_target,
+            modified: FileTime::from_last_modification_time(&metadata).into(),
+            accessed: FileTime::from_last_access_time(&metadata).into(),
+            mode,
+            uid,
+            gid,
+            xattrs: read_xattrs(path, relative_path, follow_symlinks, warnings),
+        })
+    }
+
+    /// Apply the saved metadata to a restored entry.
+    /// The owner is only changed when running as root, and the extended attributes that the
+    /// filesystem or the user cannot set are left out. The times are set last, as changing the
+    /// other metadata would update them.
+    pub(crate) fn apply(&self, path: &Path) -> io::Result<()> {
+        if is_root() {
+            set_owner(path, self.uid, self.gid)?;
+        }
+
+        // The permissions of a symlink are those of its target
+        if self.kind != EntryKind::Symlink && self.mode != 0 {
+            set_mode(path, self.mode)?;
+        }
+
+        for (name, value) in &self.xattrs {
+            let value = STANDARD.decode(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            match set_xattr(path, name, &value) {
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied || is_unsupported(&e) => {}
+                result => result?,
+            }
+        }
+
+        filetime::set_symlink_file_times(path, self.accessed.into(), self.modified.into())
+    }
+}
+
+/// Create a symlink pointing to `target`, which is not required to exist
+#[cfg(unix)]
+pub(crate) fn create_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks can only be restored on Unix"))
+}
+
+#[cfg(unix)]
+fn owner_and_mode(metadata: &fs::Metadata) -> (u32, Option<u32>, Option<u32>) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.mode(), Some(metadata.uid()), Some(metadata.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner_and_mode(_metadata: &fs::Metadata) -> (u32, Option<u32>, Option<u32>) {
+    (0, None, None)
+}
+
+#[cfg(unix)]
+fn is_root() -> bool {
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_root() -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn set_owner(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    std::os::unix::fs::lchown(path, uid, gid)
+}
+
+#[cfg(not(unix))]
+fn set_owner(_path: &Path, _uid: Option<u32>, _gid: Option<u32>) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Extended attributes of an entry, empty where the filesystem does not support them.
+/// An attribute that cannot be read, e.g. without the permission or because the entry changed
+/// meanwhile, does not prevent saving the entry: it is left out and reported in `warnings`.
+#[cfg(unix)]
+fn read_xattrs(path: &Path, relative_path: &str, follow_symlinks: bool, warnings: &mut Vec<String>) -> BTreeMap<String, String> {
+    let mut xattrs = BTreeMap::new();
+    let names = match if follow_symlinks { xattr::list_deref(path) } else { xattr::list(path) } {
+        Ok(names) => names,
+        Err(e) if is_unsupported(&e) => return xattrs,
+        Err(e) => {
+            warnings.push(format!("Extended attributes of {} not saved: {}", relative_path, e));
+            return xattrs;
+        }
+    };
+
+    for name in names {
+        let value = match if follow_symlinks { xattr::get_deref(path, &name) } else { xattr::get(path, &name) } {
+            Ok(value) => value,
+            Err(e) => {
+                warnings.push(format!("Extended attribute {} of {} not saved: {}", name.to_string_lossy(), relative_path, e));
+                continue;
+            }
+        };
+        if let (Some(name), Some(value)) = (name.to_str(), value) {
+            xattrs.insert(name.to_string(), STANDARD.encode(value));
+        }
+    }
+    xattrs
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path, _relative_path: &str, _follow_symlinks: bool, _warnings: &mut Vec<String>) -> BTreeMap<String, String> {
+    BTreeMap::new()
+}
+
+/// Filesystems without extended attributes fail with `EOPNOTSUPP`, which std does not map to
+/// `ErrorKind::Unsupported`
+#[cfg(unix)]
+fn is_unsupported(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::Unsupported || error.raw_os_error() == Some(libc::EOPNOTSUPP)
+}
+
+#[cfg(not(unix))]
+fn is_unsupported(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::Unsupported
+}
+
+#[cfg(unix)]
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    xattr::set(path, name, value)
+}
+
+#[cfg(not(unix))]
+fn set_xattr(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::from(io::ErrorKind::Unsupported))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unreadable_xattrs_are_reported() {
+        let mut warnings = Vec::new();
+        let path = std::env::temp_dir().join("backmeup-vanished-entry");
+        let xattrs = read_xattrs(&path, "vanished", true, &mut warnings);
+        assert!(xattrs.is_empty());
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("vanished"), "{}", warnings[0]);
+    }
+}
@@ -1,10 +1,22 @@
 use rdev::{listen, Event, EventType};
 use std::sync::{Arc, Mutex};
-use std::{env, fs, thread};
-use std::path::PathBuf;
+use std::{env, thread};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use crate::audio::play_sound;
-use crate::{backup};
+use crate::{backup, volumes};
+
+/// Backup waiting for the confirmation gesture, shared with the drive watcher
+#[derive(Debug, Default)]
+pub struct PendingBackup {
+    // Set by the start gesture, or when a destination drive is plugged in
+    pub enabled: bool,
+    // Jobs backed up once confirmed, all of them if `None`
+    pub jobs: Option<Vec<String>>,
+    // Set while the drive watcher runs a backup that needs no confirmation
+    pub running: bool,
+}
+
 #[derive(Debug, Clone)]
 struct Point{
     x: f64,
@@ -166,7 +178,50 @@ fn distance(p1: &Point, p2: &Point) -> f64 {
     ((p1.x - p2.x).powi(2) + (p2.y - p1.y).powi(2)).sqrt()
 }
 
-pub fn track_mouse(screen_width: f64, screen_height: f64) {
+/// Run the jobs named `jobs`, or all of them, then play the success sound once their data is on
+/// the disks, or the error sound if one of them failed
+pub fn run_backup(config_file_path: &Path, jobs: Option<&[String]>) {
+    if !config_file_path.join("config.toml").exists() {
+        play_sound(2);
+        eprintln!("File di configurazione non trovato! Backup non eseguito.");
+        return;
+    }
+    let config = backup::read_config(config_file_path.join("config.toml").to_str().unwrap());
+
+    let runs = match jobs {
+        Some(jobs) => jobs.iter()
+            .map(|job| backup::backup_files(&config, Some(job.as_str()).filter(|job| !job.is_empty()), false))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|runs| runs.into_iter().flatten().collect()),
+        // Back up every job
+        None => backup::backup_files(&config, None, false),
+    };
+    match runs {
+        Ok(runs) => {
+            let mut succeeded = true;
+            for run in runs {
+                match run.result {
+                    Ok(backup::BackupOutcome::Completed(report)) => print!("{}", report),
+                    Ok(backup::BackupOutcome::Planned(_)) => {}
+                    // Such as another backup already writing to the destination
+                    Err(e) => {
+                        eprintln!("Backup {} failed: {}", run.name, e);
+                        succeeded = false;
+                    }
+                }
+            }
+            // The success sound tells that a removable destination drive can be unplugged
+            volumes::flush_writes();
+            play_sound(if succeeded { 1 } else { 2 });
+        }
+        Err(e) => {
+            eprintln!("Backup failed: {}", e);
+            play_sound(2);
+        }
+    }
+}
+
+pub fn track_mouse(screen_width: f64, screen_height: f64, pending: Arc<Mutex<PendingBackup>>) {
     println!("Tracking enabled!");
 
     let exe_path: PathBuf = PathBuf::from(env::current_exe().unwrap().parent().unwrap());
@@ -175,8 +230,7 @@ pub fn track_mouse(screen_width: f64, screen_height: f64) {
 
     let points = Arc::new(Mutex::new(Vec::<Point>::new()));
     let points_clone = Arc::clone(&points);
-    let tracking_enabled = Arc::new(Mutex::new(false));
-    let tracking_enabled_clone = Arc::clone(&tracking_enabled);
+    let mut was_enabled = false;
 
 
     thread::spawn(move || {
@@ -185,17 +239,26 @@ pub fn track_mouse(screen_width: f64, screen_height: f64) {
                 let point = Point { x, y };
 
                 // Check if the tracking is enabled
-                let enabled = *tracking_enabled_clone.lock().unwrap();
+                let (enabled, running) = {
+                    let pending_ref = pending.lock().unwrap();
+                    (pending_ref.enabled, pending_ref.running)
+                };
 
                 let mut points = points_clone.lock().unwrap();
+                // A confirmation asked by the drive watcher waits for a new gesture
+                if enabled && !was_enabled {
+                    points.clear();
+                }
+                was_enabled = enabled;
                 points.push(point.clone());
 
                 println!("Tracked point: ({:.2}, {:.2})", point.x, point.y);
 
                 // Check if there are enough points to recognize the corners of the screen
-                if !enabled && contains_corners(&points, screen_width, screen_height, enabled) == Action::Confirm {
-                    let mut enabled_ref = tracking_enabled_clone.lock().unwrap();
-                    *enabled_ref = true;
+                if !enabled && !running && contains_corners(&points, screen_width, screen_height, enabled) == Action::Confirm {
+                    let mut enabled_ref = pending.lock().unwrap();
+                    enabled_ref.enabled = true;
+                    enabled_ref.jobs = None;
                     play_sound(0);
                     points.clear();
 
@@ -207,48 +270,27 @@ pub fn track_mouse(screen_width: f64, screen_height: f64) {
 
                 if enabled && contains_corners(&points, screen_width, screen_height, enabled) == Action::Modify {
                     points.clear();
-                    let mut enabled_ref = tracking_enabled_clone.lock().unwrap();
-                    *enabled_ref = false;
+                    let mut enabled_ref = pending.lock().unwrap();
+                    *enabled_ref = PendingBackup::default();
                     if let Err(e) = Command::new(exe_path.join("config_program")).arg("config").spawn() {
                         eprintln!("Failed to spawn process: {}", e);
                     }
                 }
 
                 if enabled && contains_corners(&points, screen_width, screen_height, enabled) == Action::Confirm {
+                    let jobs = pending.lock().unwrap().jobs.take();
+                    run_backup(config_file_path.as_path(), jobs.as_deref());
 
-                    if config_file_path.join("config.toml").exists() {
-                        let config = backup::read_config(config_file_path.join("config.toml").to_str().unwrap());
-
-                        // faccio il backup
-                        match backup::backup_files(&config) {
-                            Ok(_) => println!("Backup completed successfully"),
-                            Err(e) => match e {
-                                backup::BackupError::SourceNotFound =>
-                                    eprintln!("Backup failed: Source path does not exist"),
-                                backup::BackupError::InvalidBackupType =>
-                                    eprintln!("Backup failed: Invalid backup type specified"),
-                                backup::BackupError::IoError(e) =>
-                                    eprintln!("Backup failed due to IO error: {}", e),
-                                backup::BackupError::FsExtraError(e) =>
-                                    eprintln!("Backup failed due to fs_extra error: {}", e),
-                            }
-                        }
-                        play_sound(1);
-                    }else{
-                        play_sound(2);
-                        eprintln!("File di configurazione non trovato! Backup non eseguito.");
-
-                    }
-                    let mut enabled_ref = tracking_enabled_clone.lock().unwrap();
-                    *enabled_ref = false;
+                    let mut enabled_ref = pending.lock().unwrap();
+                    *enabled_ref = PendingBackup::default();
                     points.clear();
                 }
                 if enabled && contains_corners(&points, screen_width, screen_height, enabled) == Action::Cancel {
                     println!("Backup cancelled");
                     play_sound(2);
                     points.clear();
-                    let mut enabled_ref = tracking_enabled_clone.lock().unwrap();
-                    *enabled_ref = false;
+                    let mut enabled_ref = pending.lock().unwrap();
+                    *enabled_ref = PendingBackup::default();
                 }
             }
         }).unwrap();
@@ -0,0 +1,426 @@
+use std::{fs, io};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use fastcdc::v2020::StreamCDC;
+use walkdir::WalkDir;
+use crate::backup::{self, unix_seconds, BackupError, BackupReport, WalkedSources, SNAPSHOT_METADATA_DIR};
+use crate::compression::{self, CompressionConfig};
+use crate::encryption::{self, Cipher};
+use crate::manifest::{self, Manifest, ManifestEntry};
+use crate::metadata::EntryMetadata;
+use crate::storage::{RemoteSnapshot, SeriesFiles};
+
+// Chunk store shared by all the snapshots of a series: `<destination>/<source-name>/chunks/`
+pub(crate) const CHUNKS_DIR: &str = "chunks";
+
+// Index of a repository snapshot, saved in its metadata folder
+const INDEX_FILE: &str = "index.json";
+
+// FastCDC chunk sizes: big enough to keep the index small, small enough to isolate local changes
+const MIN_CHUNK_SIZE: u32 = 256 * 1024;
+const AVG_CHUNK_SIZE: u32 = 1024 * 1024;
+const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+
+/// List of the entries of a repository snapshot
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct SnapshotIndex {
+    pub snapshot_id: String,
+    pub source_path: String,
+    pub entries: Vec<IndexEntry>,
+}
+
+/// A file or directory of a snapshot.
+/// Files are rebuilt by concatenating their chunks, identified by their BLAKE3 hash.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub(crate) struct IndexEntry {
+    // Path relative to the source, with `/` as separator
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub modified: i64,
+    pub chunks: Vec<String>,
+}
+
+/// Back up the entries of the sources as a repository snapshot, each root in its folder.
+/// New chunks are added to the chunk store of the series, compressed unless compression is
+/// disabled and encrypted if a key is given, while the snapshot folder only receives the index.
+/// Files unchanged since the previous snapshot reuse its chunk list without being read again.
+/// For a remote destination the new chunks are written straight into its chunk store.
+/// Symlinks are only saved in the manifest, with the rest of the metadata of the entries.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn backup_with_chunks(
+    sources: WalkedSources,
+    series_path: &Path,
+    snapshot_path: &Path,
+    remote: Option<&RemoteSnapshot>,
+    previous_snapshot: Option<&Path>,
+    follow_symlinks: bool,
+    compression: &CompressionConfig,
+    cipher: Option<&Cipher>,
+    manifest: &mut Manifest,
+    report: &mut BackupReport,
+) -> io::Result<()> {
+    // Files of the previous snapshot, by path
+    let previous_entries: HashMap<String, IndexEntry> = previous_snapshot
+        .and_then(|path| read_index(path, cipher).ok())
+        .map(|index| index.entries.into_iter().map(|e| (e.path.clone(), e)).collect())
+        .unwrap_or_default();
+    let previous_hashes = previous_snapshot.map(|path| manifest::manifest_entries(path, cipher)).unwrap_or_default();
+
+    let chunks_path = series_path.join(CHUNKS_DIR);
+    let mut entries = Vec::new();
+    let mut new_bytes = 0;
+
+    // The roots themselves are not saved, only what they hold
+    for (root, entry) in sources.entries.into_iter().filter(|(_, entry)| !entry.as_ref().is_ok_and(|e| e.depth() == 0)) {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                let path = e.path().map(|path| root.manifest_path(path)).unwrap_or_default();
+                report.add_failure(path, &io::Error::from(e));
+                continue;
+            }
+        };
+
+        // Sockets, devices and pipes are not saved
+        let file_type = entry.file_type();
+        if !file_type.is_dir() && !file_type.is_file() && !file_type.is_symlink() {
+            report.skipped += 1;
+            continue;
+        }
+
+        let entry_path = entry.path();
+        let relative_path = root.manifest_path(entry_path);
+
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                report.add_failure(relative_path, &io::Error::from(e));
+                continue;
+            }
+        };
+        let entry_metadata = match EntryMetadata::read(entry_path, &relative_path, follow_symlinks, &mut report.warnings) {
+            Ok(entry_metadata) => entry_metadata,
+            Err(e) => {
+                report.add_failure(relative_path, &e);
+                continue;
+            }
+        };
+        let modified = metadata.modified().map(unix_seconds).unwrap_or(0);
+
+        if file_type.is_dir() {
+            entries.push(IndexEntry { path: relative_path, is_dir: true, size: 0, modified, chunks: Vec::new() });
+            manifest.metadata.push(entry_metadata);
+        } else if file_type.is_symlink() {
+            report.add_file(0, 0);
+            manifest.metadata.push(entry_metadata);
+        } else {
+            // Unchanged file: the chunks are already in the store
+            if let Some(previous) = previous_entries.get(&relative_path) {
+                if !previous.is_dir && previous.size == metadata.len() && previous.modified == modified {
+                    let hash = match previous_hashes.get(&relative_path) {
+                        Some(previous) => Ok(previous.hash.clone()),
+                        None => manifest::hash_file(entry_path),
+                    };
+                    if let Ok(hash) = hash {
+                        manifest.entries.push(ManifestEntry::new(relative_path.clone(), &metadata, hash));
+                    }
+                    manifest.metadata.push(entry_metadata);
+                    report.add_file(previous.size, 0);
+                    entries.push(previous.clone());
+                    continue;
+                }
+            }
+
+            // Chunks of formats already compressed are stored as they are
+            let level = (compression.mode != "none" && !compression::is_already_compressed(entry_path))
+                .then_some(compression.level);
+
+            match store_file(entry_path, &chunks_path, remote, level, cipher) {
+                Ok((chunks, stored, hash)) => {
+                    println!("Chunked: {:?} ({} chunks)", entry_path, chunks.len());
+                    report.add_file(metadata.len(), stored);
+                    new_bytes += stored;
+                    manifest.entries.push(ManifestEntry::new(relative_path.clone(), &metadata, hash));
+                    manifest.metadata.push(entry_metadata);
+                    entries.push(IndexEntry { path: relative_path, is_dir: false, size: metadata.len(), modified, chunks });
+                }
+                Err(e) => report.add_failure(relative_path, &e),
+            }
+        }
+    }
+
+    report.skipped += sources.skipped;
+
+    let index = SnapshotIndex {
+        snapshot_id: snapshot_path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+        source_path: report.source_path.clone(),
+        entries,
+    };
+    write_index(snapshot_path, &index, cipher)?;
+
+    println!("New data added to the repository: {} bytes", new_bytes);
+    Ok(())
+}
+
+/// Split a file into content-defined chunks and add the missing ones to the store.
+/// With a compression level, chunks are stored compressed as `<hash>.zst`.
+/// For a remote destination they are sent to its chunk store instead of `chunks_path`.
+/// Returns the hashes of the chunks, in order, the number of bytes actually written and the
+/// hash of the whole file.
+fn store_file(path: &Path, chunks_path: &Path, remote: Option<&RemoteSnapshot>, level: Option<i32>, cipher: Option<&Cipher>) -> io::Result<(Vec<String>, u64, String)> {
+    let file = fs::File::open(path)?;
+    let mut hashes = Vec::new();
+    let mut new_bytes = 0;
+    let mut file_hasher = blake3::Hasher::new();
+
+    for chunk in StreamCDC::new(file, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk.map_err(io::Error::other)?;
+        file_hasher.update(&chunk.data);
+        let hash = chunk_id(&chunk.data, cipher);
+        let chunk_path = chunk_path(Path::new(""), &hash);
+
+        // Chunks are stored only once, compressed or not
+        let compressed_chunk_path = compression::compressed_path(&chunk_path);
+        let exists = match remote {
+            Some(remote) => remote.has_chunk(&chunk_path)? || remote.has_chunk(&compressed_chunk_path)?,
+            None => chunks_path.join(&chunk_path).exists() || chunks_path.join(&compressed_chunk_path).exists(),
+        };
+        if !exists {
+            let (final_path, data) = match level {
+                Some(level) => (compressed_chunk_path, compression::compress_bytes(&chunk.data, level)?),
+                None => (chunk_path, chunk.data),
+            };
+            let data = match cipher {
+                Some(cipher) => cipher.encrypt(&data)?,
+                None => data,
+            };
+            match remote {
+                Some(remote) => remote.put_chunk(&final_path, &data)?,
+                None => {
+                    let final_path = chunks_path.join(final_path);
+                    fs::create_dir_all(final_path.parent().unwrap())?;
+                    let tmp_path = final_path.with_extension("tmp");
+                    fs::write(&tmp_path, &data)?;
+                    fs::rename(&tmp_path, &final_path)?;
+                }
+            }
+            new_bytes += data.len() as u64;
+        }
+
+        hashes.push(hash);
+    }
+
+    Ok((hashes, new_bytes, file_hasher.finalize().to_hex().to_string()))
+}
+
+/// Hash identifying a chunk in the store. Encrypted series use a keyed hash, which does not
+/// reveal whether a known content is stored.
+pub(crate) fn chunk_id(data: &[u8], cipher: Option<&Cipher>) -> String {
+    match cipher {
+        Some(cipher) => cipher.chunk_id(data),
+        None => blake3::hash(data).to_hex().to_string(),
+    }
+}
+
+/// Chunks are spread over subfolders named after the first two hex digits of their hash
+fn chunk_path(chunks_path: &Path, hash: &str) -> PathBuf {
+    chunks_path.join(&hash[..2]).join(hash)
+}
+
+/// Read a chunk back from the store of a series, decrypting and decompressing it if needed
+pub(crate) fn read_chunk(files: &SeriesFiles, hash: &str, cipher: Option<&Cipher>) -> io::Result<Vec<u8>> {
+    let chunk_path = chunk_path(Path::new(CHUNKS_DIR), hash);
+    let compressed = !files.exists(&chunk_path);
+    let chunk_path = if compressed { compression::compressed_path(&chunk_path) } else { chunk_path };
+    let mut data = Vec::new();
+    files.open(&chunk_path)?.read_to_end(&mut data)?;
+
+    let data = match cipher {
+        Some(cipher) => cipher.decrypt(&data)?,
+        None => data,
+    };
+    if compressed {
+        compression::decompress_bytes(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+/// Reader returning the content of a file of a repository snapshot, one chunk at a time
+pub(crate) struct ChunkReader<'a> {
+    files: SeriesFiles<'a>,
+    chunks: std::vec::IntoIter<String>,
+    cipher: Option<Cipher>,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl<'a> ChunkReader<'a> {
+    pub(crate) fn new(files: SeriesFiles<'a>, chunks: Vec<String>, cipher: Option<&Cipher>) -> Self {
+        ChunkReader {
+            files,
+            chunks: chunks.into_iter(),
+            cipher: cipher.cloned(),
+            current: io::Cursor::new(Vec::new()),
+        }
+    }
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            match self.chunks.next() {
+                Some(hash) => self.current = io::Cursor::new(read_chunk(&self.files, &hash, self.cipher.as_ref())?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Hashes of the chunks referenced by the repository snapshots of a series
+pub(crate) fn used_chunks(series_path: &Path, cipher: Option<&Cipher>) -> Result<HashSet<String>, BackupError> {
+    let mut used_chunks = HashSet::new();
+    for snapshot_id in backup::list_snapshots(series_path) {
+        let snapshot_path = series_path.join(snapshot_id);
+        if is_repository_snapshot(snapshot_path.as_path()) {
+            // An index that cannot be read stops the cleanup, its chunks could still be in use
+            let cipher = encryption::snapshot_cipher(snapshot_path.as_path(), cipher)?;
+            for entry in read_index(snapshot_path.as_path(), cipher)?.entries {
+                used_chunks.extend(entry.chunks);
+            }
+        }
+    }
+    Ok(used_chunks)
+}
+
+/// Hashes of all the chunks in the store of a series
+pub(crate) fn list_chunks(series_path: &Path) -> io::Result<Vec<String>> {
+    let mut chunks = Vec::new();
+    for entry in WalkDir::new(series_path.join(CHUNKS_DIR)).min_depth(2).max_depth(2) {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy();
+        // Skip the leftovers of interrupted writes
+        if entry.file_type().is_file() && !name.ends_with(".tmp") {
+            let hash = name.strip_suffix(&format!(".{}", compression::COMPRESSED_EXTENSION)).unwrap_or(&name);
+            chunks.push(hash.to_string());
+        }
+    }
+    Ok(chunks)
+}
+
+/// Delete a chunk from the store, returning the space freed
+pub(crate) fn remove_chunk(series_path: &Path, hash: &str) -> io::Result<u64> {
+    let mut path = chunk_path(&series_path.join(CHUNKS_DIR), hash);
+    if !path.exists() {
+        path = compression::compressed_path(&path);
+    }
+    let size = fs::metadata(&path)?.len();
+    fs::remove_file(path)?;
+    Ok(size)
+}
+
+/// Check whether a snapshot folder holds a repository snapshot
+pub(crate) fn is_repository_snapshot(snapshot_path: &Path) -> bool {
+    snapshot_path.join(SNAPSHOT_METADATA_DIR).join(INDEX_FILE).is_file()
+}
+
+pub(crate) fn read_index(snapshot_path: &Path, cipher: Option<&Cipher>) -> io::Result<SnapshotIndex> {
+    let contents = encryption::read_file(&snapshot_path.join(SNAPSHOT_METADATA_DIR).join(INDEX_FILE), cipher)?;
+    serde_json::from_slice(&contents).map_err(io::Error::other)
+}
+
+fn write_index(snapshot_path: &Path, index: &SnapshotIndex, cipher: Option<&Cipher>) -> io::Result<()> {
+    let metadata_path = snapshot_path.join(SNAPSHOT_METADATA_DIR);
+    fs::create_dir_all(&metadata_path)?;
+    let contents = serde_json::to_vec(index).map_err(io::Error::other)?;
+    encryption::write_file(&metadata_path.join(INDEX_FILE), &contents, cipher)
+}
+
+/// Portable form of a relative path, used as key in the index
+pub(crate) fn index_path(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_folder;
+
+    // Reproducible data that does not compress, so that the chunk boundaries depend on the content
+    fn random_bytes(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunk_sizes(series_path: &Path, hashes: &[String]) -> Vec<usize> {
+        hashes.iter().map(|hash| read_chunk(&SeriesFiles::Local(series_path), hash, None).unwrap().len()).collect()
+    }
+
+    #[test]
+    fn chunks_rebuild_the_file_within_the_size_bounds() {
+        let folder = temp_folder("bounds");
+        let data = random_bytes(12 * 1024 * 1024, 1);
+        fs::write(folder.join("file.bin"), &data).unwrap();
+
+        let chunks_path = folder.join(CHUNKS_DIR);
+        let (hashes, new_bytes, file_hash) = store_file(&folder.join("file.bin"), &chunks_path, None, None, None).unwrap();
+        assert_eq!(new_bytes, data.len() as u64);
+        assert_eq!(file_hash, blake3::hash(&data).to_hex().as_str());
+
+        let sizes = chunk_sizes(&folder, &hashes);
+        assert!(sizes.len() > 3, "{:?}", sizes);
+        let (last, others) = sizes.split_last().unwrap();
+        assert!(others.iter().all(|&size| (MIN_CHUNK_SIZE as usize..=MAX_CHUNK_SIZE as usize).contains(&size)), "{:?}", sizes);
+        assert!(*last <= MAX_CHUNK_SIZE as usize);
+
+        let mut rebuilt = Vec::new();
+        ChunkReader::new(SeriesFiles::Local(&folder), hashes, None).read_to_end(&mut rebuilt).unwrap();
+        assert_eq!(rebuilt, data);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn unchanged_chunks_are_stored_once() {
+        let folder = temp_folder("dedup");
+        let chunks_path = folder.join(CHUNKS_DIR);
+        let data = random_bytes(8 * 1024 * 1024, 2);
+        fs::write(folder.join("file.bin"), &data).unwrap();
+        let (hashes, _, _) = store_file(&folder.join("file.bin"), &chunks_path, None, Some(3), None).unwrap();
+
+        // The same content again adds nothing
+        fs::write(folder.join("copy.bin"), &data).unwrap();
+        let (copy_hashes, new_bytes, _) = store_file(&folder.join("copy.bin"), &chunks_path, None, Some(3), None).unwrap();
+        assert_eq!(copy_hashes, hashes);
+        assert_eq!(new_bytes, 0);
+
+        // Bytes inserted at the start only change the first chunk, the boundaries after it follow the content
+        let mut shifted = b"inserted bytes".to_vec();
+        shifted.extend_from_slice(&data);
+        fs::write(folder.join("shifted.bin"), &shifted).unwrap();
+        let (shifted_hashes, new_bytes, _) = store_file(&folder.join("shifted.bin"), &chunks_path, None, Some(3), None).unwrap();
+        let new_chunks = shifted_hashes.iter().filter(|hash| !hashes.contains(hash)).count();
+        assert_eq!(new_chunks, 1, "{} of {} chunks changed", new_chunks, shifted_hashes.len());
+        assert!(new_bytes <= MAX_CHUNK_SIZE as u64 + 1024);
+        assert_eq!(list_chunks(&folder).unwrap().len(), hashes.len() + 1);
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,723 @@
+use std::{fmt, fs, io};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use walkdir::WalkDir;
+use crate::backup::{self, BackupError, Config, SNAPSHOT_METADATA_DIR};
+use crate::compression;
+use crate::encryption::{self, Cipher};
+use crate::image;
+use crate::manifest::{self, ManifestEntry};
+use crate::metadata::{self, EntryKind, EntryMetadata};
+use crate::repository;
+use crate::storage::{self, SeriesFiles};
+
+// Suffix of the entries being restored, before they are moved in place
+const TEMPORARY_SUFFIX: &str = ".backmeup-restore";
+// Longest file name most filesystems allow, in bytes
+const MAX_NAME_SIZE: usize = 255;
+
+/// What to do when a restored file already exists at the target location
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictPolicy {
+    Skip,
+    Overwrite,
+    KeepBoth,
+    OnlyIfNewer,
+}
+
+impl ConflictPolicy {
+    pub(crate) const ALL: [ConflictPolicy; 4] = [
+        ConflictPolicy::Skip,
+        ConflictPolicy::Overwrite,
+        ConflictPolicy::KeepBoth,
+        ConflictPolicy::OnlyIfNewer,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        ConflictPolicy::ALL.into_iter().find(|policy| policy.as_str() == value)
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ConflictPolicy::Skip => "skip",
+            ConflictPolicy::Overwrite => "overwrite",
+            ConflictPolicy::KeepBoth => "keep-both",
+            ConflictPolicy::OnlyIfNewer => "only-if-newer",
+        }
+    }
+}
+
+pub struct RestoreOptions {
+    // Snapshot to restore, the latest one if not given
+    pub snapshot_id: Option<String>,
+    // Paths relative to the source to restore, everything if empty
+    pub paths: Vec<String>,
+    // Folder to restore into, the original source location if not given
+    pub target_path: Option<PathBuf>,
+    pub conflict_policy: ConflictPolicy,
+}
+
+#[derive(Debug)]
+pub enum RestoreOutcome {
+    Restored,
+    Overwritten,
+    // Restored next to the existing file, under another name
+    KeptBoth(PathBuf),
+    Skipped,
+    Failed(io::Error),
+}
+
+/// Result of the restore of a single file
+#[derive(Debug)]
+pub struct RestoreResult {
+    pub path: String,
+    pub outcome: RestoreOutcome,
+}
+
+impl fmt::Display for RestoreResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            RestoreOutcome::Restored => write!(f, "restored     {}", self.path),
+            RestoreOutcome::Overwritten => write!(f, "overwritten  {}", self.path),
+            RestoreOutcome::KeptBoth(path) => write!(f, "kept both    {} -> {}", self.path, path.display()),
+            RestoreOutcome::Skipped => write!(f, "skipped      {}", self.path),
+            RestoreOutcome::Failed(e) => write!(f, "failed       {}: {}", self.path, e),
+        }
+    }
+}
+
+// Where the content of a snapshot entry is read from
+enum EntryData {
+    Directory,
+    // Plain snapshot: copy of the file relative to the series, possibly compressed
+    File(PathBuf, bool),
+    // Repository snapshot: chunks of the file, in order
+    Chunks(Vec<String>),
+}
+
+struct SnapshotEntry {
+    path: String,
+    modified: SystemTime,
+    data: EntryData,
+}
+
+/// Restore a snapshot of the configured source, or only some paths of it.
+/// Returns the outcome for each file, failures included.
+pub fn restore_snapshot(config: &Config, options: &RestoreOptions) -> Result<Vec<RestoreResult>, BackupError> {
+    let series_path = backup::series_path(config)?;
+    // The files of a remote destination are read from it, and the metadata from the working copy
+    let backend = storage::open_backend(config)?;
+    if let Some(backend) = backend.as_deref() {
+        storage::fetch_snapshot(backend, series_path.as_path(), options.snapshot_id.as_deref())?;
+    }
+    let files = SeriesFiles::new(series_path.as_path(), backend.as_deref());
+
+    let snapshot_id = match &options.snapshot_id {
+        Some(snapshot_id) => snapshot_id.clone(),
+        None => backup::latest_snapshot(series_path.as_path()).ok_or(BackupError::SnapshotNotFound)?,
+    };
+    let snapshot_path = series_path.join(&snapshot_id);
+    if !snapshot_path.is_dir() {
+        return Err(BackupError::SnapshotNotFound);
+    }
+
+    let cipher = encryption::load_cipher(config, series_path.as_path(), false)?;
+    let cipher = encryption::snapshot_cipher(snapshot_path.as_path(), cipher.as_ref())?;
+
+    let saved_manifest = manifest::read_manifest(snapshot_path.as_path(), cipher).ok();
+
+    // An image is written back as a whole, onto the device or into the file it was read from
+    if let Some(entry) = saved_manifest.as_ref().filter(|manifest| manifest.image.is_some()).and_then(|manifest| manifest.entries.first()) {
+        let mut destination = options.target_path.clone().unwrap_or_else(|| PathBuf::from(&config.source_path));
+        if destination.is_dir() {
+            destination.push(image::IMAGE_FILE);
+        }
+        println!("Restoring the image of snapshot {} onto {:?}", snapshot_id, destination);
+        let outcome = restore_image(&files, entry, cipher, &destination, &snapshot_id, options.conflict_policy);
+        return Ok(vec![RestoreResult { path: entry.path.clone(), outcome }]);
+    }
+
+    // Files of an archive listed in the manifest, the others having failed while being archived
+    let archived_files: Option<HashSet<String>> = saved_manifest.as_ref()
+        .map(|manifest| manifest.entries.iter().map(|entry| entry.path.clone()).collect());
+
+    // Metadata and folders saved with the snapshot, missing in the snapshots taken before they were recorded
+    let (saved_metadata, roots) = saved_manifest
+        .map(|manifest| (manifest.metadata, manifest.roots))
+        .unwrap_or_default();
+    let metadata: HashMap<&str, &EntryMetadata> = saved_metadata.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+
+    let target_path = options.target_path.clone().unwrap_or_else(|| PathBuf::from(&config.source_path));
+    if options.target_path.is_none() && !roots.is_empty() {
+        println!("Restoring snapshot {} to the original locations of its folders", snapshot_id);
+    } else {
+        println!("Restoring snapshot {} into {:?}", snapshot_id, target_path);
+    }
+    // Entries go back to their original location, unless a target folder is given
+    let destination_of = |path: &str| -> io::Result<PathBuf> {
+        check_path(Path::new(path))?;
+        Ok(match options.target_path {
+            Some(_) => target_path.join(path),
+            None => original_path(&target_path, &roots, path),
+        })
+    };
+
+    let selected_paths: Vec<String> = options.paths.iter()
+        .map(|path| path.replace('\\', "/").trim_matches('/').to_string())
+        .filter(|path| !path.is_empty())
+        .collect();
+
+    // Folders restored, whose metadata is applied once their content is in place
+    let mut directories = Vec::new();
+
+    // The files of a "tar-zst" snapshot can only be read in sequence
+    let mut results = if compression::is_archive_snapshot(&files, &snapshot_id) {
+        restore_archive(
+            &files,
+            cipher,
+            &destination_of,
+            &selected_paths,
+            &snapshot_id,
+            options.conflict_policy,
+            &metadata,
+            archived_files.as_ref(),
+            &mut directories,
+        )?
+    } else {
+        let mut results = Vec::new();
+
+        for entry in read_snapshot(&files, &snapshot_id, cipher)? {
+            if !is_selected(&entry.path, &selected_paths) {
+                continue;
+            }
+
+            let destination = match destination_of(&entry.path) {
+                Ok(destination) => destination,
+                Err(e) => {
+                    results.push(RestoreResult { path: entry.path, outcome: RestoreOutcome::Failed(e) });
+                    continue;
+                }
+            };
+            let entry_metadata = metadata.get(entry.path.as_str()).copied();
+
+            let outcome = match &entry.data {
+                EntryData::Directory => {
+                    if let Some(outcome) = restore_directory(&entry.path, destination, options.conflict_policy, &mut directories) {
+                        results.push(RestoreResult { path: entry.path, outcome });
+                    }
+                    continue;
+                }
+                EntryData::File(path, compressed) => restore_file(
+                    || compression::open_stored(&files, path, *compressed, cipher),
+                    entry.modified, entry_metadata, &destination, &snapshot_id, options.conflict_policy,
+                ),
+                EntryData::Chunks(chunks) => restore_file(
+                    || Ok(repository::ChunkReader::new(files, chunks.clone(), cipher)),
+                    entry.modified, entry_metadata, &destination, &snapshot_id, options.conflict_policy,
+                ),
+            };
+            results.push(RestoreResult { path: entry.path, outcome });
+        }
+
+        results
+    };
+
+    // Symlinks are only listed in the metadata of the snapshot
+    for entry in saved_metadata.iter().filter(|entry| entry.kind == EntryKind::Symlink && is_selected(&entry.path, &selected_paths)) {
+        let outcome = match destination_of(&entry.path) {
+            Ok(destination) => restore_symlink(entry, &destination, &snapshot_id, options.conflict_policy),
+            Err(e) => RestoreOutcome::Failed(e),
+        };
+        results.push(RestoreResult { path: entry.path.clone(), outcome });
+    }
+
+    // Writing inside a folder updates its times, and a read-only folder would block the writes
+    for (path, destination) in directories.into_iter().rev() {
+        if let Some(entry_metadata) = metadata.get(path.as_str()) {
+            if let Err(e) = entry_metadata.apply(&destination) {
+                results.push(RestoreResult { path, outcome: RestoreOutcome::Failed(e) });
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// Entries of a snapshot, whatever its storage format
+fn read_snapshot(files: &SeriesFiles, snapshot_id: &str, cipher: Option<&Cipher>) -> io::Result<Vec<SnapshotEntry>> {
+    let snapshot_path = files.series_path().join(snapshot_id);
+    let snapshot_path = snapshot_path.as_path();
+    if repository::is_repository_snapshot(snapshot_path) {
+        let index = repository::read_index(snapshot_path, cipher)?;
+        return Ok(index.entries.into_iter()
+            .map(|entry| SnapshotEntry {
+                modified: from_unix_seconds(entry.modified),
+                data: if entry.is_dir { EntryData::Directory } else { EntryData::Chunks(entry.chunks) },
+                path: entry.path,
+            })
+            .collect());
+    }
+
+    // The working copy of a remote destination only holds the metadata: the entries of a plain
+    // snapshot are the folders and files of its manifest
+    if files.is_remote() {
+        let manifest = manifest::read_manifest(snapshot_path, cipher)?;
+        let directories = manifest.metadata.iter()
+            .filter(|entry| entry.kind == EntryKind::Directory)
+            .map(|entry| SnapshotEntry {
+                path: entry.path.clone(),
+                modified: from_unix_seconds(entry.modified.seconds),
+                data: EntryData::Directory,
+            });
+        let stored_files = manifest.entries.iter().map(|entry| SnapshotEntry {
+            path: entry.path.clone(),
+            modified: from_unix_seconds(entry.modified),
+            data: EntryData::File(compression::stored_path(Path::new(snapshot_id), entry, cipher), entry.compressed),
+        });
+        return Ok(directories.chain(stored_files).collect());
+    }
+
+    // Long names are stored as hashes, only found again in the manifest
+    let long_names: HashMap<String, String> = match cipher {
+        Some(cipher) => manifest::read_manifest(snapshot_path, Some(cipher))
+            .map(|manifest| {
+                let paths = manifest.entries.iter().map(|entry| entry.path.as_str())
+                    .chain(manifest.metadata.iter().map(|entry| entry.path.as_str()));
+                encryption::long_name_paths(paths, cipher)
+            })
+            .unwrap_or_default(),
+        None => HashMap::new(),
+    };
+    let decrypt_path = |cipher: &Cipher, stored: &str| match long_names.get(stored) {
+        Some(path) => Ok(path.clone()),
+        None => cipher.decrypt_path(stored),
+    };
+
+    let mut entries = Vec::new();
+    for entry in WalkDir::new(snapshot_path)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|e| e.file_name() != SNAPSHOT_METADATA_DIR)
+    {
+        let entry = entry?;
+        let path = match entry.path().strip_prefix(snapshot_path) {
+            Ok(rel) => repository::index_path(rel),
+            Err(_) => continue,
+        };
+        let modified = entry.metadata()?.modified()?;
+
+        if entry.file_type().is_dir() {
+            let path = match cipher {
+                Some(cipher) => decrypt_path(cipher, &path)?,
+                None => path,
+            };
+            entries.push(SnapshotEntry { path, modified, data: EntryData::Directory });
+        } else if entry.file_type().is_file() {
+            // Compressed copies are hidden files, a name no other file of the snapshot has
+            let (path, compressed) = match compression::original_path(&path) {
+                Some(original) => (original, true),
+                None => (path, false),
+            };
+            let path = match cipher {
+                Some(cipher) => decrypt_path(cipher, &path)?,
+                None => path,
+            };
+            let stored_path = Path::new(snapshot_id).join(entry.path().strip_prefix(snapshot_path).unwrap_or(entry.path()));
+            entries.push(SnapshotEntry { path, modified, data: EntryData::File(stored_path, compressed) });
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Restore the selected entries of a "tar-zst" snapshot, reading its archive once
+#[allow(clippy::too_many_arguments)]
+fn restore_archive(
+    files: &SeriesFiles,
+    cipher: Option<&Cipher>,
+    destination_of: &dyn Fn(&str) -> io::Result<PathBuf>,
+    selected_paths: &[String],
+    snapshot_id: &str,
+    conflict_policy: ConflictPolicy,
+    metadata: &HashMap<&str, &EntryMetadata>,
+    archived_files: Option<&HashSet<String>>,
+    directories: &mut Vec<(String, PathBuf)>,
+) -> io::Result<Vec<RestoreResult>> {
+    let mut archive = compression::open_archive(files, snapshot_id, cipher)?;
+    let mut results = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let path = repository::index_path(&entry_path);
+        if !is_selected(&path, selected_paths) {
+            continue;
+        }
+
+        // The archive path is checked as stored, `index_path` dropping the components it rejects
+        let destination = match check_path(&entry_path).and_then(|_| destination_of(&path)) {
+            Ok(destination) => destination,
+            Err(e) => {
+                results.push(RestoreResult { path: entry_path.to_string_lossy().to_string(), outcome: RestoreOutcome::Failed(e) });
+                continue;
+            }
+        };
+        let modified = from_unix_seconds(entry.header().mtime()? as i64);
+
+        let outcome = match entry.header().entry_type() {
+            tar::EntryType::Directory => {
+                if let Some(outcome) = restore_directory(&path, destination, conflict_policy, directories) {
+                    results.push(RestoreResult { path, outcome });
+                }
+                continue;
+            }
+            tar::EntryType::Regular if archived_files.is_some_and(|files| !files.contains(&path)) => continue,
+            tar::EntryType::Regular => {
+                let entry_metadata = metadata.get(path.as_str()).copied();
+                restore_file(|| Ok(&mut entry), modified, entry_metadata, &destination, snapshot_id, conflict_policy)
+            }
+            _ => continue,
+        };
+        results.push(RestoreResult { path, outcome });
+    }
+
+    Ok(results)
+}
+
+/// Original location of an entry of a snapshot: inside the source folder, or for a snapshot of
+/// several folders inside the source or the volume whose folder holds it
+fn original_path(source_path: &Path, roots: &BTreeMap<String, String>, path: &str) -> PathBuf {
+    let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+    match roots.get(name) {
+        Some(root) if rest.is_empty() => PathBuf::from(root),
+        Some(root) => Path::new(root).join(rest),
+        None => source_path.join(path),
+    }
+}
+
+/// Make sure a path read from a snapshot stays inside the folder it is restored into: a damaged or
+/// crafted manifest, index or archive could otherwise write anywhere with `..` or a root
+fn check_path(path: &Path) -> io::Result<()> {
+    if path.components().all(|component| matches!(component, Component::Normal(_))) {
+        Ok(())
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsafe path in the snapshot: {}", path.display())))
+    }
+}
+
+/// A path is selected if it is one of the requested paths or lies inside one of them
+fn is_selected(path: &str, selected_paths: &[String]) -> bool {
+    selected_paths.is_empty()
+        || selected_paths.iter().any(|selected| {
+            path == selected || path.starts_with(&format!("{}/", selected))
+        })
+}
+
+/// Create a restored folder, which is added to `directories` to receive its metadata later.
+/// The metadata of a folder that already exists is left alone when existing entries are skipped.
+/// Returns the outcome only if the folder could not be created.
+fn restore_directory(
+    path: &str,
+    destination: PathBuf,
+    conflict_policy: ConflictPolicy,
+    directories: &mut Vec<(String, PathBuf)>,
+) -> Option<RestoreOutcome> {
+    let existed = destination.is_dir();
+    if let Err(e) = fs::create_dir_all(&destination) {
+        return Some(RestoreOutcome::Failed(e));
+    }
+    if !existed || conflict_policy != ConflictPolicy::Skip {
+        directories.push((path.to_string(), destination));
+    }
+    None
+}
+
+/// Restore a single file, applying the conflict policy if it already exists.
+/// `open` is only called if the file has to be written.
+fn restore_file<R: Read>(
+    open: impl FnOnce() -> io::Result<R>,
+    modified: SystemTime,
+    metadata: Option<&EntryMetadata>,
+    destination: &Path,
+    snapshot_id: &str,
+    conflict_policy: ConflictPolicy,
+) -> RestoreOutcome {
+    let Some((destination, outcome)) = resolve_conflict(destination, backup::unix_seconds(modified), snapshot_id, conflict_policy) else {
+        return RestoreOutcome::Skipped;
+    };
+
+    match open().and_then(|mut reader| write_file(&mut reader, modified, metadata, &destination)) {
+        Ok(()) => outcome,
+        Err(e) => RestoreOutcome::Failed(e),
+    }
+}
+
+/// Write the image of an "image" snapshot back onto a device, or into a file.
+/// A device is overwritten in place, and only with the "overwrite" policy. A file is written
+/// under a temporary name and moved in place like the other files, with the zero blocks left as
+/// holes.
+fn restore_image(
+    files: &SeriesFiles,
+    entry: &ManifestEntry,
+    cipher: Option<&Cipher>,
+    destination: &Path,
+    snapshot_id: &str,
+    conflict_policy: ConflictPolicy,
+) -> RestoreOutcome {
+    let stored_path = compression::stored_path(Path::new(snapshot_id), entry, cipher);
+    let open = || compression::open_stored(files, &stored_path, entry.compressed, cipher);
+
+    if image::is_device(destination) {
+        if conflict_policy != ConflictPolicy::Overwrite {
+            return RestoreOutcome::Skipped;
+        }
+        let result = open().and_then(|mut reader| {
+            let mut device = fs::OpenOptions::new().write(true).open(destination)?;
+            image::write_image(&mut reader, &mut device, entry, false)
+        });
+        return match result {
+            Ok(()) => RestoreOutcome::Overwritten,
+            Err(e) => RestoreOutcome::Failed(e),
+        };
+    }
+
+    let Some((destination, outcome)) = resolve_conflict(destination, entry.modified, snapshot_id, conflict_policy) else {
+        return RestoreOutcome::Skipped;
+    };
+    let tmp_path = temporary_path(&destination);
+    let result = open().and_then(|mut reader| {
+        let mut file = fs::File::create(&tmp_path)?;
+        image::write_image(&mut reader, &mut file, entry, true)?;
+        drop(file);
+        fs::rename(&tmp_path, &destination)
+    });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    match result {
+        Ok(()) => outcome,
+        Err(e) => RestoreOutcome::Failed(e),
+    }
+}
+
+/// Restore a symlink, applying the conflict policy if its path already exists
+fn restore_symlink(entry: &EntryMetadata, destination: &Path, snapshot_id: &str, conflict_policy: ConflictPolicy) -> RestoreOutcome {
+    let Some((destination, outcome)) = resolve_conflict(destination, entry.modified.seconds, snapshot_id, conflict_policy) else {
+        return RestoreOutcome::Skipped;
+    };
+
+    match write_symlink(entry, &destination) {
+        Ok(()) => outcome,
+        Err(e) => RestoreOutcome::Failed(e),
+    }
+}
+
+/// Path to restore an entry to, and the outcome if it succeeds, following the conflict policy
+/// if something already exists at `destination`. `None` if the existing entry is kept.
+fn resolve_conflict(
+    destination: &Path,
+    modified: i64,
+    snapshot_id: &str,
+    conflict_policy: ConflictPolicy,
+) -> Option<(PathBuf, RestoreOutcome)> {
+    let Ok(existing) = fs::symlink_metadata(destination) else {
+        return Some((destination.to_path_buf(), RestoreOutcome::Restored));
+    };
+
+    match conflict_policy {
+        ConflictPolicy::Skip => None,
+        ConflictPolicy::Overwrite => Some((destination.to_path_buf(), RestoreOutcome::Overwritten)),
+        ConflictPolicy::KeepBoth => {
+            let destination = keep_both_path(destination, snapshot_id);
+            Some((destination.clone(), RestoreOutcome::KeptBoth(destination)))
+        }
+        ConflictPolicy::OnlyIfNewer => {
+            let existing_time = existing.modified().map(backup::unix_seconds).unwrap_or(i64::MIN);
+            (modified > existing_time).then(|| (destination.to_path_buf(), RestoreOutcome::Overwritten))
+        }
+    }
+}
+
+/// Write the content of a file to a temporary file, then move it in place,
+/// so that an existing file is never left half overwritten.
+fn write_file(reader: &mut dyn Read, modified: SystemTime, metadata: Option<&EntryMetadata>, destination: &Path) -> io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = temporary_path(destination);
+
+    let result = (|| {
+        let mut file = fs::File::create(&tmp_path)?;
+        io::copy(reader, &mut file)?;
+        file.set_modified(modified)?;
+        drop(file);
+        if let Some(metadata) = metadata {
+            metadata.apply(&tmp_path)?;
+        }
+        fs::rename(&tmp_path, destination)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Create a symlink under a temporary name, then move it in place like the restored files
+fn write_symlink(entry: &EntryMetadata, destination: &Path) -> io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = temporary_path(destination);
+    if fs::symlink_metadata(&tmp_path).is_ok() {
+        fs::remove_file(&tmp_path)?;
+    }
+
+    let result = (|| {
+        metadata::create_symlink(entry.link_target.as_deref().unwrap_or_default(), &tmp_path)?;
+        entry.apply(&tmp_path)?;
+        fs::rename(&tmp_path, destination)
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Name an entry is written under before being moved in place.
+/// Names too long to take the suffix are replaced by a hash of the name.
+fn temporary_path(destination: &Path) -> PathBuf {
+    let name = destination.file_name().unwrap_or_default();
+    let mut tmp_name = name.to_os_string();
+    tmp_name.push(TEMPORARY_SUFFIX);
+    if tmp_name.len() > MAX_NAME_SIZE {
+        let hash = blake3::hash(name.as_encoded_bytes()).to_hex();
+        tmp_name = format!(".{}{}", &hash[..32], TEMPORARY_SUFFIX).into();
+    }
+    destination.with_file_name(tmp_name)
+}
+
+/// Free name for a restored file next to an existing one: `name.restored-<snapshot>.ext`
+fn keep_both_path(destination: &Path, snapshot_id: &str) -> PathBuf {
+    let stem = destination.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    let extension = destination.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+
+    let mut candidate = destination.with_file_name(format!("{}.restored-{}{}", stem, snapshot_id, extension));
+    let mut counter = 1;
+    while candidate.exists() {
+        candidate = destination.with_file_name(format!("{}.restored-{}-{}{}", stem, snapshot_id, counter, extension));
+        counter += 1;
+    }
+    candidate
+}
+
+fn from_unix_seconds(seconds: i64) -> SystemTime {
+    if seconds >= 0 {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(seconds as u64)
+    } else {
+        SystemTime::UNIX_EPOCH - Duration::from_secs(seconds.unsigned_abs())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::BackupOutcome;
+    use crate::backup::tests::{test_config, test_folder};
+
+    #[test]
+    fn only_relative_paths_are_restored() {
+        for path in ["a.txt", "folder/a.txt", "", "..a/b..c"] {
+            assert!(check_path(Path::new(path)).is_ok(), "{}", path);
+        }
+        for path in ["../a.txt", "folder/../../a.txt", "/etc/passwd"] {
+            assert_eq!(check_path(Path::new(path)).unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", path);
+        }
+    }
+
+    /// Back up `a.txt`, then restore it into a folder where `a.txt` exists, modified
+    /// `existing_offset` seconds after the one backed up. Returns the folder, the id of the
+    /// snapshot and the outcome.
+    fn restored_over_existing(name: &str, conflict_policy: ConflictPolicy, existing_offset: i64) -> (PathBuf, String, RestoreOutcome) {
+        let folder = test_folder(&format!("conflict-{}", name), &[("a.txt", "backed up")]);
+        let config = test_config(&folder);
+        let BackupOutcome::Completed(report) = backup::run_job(&config, false).unwrap() else {
+            panic!("no backup was made");
+        };
+
+        fs::create_dir_all(folder.join("restored")).unwrap();
+        fs::write(folder.join("restored/a.txt"), "existing").unwrap();
+        let backed_up = backup::unix_seconds(fs::metadata(folder.join("source/a.txt")).unwrap().modified().unwrap());
+        let existing = fs::File::options().write(true).open(folder.join("restored/a.txt")).unwrap();
+        existing.set_modified(from_unix_seconds(backed_up + existing_offset)).unwrap();
+        // The first name keep-both would use is taken too
+        fs::write(folder.join("restored").join(format!("a.restored-{}.txt", report.snapshot_id)), "kept before").unwrap();
+
+        let options = RestoreOptions {
+            snapshot_id: None,
+            paths: Vec::new(),
+            target_path: Some(folder.join("restored")),
+            conflict_policy,
+        };
+        let mut results = restore_snapshot(&config, &options).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, "a.txt");
+        (folder, report.snapshot_id, results.remove(0).outcome)
+    }
+
+    fn contents(path: PathBuf) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn skip_keeps_the_existing_file() {
+        let (folder, _, outcome) = restored_over_existing("skip", ConflictPolicy::Skip, -1000);
+        assert!(matches!(outcome, RestoreOutcome::Skipped));
+        assert_eq!(contents(folder.join("restored/a.txt")), "existing");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn overwrite_replaces_the_existing_file() {
+        let (folder, _, outcome) = restored_over_existing("overwrite", ConflictPolicy::Overwrite, 3600);
+        assert!(matches!(outcome, RestoreOutcome::Overwritten));
+        assert_eq!(contents(folder.join("restored/a.txt")), "backed up");
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn keep_both_restores_next_to_the_existing_file() {
+        let (folder, snapshot_id, outcome) = restored_over_existing("keep-both", ConflictPolicy::KeepBoth, 0);
+        let kept = folder.join("restored").join(format!("a.restored-{}-1.txt", snapshot_id));
+        assert!(matches!(&outcome, RestoreOutcome::KeptBoth(path) if *path == kept), "{:?}", outcome);
+        assert_eq!(contents(kept), "backed up");
+        assert_eq!(contents(folder.join("restored/a.txt")), "existing");
+        assert_eq!(contents(folder.join("restored").join(format!("a.restored-{}.txt", snapshot_id))), "kept before");
+
+        // A name without extension gets the suffix at its end
+        assert_eq!(keep_both_path(&folder.join("restored/notes"), "2024-01-01_00-00-00"), folder.join("restored/notes.restored-2024-01-01_00-00-00"));
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn only_if_newer_compares_the_modification_times() {
+        let (folder, _, outcome) = restored_over_existing("older", ConflictPolicy::OnlyIfNewer, -1);
+        assert!(matches!(outcome, RestoreOutcome::Overwritten));
+        assert_eq!(contents(folder.join("restored/a.txt")), "backed up");
+        fs::remove_dir_all(folder).unwrap();
+
+        // The same time is not newer
+        for (name, existing_offset) in [("newer", 1), ("same", 0)] {
+            let (folder, _, outcome) = restored_over_existing(name, ConflictPolicy::OnlyIfNewer, existing_offset);
+            assert!(matches!(outcome, RestoreOutcome::Skipped), "{}", name);
+            assert_eq!(contents(folder.join("restored/a.txt")), "existing", "{}", name);
+            fs::remove_dir_all(folder).unwrap();
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::{fmt, fs};
+use std::path::Path;
+use chrono::{Datelike, Months, NaiveDateTime, TimeDelta};
+use crate::backup::{self, BackupError};
+use crate::encryption::Cipher;
+use crate::repository;
+
+/// `[retention]` section of config.toml.
+/// A snapshot is kept if at least one rule selects it; the others are deleted after each backup.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct RetentionPolicy {
+    // Keep the n most recent snapshots
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_last: Option<u32>,
+    // Keep the most recent snapshot of each of the last n hours/days/weeks/months/years
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_hourly: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_daily: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_weekly: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_monthly: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_yearly: Option<u32>,
+    // Keep all the snapshots taken within this duration of the newest one, e.g. "1y6m", "2w", "36h"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keep_within: Option<String>,
+}
+
+impl RetentionPolicy {
+    /// Whether no rule keeps any snapshot, which would delete the whole history but `latest`
+    fn keeps_nothing(&self) -> bool {
+        let counts = [self.keep_last, self.keep_hourly, self.keep_daily, self.keep_weekly, self.keep_monthly, self.keep_yearly];
+        counts.iter().all(|count| count.unwrap_or(0) == 0)
+            && self.keep_within.as_deref().is_none_or(|within| within.trim().is_empty())
+    }
+}
+
+/// Rule keeping one snapshot per bucket: its name, the number of buckets, and the bucket of a time
+type BucketRule = (&'static str, Option<u32>, fn(&NaiveDateTime) -> String);
+
+/// What happens to a snapshot when pruning, and why
+#[derive(Debug)]
+pub struct PruneDecision {
+    pub snapshot_id: String,
+    pub keep: bool,
+    pub reasons: Vec<String>,
+}
+
+impl fmt::Display for PruneDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = if self.keep { "keep  " } else { "remove" };
+        write!(f, "{} {}  ({})", action, self.snapshot_id, self.reasons.join(", "))
+    }
+}
+
+/// Decide which snapshots of a series the policy keeps, without touching them
+pub(crate) fn plan_prune(series_path: &Path, policy: &RetentionPolicy) -> Result<Vec<PruneDecision>, BackupError> {
+    if policy.keeps_nothing() {
+        return Err(BackupError::InvalidRetentionPolicy("no keep rule is set, every snapshot would be deleted".to_string()));
+    }
+
+    // Newest first, so that each rule keeps the most recent snapshots
+    let mut snapshots: Vec<(String, NaiveDateTime)> = backup::list_snapshots(series_path)
+        .into_iter()
+        .filter_map(|id| backup::snapshot_time(&id).map(|time| (id, time)))
+        .collect();
+    snapshots.reverse();
+
+    let mut decisions: Vec<PruneDecision> = snapshots.iter()
+        .map(|(id, _)| PruneDecision { snapshot_id: id.clone(), keep: false, reasons: Vec::new() })
+        .collect();
+
+    if let Some(keep_last) = policy.keep_last {
+        for decision in decisions.iter_mut().take(keep_last as usize) {
+            decision.reasons.push(format!("last {}", keep_last));
+        }
+    }
+
+    let buckets: [BucketRule; 5] = [
+        ("hourly", policy.keep_hourly, |t| t.format("%Y-%m-%d %H:00").to_string()),
+        ("daily", policy.keep_daily, |t| t.format("%Y-%m-%d").to_string()),
+        ("weekly", policy.keep_weekly, |t| format!("{}-W{:02}", t.iso_week().year(), t.iso_week().week())),
+        ("monthly", policy.keep_monthly, |t| t.format("%Y-%m").to_string()),
+        ("yearly", policy.keep_yearly, |t| t.format("%Y").to_string()),
+    ];
+
+    for (name, count, bucket_of) in buckets {
+        let Some(count) = count else { continue };
+        let mut last_bucket = None;
+        let mut kept = 0;
+
+        for (decision, (_, time)) in decisions.iter_mut().zip(&snapshots) {
+            if kept == count {
+                break;
+            }
+            let bucket = bucket_of(time);
+            if last_bucket.as_ref() != Some(&bucket) {
+                decision.reasons.push(format!("{} {}", name, bucket));
+                last_bucket = Some(bucket);
+                kept += 1;
+            }
+        }
+    }
+
+    if let Some(keep_within) = &policy.keep_within {
+        if let Some((_, newest)) = snapshots.first() {
+            let oldest_kept = subtract_duration(*newest, keep_within)?;
+            for (decision, (_, time)) in decisions.iter_mut().zip(&snapshots) {
+                if *time >= oldest_kept {
+                    decision.reasons.push(format!("within {}", keep_within));
+                }
+            }
+        }
+    }
+
+    // The snapshot `latest` points to is never removed
+    let latest = backup::latest_snapshot(series_path);
+    for decision in decisions.iter_mut() {
+        if Some(&decision.snapshot_id) == latest.as_ref() && decision.reasons.is_empty() {
+            decision.reasons.push("latest".to_string());
+        }
+        decision.keep = !decision.reasons.is_empty();
+        if !decision.keep {
+            decision.reasons.push("not selected by any keep rule".to_string());
+        }
+    }
+
+    // Back to chronological order
+    decisions.reverse();
+    Ok(decisions)
+}
+
+/// Apply the policy to a series: delete the snapshots it does not keep, then the chunks of the
+/// repository no snapshot refers to anymore. With `dry_run` nothing is deleted.
+/// The key is needed to read the indexes of encrypted repository snapshots.
+pub fn prune(
+    series_path: &Path,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<PruneDecision>, BackupError> {
+    let decisions = plan_prune(series_path, policy)?;
+    if dry_run {
+        return Ok(decisions);
+    }
+
+    let mut removed = 0;
+    for decision in decisions.iter().filter(|d| !d.keep) {
+        fs::remove_dir_all(series_path.join(&decision.snapshot_id))?;
+        println!("Removed snapshot: {}", decision.snapshot_id);
+        removed += 1;
+    }
+
+    if removed > 0 && series_path.join(repository::CHUNKS_DIR).is_dir() {
+        remove_unused_chunks(series_path, cipher)?;
+    }
+
+    Ok(decisions)
+}
+
+/// Delete the chunks that are not referenced by any remaining repository snapshot
+fn remove_unused_chunks(series_path: &Path, cipher: Option<&Cipher>) -> Result<(), BackupError> {
+    let used_chunks = repository::used_chunks(series_path, cipher)?;
+    let mut freed = 0;
+    for chunk in repository::list_chunks(series_path)? {
+        if !used_chunks.contains(&chunk) {
+            freed += repository::remove_chunk(series_path, &chunk)?;
+        }
+    }
+
+    println!("Space freed in the repository: {} bytes", freed);
+    Ok(())
+}
+
+/// Go back from `time` by a duration made of years (y), months (m), weeks (w), days (d) and hours (h)
+fn subtract_duration(time: NaiveDateTime, duration: &str) -> Result<NaiveDateTime, BackupError> {
+    let invalid = || BackupError::InvalidRetentionPolicy(format!("invalid keep_within duration: {:?}", duration));
+
+    let mut result = time;
+    let mut number = String::new();
+    for c in duration.trim().chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let amount: u32 = number.parse().map_err(|_| invalid())?;
+        number.clear();
+        result = match c {
+            'y' => amount.checked_mul(12).and_then(|months| result.checked_sub_months(Months::new(months))),
+            'm' => result.checked_sub_months(Months::new(amount)),
+            'w' => TimeDelta::try_weeks(amount as i64).and_then(|delta| result.checked_sub_signed(delta)),
+            'd' => TimeDelta::try_days(amount as i64).and_then(|delta| result.checked_sub_signed(delta)),
+            'h' => TimeDelta::try_hours(amount as i64).and_then(|delta| result.checked_sub_signed(delta)),
+            _ => None,
+        }.ok_or_else(invalid)?;
+    }
+
+    // A trailing number without unit, or an empty duration
+    if !number.is_empty() || result == time {
+        return Err(invalid());
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use chrono::NaiveDate;
+
+    // Series folder holding empty snapshots with the given ids, `latest` pointing to the last one
+    fn series(name: &str, snapshot_ids: &[&str]) -> PathBuf {
+        let series_path = std::env::temp_dir().join(format!("backmeup-retention-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&series_path);
+        for snapshot_id in snapshot_ids {
+            fs::create_dir_all(series_path.join(snapshot_id)).unwrap();
+        }
+        if let Some(latest) = snapshot_ids.last() {
+            backup::update_latest_snapshot(&series_path, latest).unwrap();
+        }
+        series_path
+    }
+
+    fn kept(decisions: &[PruneDecision]) -> Vec<&str> {
+        decisions.iter().filter(|d| d.keep).map(|d| d.snapshot_id.as_str()).collect()
+    }
+
+    fn time(date: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn rules_select_the_newest_snapshot_of_each_bucket() {
+        let series_path = series("buckets", &[
+            "2024-01-30_10-00-00",
+            "2024-01-31_09-00-00",
+            "2024-01-31_18-00-00",
+            "2024-02-01_08-00-00",
+            "2024-02-01_20-00-00",
+        ]);
+        let policy = RetentionPolicy { keep_last: Some(1), keep_daily: Some(2), keep_monthly: Some(2), ..Default::default() };
+        let decisions = plan_prune(&series_path, &policy).unwrap();
+        assert_eq!(kept(&decisions), ["2024-01-31_18-00-00", "2024-02-01_20-00-00"]);
+        assert_eq!(decisions[4].reasons, ["last 1", "daily 2024-02-01", "monthly 2024-02"]);
+        assert_eq!(decisions[2].reasons, ["daily 2024-01-31", "monthly 2024-01"]);
+        assert!(!decisions[0].keep);
+        fs::remove_dir_all(series_path).unwrap();
+    }
+
+    #[test]
+    fn keep_within_counts_from_the_newest_snapshot() {
+        let series_path = series("within", &["2024-01-01_00-00-00", "2024-01-20_00-00-00", "2024-02-01_00-00-00"]);
+        let policy = RetentionPolicy { keep_within: Some("2w".to_string()), ..Default::default() };
+        let decisions = plan_prune(&series_path, &policy).unwrap();
+        assert_eq!(kept(&decisions), ["2024-01-20_00-00-00", "2024-02-01_00-00-00"]);
+        fs::remove_dir_all(series_path).unwrap();
+    }
+
+    #[test]
+    fn latest_snapshot_is_always_kept() {
+        let series_path = series("latest", &["2024-01-01_00-00-00", "2024-01-02_00-00-00"]);
+        // A newer snapshot than `latest`, e.g. left by a backup that failed before updating it
+        fs::create_dir_all(series_path.join("2024-01-03_00-00-00")).unwrap();
+        let policy = RetentionPolicy { keep_last: Some(1), ..Default::default() };
+        let decisions = plan_prune(&series_path, &policy).unwrap();
+        assert_eq!(kept(&decisions), ["2024-01-02_00-00-00", "2024-01-03_00-00-00"]);
+        assert_eq!(decisions[1].reasons, ["latest"]);
+        fs::remove_dir_all(series_path).unwrap();
+    }
+
+    #[test]
+    fn policy_without_rule_is_refused() {
+        let series_path = series("empty", &["2024-01-01_00-00-00", "2024-01-02_00-00-00"]);
+        let policies = [
+            RetentionPolicy::default(),
+            RetentionPolicy { keep_last: Some(0), keep_daily: Some(0), ..Default::default() },
+            RetentionPolicy { keep_within: Some(" ".to_string()), ..Default::default() },
+        ];
+        for policy in policies {
+            assert!(matches!(prune(&series_path, &policy, false, None), Err(BackupError::InvalidRetentionPolicy(_))), "{:?}", policy);
+        }
+        assert_eq!(backup::list_snapshots(&series_path).len(), 2);
+        fs::remove_dir_all(series_path).unwrap();
+    }
+
+    #[test]
+    fn durations_are_subtracted_unit_by_unit() {
+        let now = time("2024-03-31 12:00");
+        assert_eq!(subtract_duration(now, "36h").unwrap(), time("2024-03-30 00:00"));
+        assert_eq!(subtract_duration(now, "2w").unwrap(), time("2024-03-17 12:00"));
+        assert_eq!(subtract_duration(now, "1m").unwrap(), time("2024-02-29 12:00"));
+        assert_eq!(subtract_duration(now, " 1y6m2d ").unwrap(), NaiveDate::from_ymd_opt(2022, 9, 28).unwrap().and_hms_opt(12, 0, 0).unwrap());
+    }
+
+    #[test]
+    fn invalid_durations_are_refused() {
+        let now = time("2024-03-31 12:00");
+        for duration in ["", "5", "d", "3x", "1y2", "0d", "4294967295y", "99999999999h", "4294967295h", "999999999d", "20000000w"] {
+            assert!(matches!(subtract_duration(now, duration), Err(BackupError::InvalidRetentionPolicy(_))), "{:?}", duration);
+        }
+    }
+}
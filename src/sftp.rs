@@ -0,0 +1,651 @@
+use std::{env, fs, io, thread};
+use std::collections::HashSet;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+use ssh2::{CheckResult, ErrorCode, File, FileStat, KnownHostFileKind, OpenFlags, OpenType, Session, Sftp};
+use crate::backup::BackupError;
+use crate::manifest;
+use crate::storage::{self, ObjectInfo, ObjectWriter, StorageBackend};
+
+// Failed operations are retried this many times on a new connection, waiting twice as long each time
+const RETRIES: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Network operations taking longer than this fail, and are retried
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+// Smaller files are always uploaded again from the start. The partial upload of a larger one is
+// only continued from the same file: the index, the report or the log are rewritten under the
+// same name, and a part left by a previous version would corrupt the new one.
+const RESUME_MIN_SIZE: u64 = 1024 * 1024;
+
+// Suffix of the files being uploaded, renamed once complete
+const PARTIAL_SUFFIX: &str = ".part";
+
+// Suffix of the file next to a partial upload, holding the size, the modification time and the
+// hash of the file being uploaded
+const SOURCE_SUFFIX: &str = ".part.source";
+
+// Size of the writes sent to the server
+const BUFFER_SIZE: usize = 256 * 1024;
+
+// SFTP status codes
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_PERMISSION_DENIED: i32 = 3;
+const FX_NO_SUCH_PATH: i32 = 10;
+
+/// Authentication of an `sftp://` destination, the `[sftp]` section of `config.toml`.
+/// The user, the host, the port and the folder are given by the destination:
+/// `sftp://<user>@<host>:<port>/<path>`.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct SftpConfig {
+    // Private key to log in with. Without it the SSH agent is tried, then the default keys of ~/.ssh.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private_key: Option<String>,
+    // Passphrase of the private key, if it has one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    // File of the trusted host keys, ~/.ssh/known_hosts by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub known_hosts: Option<String>,
+}
+
+/// Folder of a server reached over SSH. The connection is opened on first use, after checking the
+/// key of the server against the known hosts, and opened again when it breaks. The backup workers
+/// share the connection, one operation at a time.
+pub(crate) struct SftpBackend {
+    user: String,
+    host: String,
+    port: u16,
+    // Absolute path of the destination folder on the server, without trailing `/`
+    root: String,
+    config: SftpConfig,
+    sftp: Mutex<Option<Sftp>>,
+    // Folders known to exist, so that they are not checked again for every upload
+    folders: Mutex<HashSet<String>>,
+}
+
+impl SftpBackend {
+    /// Backend of the destination `sftp://<location>`, where the location is `<user>@<host>:<port>/<path>`
+    pub(crate) fn new(config: &SftpConfig, location: &str) -> Result<Self, BackupError> {
+        let invalid = |message: &str| BackupError::InvalidDestination(message.to_string());
+
+        let (authority, path) = location.split_once('/').unwrap_or((location, ""));
+        let (user, address) = authority.rsplit_once('@').ok_or_else(|| invalid("the SFTP destination has no user"))?;
+        // IPv6 addresses are written in brackets, as in `[::1]:22`
+        let (host, port) = match address.strip_prefix('[') {
+            Some(rest) => {
+                let (host, port) = rest.split_once(']').ok_or_else(|| invalid("the SFTP address is not valid"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match address.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (address, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid("the SFTP port is not valid"))?,
+            None => 22,
+        };
+        if user.is_empty() || host.is_empty() {
+            return Err(invalid("the SFTP destination has no user or no host"));
+        }
+
+        Ok(SftpBackend {
+            user: user.to_string(),
+            host: host.to_string(),
+            port,
+            root: format!("/{}", path.trim_matches('/')).trim_end_matches('/').to_string(),
+            config: config.clone(),
+            sftp: Mutex::new(None),
+            folders: Mutex::new(HashSet::new()),
+        })
+    }
+
+    fn path(&self, key: &str) -> String {
+        format!("{}/{}", self.root, key.trim_end_matches('/'))
+    }
+
+    /// Open an SFTP session: connect, check the key of the server and log in
+    fn connect(&self) -> io::Result<Sftp> {
+        let unreachable = |e: io::Error| io::Error::new(e.kind(), format!("{}:{}: {}", self.host, self.port, e));
+        let addresses = (self.host.as_str(), self.port).to_socket_addrs().map_err(unreachable)?;
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+        let mut stream = None;
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, TIMEOUT) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let stream = stream.ok_or_else(|| unreachable(last_error))?;
+
+        let mut session = Session::new().map_err(sftp_error)?;
+        session.set_tcp_stream(stream);
+        session.set_timeout(TIMEOUT.as_millis() as u32);
+        session.handshake().map_err(sftp_error)?;
+        self.check_host_key(&session)?;
+        self.authenticate(&session)?;
+        session.sftp().map_err(sftp_error)
+    }
+
+    /// Refuse a server whose key is not the one recorded in the known hosts, or is not recorded at all
+    fn check_host_key(&self, session: &Session) -> io::Result<()> {
+        let known_hosts_path = match &self.config.known_hosts {
+            Some(path) if !path.trim().is_empty() => PathBuf::from(path.trim()),
+            _ => ssh_folder().join("known_hosts"),
+        };
+        let refused = |message: String| io::Error::new(io::ErrorKind::PermissionDenied, message);
+
+        let mut known_hosts = session.known_hosts().map_err(sftp_error)?;
+        if known_hosts_path.is_file() {
+            known_hosts.read_file(&known_hosts_path, KnownHostFileKind::OpenSSH).map_err(sftp_error)?;
+        }
+        let (key, _) = session.host_key().ok_or_else(|| refused(format!("{} sent no host key", self.host)))?;
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(refused(format!(
+                "the host key of {} does not match the one in {}: the server may be impersonated",
+                self.host,
+                known_hosts_path.display()
+            ))),
+            CheckResult::NotFound => Err(refused(format!(
+                "the host key of {} is not in {}: connect once with ssh to check and add it",
+                self.host,
+                known_hosts_path.display()
+            ))),
+            CheckResult::Failure => Err(refused(format!("the host key of {} could not be checked", self.host))),
+        }
+    }
+
+    /// Log in with the configured key, or else with the SSH agent or one of the default keys
+    fn authenticate(&self, session: &Session) -> io::Result<()> {
+        let passphrase = self.config.passphrase.as_deref().filter(|passphrase| !passphrase.is_empty());
+        match &self.config.private_key {
+            Some(private_key) if !private_key.trim().is_empty() => {
+                // A refused key is not worth retrying, unlike a broken session
+                session.userauth_pubkey_file(&self.user, None, Path::new(private_key.trim()), passphrase).map_err(|e| {
+                    io::Error::new(io::ErrorKind::PermissionDenied, format!("{}@{} refused the key {}: {}", self.user, self.host, private_key.trim(), e.message()))
+                })?;
+            }
+            _ => {
+                if session.userauth_agent(&self.user).is_err() {
+                    for name in ["id_ed25519", "id_ecdsa", "id_rsa"] {
+                        let private_key = ssh_folder().join(name);
+                        if private_key.is_file() && session.userauth_pubkey_file(&self.user, None, &private_key, passphrase).is_ok() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        if session.authenticated() {
+            Ok(())
+        } else {
+            Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("{}@{} refused the SSH keys", self.user, self.host)))
+        }
+    }
+
+    /// Run an operation on the SFTP session, connecting first if needed.
+    /// When the connection fails or breaks, the operation is retried on a new one.
+    fn with_sftp<T>(&self, operation: impl Fn(&Sftp) -> io::Result<T>) -> io::Result<T> {
+        let mut sftp = self.sftp.lock().unwrap();
+        let mut attempt = 0;
+        loop {
+            if sftp.is_none() {
+                match self.connect() {
+                    Ok(connected) => *sftp = Some(connected),
+                    Err(e) if attempt < RETRIES && is_retryable(&e) => {
+                        thread::sleep(RETRY_DELAY * 2u32.pow(attempt));
+                        attempt += 1;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let result = operation(sftp.as_ref().expect("connected above"));
+            match result {
+                Err(e) if attempt < RETRIES && is_retryable(&e) => {
+                    *sftp = None;
+                    self.folders.lock().unwrap().clear();
+                    thread::sleep(RETRY_DELAY * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Create the parent folders of a file, the ones missing, up to the destination folder itself
+    fn create_parents(&self, sftp: &Sftp, path: &str) -> io::Result<()> {
+        let Some((parent, _)) = path.rsplit_once('/') else {
+            return Ok(());
+        };
+        if parent.is_empty() || self.folders.lock().unwrap().contains(parent) {
+            return Ok(());
+        }
+        if sftp.stat(Path::new(parent)).is_err() {
+            self.create_parents(sftp, parent)?;
+            if let Err(e) = sftp.mkdir(Path::new(parent), 0o755) {
+                // Created in the meantime by another backup
+                if sftp.stat(Path::new(parent)).is_err() {
+                    return Err(sftp_error(e));
+                }
+            }
+        }
+        self.folders.lock().unwrap().insert(parent.to_string());
+        Ok(())
+    }
+}
+
+impl StorageBackend for SftpBackend {
+    fn put(&self, key: &str, source: &Path) -> io::Result<Option<String>> {
+        let path = self.path(key);
+        let partial_path = format!("{}{}", path, PARTIAL_SUFFIX);
+        let source_path = format!("{}{}", path, SOURCE_SUFFIX);
+        let metadata = fs::metadata(source)?;
+        let size = metadata.len();
+        let stamp = if size >= RESUME_MIN_SIZE { Some(source_stamp(source, &metadata)?) } else { None };
+
+        self.with_sftp(|sftp| {
+            self.create_parents(sftp, &path)?;
+
+            // A large file is written after the part an interrupted upload of the same file left
+            let offset = match &stamp {
+                Some(stamp) if read_stamp(sftp, &source_path).as_ref() == Some(stamp) => sftp.stat(Path::new(&partial_path))
+                    .ok()
+                    .and_then(|stat| stat.size)
+                    .filter(|uploaded| *uploaded <= size)
+                    .unwrap_or(0),
+                _ => 0,
+            };
+            let mut remote = if offset > 0 {
+                let mut remote = sftp.open_mode(Path::new(&partial_path), OpenFlags::WRITE, 0o644, OpenType::File).map_err(sftp_error)?;
+                remote.seek(SeekFrom::Start(offset)).map_err(broken)?;
+                remote
+            } else {
+                // The part is emptied before the file it comes from is recorded
+                let remote = sftp.create(Path::new(&partial_path)).map_err(sftp_error)?;
+                if let Some(stamp) = &stamp {
+                    let mut source_file = sftp.create(Path::new(&source_path)).map_err(sftp_error)?;
+                    source_file.write_all(stamp.as_bytes()).map_err(broken)?;
+                }
+                remote
+            };
+
+            let mut local = fs::File::open(source)?;
+            local.seek(SeekFrom::Start(offset))?;
+            let mut buffer = vec![0; BUFFER_SIZE];
+            loop {
+                let read = local.read(&mut buffer)?;
+                if read == 0 {
+                    break;
+                }
+                remote.write_all(&buffer[..read]).map_err(broken)?;
+            }
+            remote.flush().map_err(broken)?;
+            drop(remote);
+
+            // The file only appears under its name once complete
+            rename_partial(sftp, &partial_path, &path)?;
+            if stamp.is_some() {
+                let _ = sftp.unlink(Path::new(&source_path));
+            }
+            Ok(())
+        })?;
+
+        // Permissions and times are kept where the server allows it
+        let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|time| time.as_secs());
+        let mode = manifest::file_mode(&metadata) & 0o7777;
+        let stat = FileStat { size: None, uid: None, gid: None, perm: Some(mode).filter(|mode| *mode != 0), atime: modified, mtime: modified };
+        let _ = self.with_sftp(|sftp| sftp.setstat(Path::new(&path), stat.clone()).map_err(sftp_error));
+        Ok(None)
+    }
+
+    fn get(&self, key: &str, destination: &Path) -> io::Result<()> {
+        let path = self.path(key);
+        let tmp_path = storage::temporary_path(destination, ".download");
+        let result = self.with_sftp(|sftp| {
+            let mut remote = sftp.open(Path::new(&path)).map_err(sftp_error)?;
+            let mut local = fs::File::create(&tmp_path)?;
+            let mut buffer = vec![0; BUFFER_SIZE];
+            loop {
+                let read = remote.read(&mut buffer).map_err(broken)?;
+                if read == 0 {
+                    break;
+                }
+                local.write_all(&buffer[..read])?;
+            }
+            local.flush()
+        });
+        match result {
+            Ok(()) => fs::rename(&tmp_path, destination),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let path = self.path(prefix);
+        let entries = match self.with_sftp(|sftp| sftp.readdir(Path::new(&path)).map_err(sftp_error)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            result => result?,
+        };
+        let mut names = Vec::new();
+        for (entry_path, stat) in entries {
+            let Some(name) = entry_path.file_name().map(|name| name.to_string_lossy().to_string()) else {
+                continue;
+            };
+            // Uploads in progress are not objects yet
+            if stat.is_dir() {
+                names.push(format!("{}/", name));
+            } else if !name.ends_with(PARTIAL_SUFFIX) && !name.ends_with(SOURCE_SUFFIX) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        self.with_sftp(|sftp| {
+            match sftp.unlink(Path::new(&path)).map_err(sftp_error) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                result => result?,
+            }
+            // Folders only exist through their files, as in a bucket
+            let mut folder = path.as_str();
+            while let Some((parent, _)) = folder.rsplit_once('/') {
+                if parent.len() <= self.root.len() || sftp.rmdir(Path::new(parent)).is_err() {
+                    break;
+                }
+                self.folders.lock().unwrap().remove(parent);
+                folder = parent;
+            }
+            Ok(())
+        })
+    }
+
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>> {
+        let path = self.path(key);
+        match self.with_sftp(|sftp| sftp.stat(Path::new(&path)).map_err(sftp_error)) {
+            Ok(stat) if stat.is_file() => Ok(Some(ObjectInfo { size: stat.size.unwrap_or(0), etag: None })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the object into its `.part` file as it comes, renamed once complete
+    fn create(&self, key: &str, _size: Option<u64>) -> io::Result<Box<dyn ObjectWriter + '_>> {
+        let path = self.path(key);
+        let partial_path = format!("{}{}", path, PARTIAL_SUFFIX);
+        let file = self.with_sftp(|sftp| {
+            self.create_parents(sftp, &path)?;
+            sftp.create(Path::new(&partial_path)).map_err(sftp_error)
+        })?;
+        Ok(Box::new(SftpObject { backend: self, path, partial_path, file: Some(BufWriter::with_capacity(BUFFER_SIZE, file)), finished: false }))
+    }
+
+    /// Read the object from the server as it is consumed
+    fn open(&self, key: &str) -> io::Result<Box<dyn Read + '_>> {
+        let path = self.path(key);
+        let file = self.with_sftp(|sftp| sftp.open(Path::new(&path)).map_err(sftp_error))?;
+        Ok(Box::new(SftpReader(file)))
+    }
+
+    /// Free space of the filesystem of the destination folder, on servers supporting the
+    /// `statvfs@openssh.com` extension
+    fn available_space(&self) -> Option<u64> {
+        let root = if self.root.is_empty() { "/" } else { self.root.as_str() };
+        let stat = self.with_sftp(|sftp| sftp.opendir(Path::new(root)).and_then(|mut folder| folder.statvfs()).map_err(sftp_error)).ok()?;
+        Some(stat.f_bavail * stat.f_frsize)
+    }
+}
+
+/// Object being written by `SftpBackend::create`. The `.part` file is removed if the object is
+/// dropped before it is finished.
+struct SftpObject<'a> {
+    backend: &'a SftpBackend,
+    path: String,
+    partial_path: String,
+    file: Option<BufWriter<File>>,
+    finished: bool,
+}
+
+impl Write for SftpObject<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("written before being finished").write(buf).map_err(broken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("written before being finished").flush().map_err(broken)
+    }
+}
+
+impl ObjectWriter for SftpObject<'_> {
+    fn finish(mut self: Box<Self>) -> io::Result<Option<String>> {
+        let file = self.file.take().expect("finished once");
+        // The file is closed before being renamed
+        drop(file.into_inner().map_err(|e| broken(e.into_error()))?);
+        self.backend.with_sftp(|sftp| rename_partial(sftp, &self.partial_path, &self.path))?;
+        self.finished = true;
+        Ok(None)
+    }
+}
+
+impl Drop for SftpObject<'_> {
+    fn drop(&mut self) {
+        drop(self.file.take());
+        if !self.finished {
+            let _ = self.backend.with_sftp(|sftp| sftp.unlink(Path::new(&self.partial_path)).map_err(sftp_error));
+        }
+    }
+}
+
+/// Remote file being read, whose errors mean the connection is broken
+struct SftpReader(File);
+
+impl Read for SftpReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf).map_err(broken)
+    }
+}
+
+/// Give a complete upload its name, replacing the file there
+fn rename_partial(sftp: &Sftp, partial_path: &str, path: &str) -> io::Result<()> {
+    if sftp.rename(Path::new(partial_path), Path::new(path), None).is_err() {
+        // Servers speaking version 3 of the protocol do not replace an existing file
+        let _ = sftp.unlink(Path::new(path));
+        sftp.rename(Path::new(partial_path), Path::new(path), None).map_err(sftp_error)?;
+    }
+    Ok(())
+}
+
+/// What identifies the file being uploaded: its size, its modification time and its hash
+fn source_stamp(source: &Path, metadata: &fs::Metadata) -> io::Result<String> {
+    let modified = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+    Ok(format!("{} {}.{:09} {}", metadata.len(), modified.as_secs(), modified.subsec_nanos(), manifest::hash_file(source)?))
+}
+
+/// Contents of the file recording the source of a partial upload, `None` if there is none
+fn read_stamp(sftp: &Sftp, source_path: &str) -> Option<String> {
+    let mut stamp = String::new();
+    sftp.open(Path::new(source_path)).ok()?.read_to_string(&mut stamp).ok()?;
+    Some(stamp)
+}
+
+/// IO error for a failed SSH or SFTP operation. The errors of the session itself, unlike the
+/// answers of the server, mean the connection is broken.
+fn sftp_error(error: ssh2::Error) -> io::Error {
+    let kind = match error.code() {
+        ErrorCode::SFTP(FX_NO_SUCH_FILE) | ErrorCode::SFTP(FX_NO_SUCH_PATH) => io::ErrorKind::NotFound,
+        ErrorCode::SFTP(FX_PERMISSION_DENIED) => io::ErrorKind::PermissionDenied,
+        ErrorCode::SFTP(_) => io::ErrorKind::Other,
+        ErrorCode::Session(_) => io::ErrorKind::ConnectionAborted,
+    };
+    io::Error::new(kind, format!("SFTP error: {}", error.message()))
+}
+
+/// Reading or writing a remote file fails when the connection breaks
+fn broken(error: io::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, error)
+}
+
+fn is_retryable(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Folder of the SSH keys and known hosts of the user
+fn ssh_folder() -> PathBuf {
+    env::var_os("HOME")
+        .or_else(|| env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(".ssh")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD;
+    use crate::storage::tests::{check_operations, check_resumed_push, temp_folder, test_bytes, unique_name};
+
+    /// Server given by BACKMEUP_TEST_SFTP_URL, as `<user>@<host>:<port>/<path>`, logged in with the key
+    /// BACKMEUP_TEST_SFTP_KEY and checked against BACKMEUP_TEST_SFTP_KNOWN_HOSTS, e.g. a local sshd
+    fn test_server() -> (SftpConfig, String) {
+        let config = SftpConfig {
+            private_key: Some(env::var("BACKMEUP_TEST_SFTP_KEY").expect("BACKMEUP_TEST_SFTP_KEY is not set")),
+            passphrase: None,
+            known_hosts: Some(env::var("BACKMEUP_TEST_SFTP_KNOWN_HOSTS").expect("BACKMEUP_TEST_SFTP_KNOWN_HOSTS is not set")),
+        };
+        (config, env::var("BACKMEUP_TEST_SFTP_URL").expect("BACKMEUP_TEST_SFTP_URL is not set"))
+    }
+
+    #[test]
+    #[ignore]
+    fn sftp_backend() {
+        let (config, location) = test_server();
+        let backend = SftpBackend::new(&config, &location).unwrap();
+        check_operations(&backend, 3 * 1024 * 1024);
+        check_resumed_push(&backend);
+    }
+
+    #[test]
+    #[ignore]
+    fn interrupted_upload_continues_from_the_part_file_of_the_same_file() {
+        let (config, location) = test_server();
+        let backend = SftpBackend::new(&config, &location).unwrap();
+        let folder = temp_folder("sftp-resume");
+        let key = format!("{}/large.bin", unique_name("resume"));
+        let contents = test_bytes(2 * RESUME_MIN_SIZE as usize + 5);
+        let half = contents.len() / 2;
+        fs::write(folder.join("large.bin"), &contents).unwrap();
+        let metadata = fs::metadata(folder.join("large.bin")).unwrap();
+
+        // The first half already on the server, written differently so that a new upload of it is seen
+        fs::write(folder.join("part"), vec![0xAA; half]).unwrap();
+        backend.put(&format!("{}{}", key, PARTIAL_SUFFIX), &folder.join("part")).unwrap();
+
+        // Left by another file: the upload starts again
+        fs::write(folder.join("stamp"), format!("{} 0.000000000 {}", contents.len(), "0".repeat(64))).unwrap();
+        backend.put(&format!("{}{}", key, SOURCE_SUFFIX), &folder.join("stamp")).unwrap();
+        backend.put(&key, &folder.join("large.bin")).unwrap();
+        backend.get(&key, &folder.join("downloaded")).unwrap();
+        assert!(fs::read(folder.join("downloaded")).unwrap() == contents);
+
+        // Left by the same file: the upload continues after the part
+        backend.put(&format!("{}{}", key, PARTIAL_SUFFIX), &folder.join("part")).unwrap();
+        fs::write(folder.join("stamp"), source_stamp(&folder.join("large.bin"), &metadata).unwrap()).unwrap();
+        backend.put(&format!("{}{}", key, SOURCE_SUFFIX), &folder.join("stamp")).unwrap();
+        backend.put(&key, &folder.join("large.bin")).unwrap();
+        backend.get(&key, &folder.join("downloaded")).unwrap();
+        let downloaded = fs::read(folder.join("downloaded")).unwrap();
+        assert_eq!(downloaded.len(), contents.len());
+        assert!(downloaded[..half].iter().all(|byte| *byte == 0xAA));
+        assert!(downloaded[half..] == contents[half..]);
+
+        assert!(backend.stat(&format!("{}{}", key, PARTIAL_SUFFIX)).unwrap().is_none());
+        assert!(backend.stat(&format!("{}{}", key, SOURCE_SUFFIX)).unwrap().is_none());
+        assert_eq!(backend.list(&format!("{}/", key.rsplit_once('/').unwrap().0)).unwrap(), ["large.bin"]);
+        backend.delete(&key).unwrap();
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn streamed_object_appears_once_finished() {
+        let (config, location) = test_server();
+        let backend = SftpBackend::new(&config, &location).unwrap();
+        let prefix = unique_name("stream");
+        let contents = test_bytes(3 * BUFFER_SIZE + 7);
+
+        let mut writer = backend.create(&format!("{}/dropped.bin", prefix), None).unwrap();
+        writer.write_all(&contents).unwrap();
+        drop(writer);
+        let mut writer = backend.create(&format!("{}/large.bin", prefix), None).unwrap();
+        writer.write_all(&contents).unwrap();
+        assert!(backend.list(&format!("{}/", prefix)).unwrap().is_empty());
+        writer.finish().unwrap();
+        assert_eq!(backend.list(&format!("{}/", prefix)).unwrap(), ["large.bin"]);
+
+        let mut read = Vec::new();
+        backend.open(&format!("{}/large.bin", prefix)).unwrap().read_to_end(&mut read).unwrap();
+        assert!(read == contents);
+        assert!(backend.stat(&format!("{}/dropped.bin{}", prefix, PARTIAL_SUFFIX)).unwrap().is_none());
+        assert_eq!(backend.open(&format!("{}/missing.bin", prefix)).err().unwrap().kind(), io::ErrorKind::NotFound);
+        backend.delete(&format!("{}/large.bin", prefix)).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn unknown_or_changed_host_keys_are_refused() {
+        let (mut config, location) = test_server();
+        let folder = temp_folder("sftp-hosts");
+        let known_hosts = fs::read_to_string(config.known_hosts.as_ref().unwrap()).unwrap();
+
+        // The same hosts with another key: the last byte of each key is changed
+        let changed: Vec<String> = known_hosts.lines()
+            .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let mut key = STANDARD.decode(fields[2]).unwrap();
+                *key.last_mut().unwrap() ^= 0xFF;
+                format!("{} {} {}", fields[0], fields[1], STANDARD.encode(key))
+            })
+            .collect();
+        fs::write(folder.join("changed"), changed.join("\n") + "\n").unwrap();
+        config.known_hosts = Some(folder.join("changed").display().to_string());
+        let error = SftpBackend::new(&config, &location).unwrap().stat("missing").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("does not match"), "{}", error);
+
+        fs::write(folder.join("empty"), "").unwrap();
+        config.known_hosts = Some(folder.join("empty").display().to_string());
+        let error = SftpBackend::new(&config, &location).unwrap().stat("missing").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(error.to_string().contains("is not in"), "{}", error);
+
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use std::{fs, io};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use crate::backup::{Config, SNAPSHOT_METADATA_DIR};
+use crate::compression;
+use crate::encryption::{self, Cipher};
+use crate::manifest::ManifestEntry;
+use crate::metadata::EntryMetadata;
+use crate::storage::{self, RemoteSnapshot, StorageBackend};
+
+/// Hidden folder of the series where a snapshot is written before being committed.
+/// Its name cannot be parsed as a snapshot id, so it is never listed as a snapshot.
+pub(crate) const STAGING_DIR: &str = ".staging";
+
+// Entries completed in the staged snapshot, one per line
+const JOURNAL_FILE: &str = "journal.jsonl";
+
+/// A file or symlink completed in the staged snapshot
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub(crate) struct JournalEntry {
+    // Checksum entry of a file, missing for a symlink
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub entry: Option<ManifestEntry>,
+    pub metadata: EntryMetadata,
+    // Space taken on the destination, 0 for a file linked to the previous snapshot
+    pub stored_size: u64,
+}
+
+/// Journal of the staged snapshot: the entries completed by an interrupted run, which are not
+/// saved again, and the file the entries completed by this run are appended to.
+/// With encryption each line is encrypted on its own, as it names a file of the source.
+pub(crate) struct Journal<'a> {
+    file: fs::File,
+    cipher: Option<&'a Cipher>,
+    completed: HashMap<String, JournalEntry>,
+    // The journal was left by an interrupted run, so the staged snapshot may hold partial files
+    resuming: bool,
+    // First write that failed, after which the journal is no longer written
+    error: Option<io::Error>,
+}
+
+impl<'a> Journal<'a> {
+    /// Open the journal of a staged snapshot, reading the entries already completed
+    pub(crate) fn open(staged_path: &Path, cipher: Option<&'a Cipher>) -> io::Result<Self> {
+        let metadata_path = staged_path.join(SNAPSHOT_METADATA_DIR);
+        fs::create_dir_all(&metadata_path)?;
+        let journal_path = metadata_path.join(JOURNAL_FILE);
+        let resuming = journal_path.is_file();
+
+        let mut completed = HashMap::new();
+        if let Ok(file) = fs::File::open(&journal_path) {
+            // The last line may have been cut by the interruption: the journal ends there
+            for line in BufReader::new(file).lines() {
+                let Some(journal_entry) = line.ok().and_then(|line| decode_line(&line, cipher)) else { break };
+                completed.insert(journal_entry.metadata.path.clone(), journal_entry);
+            }
+        }
+
+        let file = fs::OpenOptions::new().create(true).append(true).open(&journal_path)?;
+        Ok(Journal { file, cipher, completed, resuming, error: None })
+    }
+
+    /// Whether this run resumes an interrupted one
+    pub(crate) fn is_resuming(&self) -> bool {
+        self.resuming
+    }
+
+    /// Entry completed by the interrupted run, if it is still the same as in the source.
+    /// A file must have the same size and modification time, and its copy must be whole, in the
+    /// staged snapshot or for a remote destination in the destination.
+    pub(crate) fn completed(&self, snapshot_path: &Path, remote: Option<&RemoteSnapshot>, current: &EntryMetadata, size: u64) -> Option<&JournalEntry> {
+        let journal_entry = self.completed.get(&current.path)?;
+        match &journal_entry.entry {
+            Some(entry) => {
+                let stored_size = match remote {
+                    Some(remote) => remote.resumed_size(&compression::stored_path(Path::new(""), entry, self.cipher))?,
+                    None => fs::metadata(compression::stored_path(snapshot_path, entry, self.cipher)).ok()?.len(),
+                };
+                let whole = journal_entry.stored_size == 0 || stored_size == journal_entry.stored_size;
+                (whole && entry.size == size && entry.modified == current.modified.seconds).then_some(journal_entry)
+            }
+            None => (journal_entry.metadata.link_target == current.link_target).then_some(journal_entry),
+        }
+    }
+
+    /// Record an entry completed by this run.
+    /// A failed write does not stop the backup, it only means it cannot be resumed: the error is
+    /// kept for the report.
+    pub(crate) fn append(&mut self, entry: Option<&ManifestEntry>, metadata: &EntryMetadata, stored_size: u64) {
+        if self.error.is_none() {
+            let journal_entry = JournalEntry { entry: entry.cloned(), metadata: metadata.clone(), stored_size };
+            self.error = self.write_line(&journal_entry).err();
+        }
+    }
+
+    fn write_line(&mut self, journal_entry: &JournalEntry) -> io::Result<()> {
+        let json = serde_json::to_vec(journal_entry).map_err(io::Error::other)?;
+        let mut line = match self.cipher {
+            Some(cipher) => STANDARD.encode(cipher.encrypt(&json)?).into_bytes(),
+            None => json,
+        };
+        line.push(b'\n');
+        // A single write per line, so that an interruption cuts at most the last one
+        self.file.write_all(&line)
+    }
+
+    /// Error that stopped the journal, if any
+    pub(crate) fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+fn decode_line(line: &str, cipher: Option<&Cipher>) -> Option<JournalEntry> {
+    let json = match cipher {
+        Some(cipher) => cipher.decrypt(&STANDARD.decode(line).ok()?).ok()?,
+        None => line.as_bytes().to_vec(),
+    };
+    serde_json::from_slice(&json).ok()
+}
+
+/// Folder a snapshot is written to until it is committed
+pub(crate) fn staged_path(series_path: &Path, snapshot_id: &str) -> PathBuf {
+    series_path.join(STAGING_DIR).join(snapshot_id)
+}
+
+/// Id of the snapshot left unfinished by an interrupted backup, if it can be resumed.
+/// Only plain snapshots copied file by file are resumed, and only with the same encryption:
+/// anything else left in the staging folder is deleted, along with the files it wrote into a
+/// remote destination, and the backup starts from scratch.
+pub(crate) fn unfinished_snapshot(series_path: &Path, config: &Config, backend: Option<&dyn StorageBackend>, cipher: Option<&Cipher>) -> io::Result<Option<String>> {
+    let staging_path = series_path.join(STAGING_DIR);
+    let Ok(entries) = fs::read_dir(&staging_path) else { return Ok(None) };
+
+    let resumable = config.storage_format == "plain" && config.compression.mode != "tar-zst";
+    let snapshot_id = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            resumable
+                && path.join(SNAPSHOT_METADATA_DIR).join(JOURNAL_FILE).is_file()
+                && encryption::is_encrypted_snapshot(path) == cipher.is_some()
+        })
+        .and_then(|path| path.file_name().map(|name| name.to_string_lossy().to_string()));
+
+    if snapshot_id.is_none() {
+        if let Some(backend) = backend {
+            for entry in fs::read_dir(&staging_path)? {
+                storage::delete_snapshot(backend, series_path, &entry?.file_name().to_string_lossy())?;
+            }
+        }
+        fs::remove_dir_all(&staging_path)?;
+    }
+    Ok(snapshot_id)
+}
+
+/// Make the staged snapshot a committed one, renaming it into the series folder.
+/// The rename is atomic: the snapshot appears whole or not at all.
+pub(crate) fn commit(series_path: &Path, snapshot_id: &str) -> io::Result<()> {
+    let staged_path = staged_path(series_path, snapshot_id);
+    match fs::remove_file(staged_path.join(SNAPSHOT_METADATA_DIR).join(JOURNAL_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    fs::rename(&staged_path, series_path.join(snapshot_id))?;
+    fs::remove_dir_all(series_path.join(STAGING_DIR))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::temp_folder;
+    use crate::encryption::EncryptionConfig;
+    use crate::manifest;
+
+    // Copy a source file into the staged snapshot and record it in the journal, as a backup does
+    fn save(journal: &mut Journal, source: &Path, staged: &Path, name: &str) -> EntryMetadata {
+        let source_file = source.join(name);
+        fs::copy(&source_file, staged.join(name)).unwrap();
+        let metadata = fs::metadata(&source_file).unwrap();
+        let entry = ManifestEntry::new(name.to_string(), &metadata, manifest::hash_file(&source_file).unwrap());
+        let entry_metadata = EntryMetadata::read(&source_file, name, false, &mut Vec::new()).unwrap();
+        journal.append(Some(&entry), &entry_metadata, metadata.len());
+        entry_metadata
+    }
+
+    #[test]
+    fn completed_entries_are_resumed() {
+        let folder = temp_folder("resume");
+        let (source, staged) = (folder.join("source"), folder.join("staged"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&staged).unwrap();
+        fs::write(source.join("a.txt"), "first file").unwrap();
+        fs::write(source.join("b.txt"), "second file").unwrap();
+        fs::write(source.join("c.txt"), "third file").unwrap();
+
+        let mut journal = Journal::open(&staged, None).unwrap();
+        assert!(!journal.is_resuming());
+        let a = save(&mut journal, &source, &staged, "a.txt");
+        let b = save(&mut journal, &source, &staged, "b.txt");
+        let c = save(&mut journal, &source, &staged, "c.txt");
+        assert!(journal.error().is_none());
+        drop(journal);
+
+        // Interrupted while writing the journal line of c.txt
+        let journal_path = staged.join(SNAPSHOT_METADATA_DIR).join(JOURNAL_FILE);
+        let contents = fs::read_to_string(&journal_path).unwrap();
+        let last_line = contents.trim_end().rfind('\n').unwrap() + 1;
+        fs::write(&journal_path, &contents[..last_line + 10]).unwrap();
+        // Interrupted while copying b.txt again, e.g. by a previous resume
+        fs::write(staged.join("b.txt"), "second").unwrap();
+
+        let journal = Journal::open(&staged, None).unwrap();
+        assert!(journal.is_resuming());
+        assert!(journal.completed(&staged, None, &a, 10).is_some());
+        assert!(journal.completed(&staged, None, &b, 11).is_none());
+        assert!(journal.completed(&staged, None, &c, 10).is_none());
+        // Changed in the source since the interrupted run
+        assert!(journal.completed(&staged, None, &a, 12).is_none());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn encrypted_journal_needs_the_key() {
+        let folder = temp_folder("encrypted");
+        let (source, staged) = (folder.join("source"), folder.join("staged"));
+        fs::create_dir_all(&source).unwrap();
+        fs::create_dir_all(&staged).unwrap();
+        fs::write(source.join("secret.txt"), "secret").unwrap();
+
+        let config = Config {
+            encryption: Some(EncryptionConfig { passphrase: Some("passphrase".to_string()), key_file: None }),
+            ..Default::default()
+        };
+        let cipher = encryption::load_cipher(&config, &folder, true).unwrap().unwrap();
+        let mut journal = Journal::open(&staged, Some(&cipher)).unwrap();
+        let entry_metadata = save(&mut journal, &source, &staged, "secret.txt");
+        drop(journal);
+
+        let contents = fs::read_to_string(staged.join(SNAPSHOT_METADATA_DIR).join(JOURNAL_FILE)).unwrap();
+        assert!(!contents.contains("secret"));
+        assert!(Journal::open(&staged, None).unwrap().completed.is_empty());
+        assert!(Journal::open(&staged, Some(&cipher)).unwrap().completed.contains_key(&entry_metadata.path));
+        fs::remove_dir_all(folder).unwrap();
+    }
+}
@@ -0,0 +1,1205 @@
+use std::{env, fs, io, process};
+use std::collections::{BTreeMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use walkdir::WalkDir;
+use crate::backup::{self, BackupError, Config, LATEST_SNAPSHOT, SNAPSHOT_METADATA_DIR};
+use crate::compression::{self, ARCHIVE_FILE};
+use crate::encryption::{Cipher, KEY_FILE};
+use crate::repository::{self, CHUNKS_DIR};
+use crate::s3::S3Backend;
+use crate::sftp::SftpBackend;
+use crate::volumes;
+use crate::webdav::WebDavBackend;
+
+// Written in the metadata folder of a snapshot once all its files are uploaded, with the ETags of
+// their objects
+const UPLOADED_MARKER: &str = "uploaded";
+
+// Log of the backups of a series, uploaded after each backup
+const LOG_FILE: &str = "backup_log.txt";
+
+// Number of the next temporary file of this process
+static TEMPORARY_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Size of an object of a storage backend, and its ETag for the backends that have them
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub size: u64,
+    // Changes whenever the object is written, as opposed to its size
+    pub etag: Option<String>,
+}
+
+/// Place the snapshots are stored, such as a local folder or a bucket.
+/// Objects are named by keys: paths relative to the destination with `/` as separator,
+/// such as `<series>/<snapshot-id>/.backmeup/manifest.json`.
+/// Backends are shared by the copy workers of a backup.
+pub trait StorageBackend: Sync {
+    /// Store the file `source` as `key`, replacing the object if there is one, and return the ETag
+    /// of the new object if the backend gave one
+    fn put(&self, key: &str, source: &Path) -> io::Result<Option<String>>;
+
+    /// Write the object `key` into the file `destination`
+    fn get(&self, key: &str, destination: &Path) -> io::Result<()>;
+
+    /// Names of the objects and folders directly inside the folder `prefix`, which is empty or
+    /// ends with `/`. The names of folders end with `/`.
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Remove the object `key`, doing nothing if it does not exist
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Size of the object `key`, or `None` if it does not exist
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>>;
+
+    /// Copy the object `from` to `to` on the backend itself, without sending it again, and return
+    /// the ETag of the copy. Backends unable to copy objects return an `Unsupported` error.
+    fn copy(&self, _from: &str, _to: &str) -> io::Result<Option<String>> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the destination cannot copy files"))
+    }
+
+    /// Write the object `key` through the returned writer. The object only replaces the previous
+    /// one once the writer is finished, and a writer dropped before leaves nothing. `size` is the
+    /// size of the object, when known in advance.
+    /// By default the object is written into a temporary file, stored with `put` once finished.
+    fn create(&self, key: &str, _size: Option<u64>) -> io::Result<Box<dyn ObjectWriter + '_>> {
+        spooled_object(self, key)
+    }
+
+    /// Read the object `key`, with a `NotFound` error if it does not exist.
+    /// By default the object is downloaded with `get` into a temporary file first.
+    fn open(&self, key: &str) -> io::Result<Box<dyn Read + '_>> {
+        let path = TemporaryFile::new_path();
+        self.get(key, &path)?;
+        let file = fs::File::open(&path)?;
+        Ok(Box::new(TemporaryFile { path, file }))
+    }
+
+    /// Space left on the destination, `None` if it cannot be known, such as for a bucket
+    fn available_space(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Object being written by `StorageBackend::create`
+pub trait ObjectWriter: Write {
+    /// Complete the object, which then appears under its key, and return its ETag if the backend
+    /// gave one
+    fn finish(self: Box<Self>) -> io::Result<Option<String>>;
+}
+
+/// File of the temporary folder of the system, deleted once dropped
+struct TemporaryFile {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl TemporaryFile {
+    fn new_path() -> PathBuf {
+        let number = TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed);
+        env::temp_dir().join(format!("backmeup-{}-{}.tmp", process::id(), number))
+    }
+
+    fn create() -> io::Result<Self> {
+        let path = TemporaryFile::new_path();
+        let file = fs::File::create(&path)?;
+        Ok(TemporaryFile { path, file })
+    }
+}
+
+impl Drop for TemporaryFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Read for TemporaryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+/// Object written into a temporary file, for the backends that need a whole file to store it
+struct SpooledObject<'a, B: StorageBackend + ?Sized> {
+    backend: &'a B,
+    key: String,
+    file: TemporaryFile,
+}
+
+impl<B: StorageBackend + ?Sized> Write for SpooledObject<'_, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.file.flush()
+    }
+}
+
+impl<B: StorageBackend + ?Sized> ObjectWriter for SpooledObject<'_, B> {
+    fn finish(mut self: Box<Self>) -> io::Result<Option<String>> {
+        self.file.file.flush()?;
+        self.backend.put(&self.key, &self.file.path)
+    }
+}
+
+/// Write the object `key` into a temporary file, stored with `put` once finished
+pub(crate) fn spooled_object<'a, B: StorageBackend + ?Sized>(backend: &'a B, key: &str) -> io::Result<Box<dyn ObjectWriter + 'a>> {
+    Ok(Box::new(SpooledObject { backend, key: key.to_string(), file: TemporaryFile::create()? }))
+}
+
+/// Backend of a folder of this computer, the destination `file://<path>`
+pub(crate) struct LocalBackend {
+    root: PathBuf,
+}
+
+impl LocalBackend {
+    pub(crate) fn new(root: &Path) -> Self {
+        LocalBackend { root: root.to_path_buf() }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key.trim_end_matches('/'))
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn put(&self, key: &str, source: &Path) -> io::Result<Option<String>> {
+        // Copied under a temporary name, so that an interrupted upload does not leave a partial object
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = temporary_path(&path, ".upload");
+        fs::copy(source, &tmp_path)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(None)
+    }
+
+    fn get(&self, key: &str, destination: &Path) -> io::Result<()> {
+        fs::copy(self.path(key), destination).map(|_| ())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.path(prefix)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            result => result?,
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if entry.file_type()?.is_dir() {
+                names.push(format!("{}/", name));
+            } else {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        }
+        // Folders only exist through their objects, as in a bucket
+        for parent in path.ancestors().skip(1).take_while(|parent| *parent != self.root) {
+            if fs::remove_dir(parent).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>> {
+        match fs::metadata(self.path(key)) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(ObjectInfo { size: metadata.len(), etag: None })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn create(&self, key: &str, _size: Option<u64>) -> io::Result<Box<dyn ObjectWriter + '_>> {
+        let path = self.path(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_path = temporary_path(&path, ".upload");
+        let file = fs::File::create(&tmp_path)?;
+        Ok(Box::new(LocalObject { path, tmp_path, file: Some(file) }))
+    }
+
+    fn open(&self, key: &str) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(fs::File::open(self.path(key))?))
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        backup::available_space(&self.root)
+    }
+}
+
+/// Object of a local folder being written, under a temporary name until it is finished
+struct LocalObject {
+    path: PathBuf,
+    tmp_path: PathBuf,
+    // Taken once the object is finished
+    file: Option<fs::File>,
+}
+
+impl Write for LocalObject {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("not finished").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("not finished").flush()
+    }
+}
+
+impl ObjectWriter for LocalObject {
+    fn finish(mut self: Box<Self>) -> io::Result<Option<String>> {
+        let mut file = self.file.take().expect("not finished");
+        file.flush()?;
+        drop(file);
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(None)
+    }
+}
+
+impl Drop for LocalObject {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Name a file is written under before being moved in place: its name followed by `suffix`, so
+/// that files differing only by their extension do not share it
+pub(crate) fn temporary_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_os_string();
+    tmp_path.push(suffix);
+    PathBuf::from(tmp_path)
+}
+
+/// Backend of a remote destination, given as a URL such as `s3://bucket/prefix`, `sftp://user@host/path`
+/// or `webdavs://host/path`.
+/// A destination without a scheme is a plain folder, written directly: `None` is returned.
+pub fn open_backend(config: &Config) -> Result<Option<Box<dyn StorageBackend>>, BackupError> {
+    let Some((scheme, location)) = config.destination_path.trim().split_once("://") else {
+        return Ok(None);
+    };
+    match scheme {
+        "file" => Ok(Some(Box::new(LocalBackend::new(Path::new(location))))),
+        "s3" => {
+            let s3 = config.s3.as_ref().ok_or_else(|| BackupError::InvalidDestination("the [s3] section is missing".to_string()))?;
+            Ok(Some(Box::new(S3Backend::new(s3, location)?)))
+        }
+        "sftp" => Ok(Some(Box::new(SftpBackend::new(&config.sftp.clone().unwrap_or_default(), location)?))),
+        "webdav" | "webdavs" => {
+            let webdav = config.webdav.clone().unwrap_or_default();
+            Ok(Some(Box::new(WebDavBackend::new(&webdav, scheme == "webdavs", location)?)))
+        }
+        _ => Err(BackupError::InvalidDestination(format!("unknown scheme \"{}\"", scheme))),
+    }
+}
+
+/// Folder the series are written in: the destination itself, the folder of a removable drive
+/// where it is mounted, or for a remote destination its local working copy, which only keeps the
+/// metadata of the snapshots, their files being written into the destination
+pub(crate) fn local_path(config: &Config) -> Result<PathBuf, BackupError> {
+    let destination = config.destination_path.trim();
+    if let Some((drive, folder)) = volumes::parse_drive(destination) {
+        // Never fall back to a folder of the system disk when the drive is unplugged
+        let mount_point = volumes::drive_mount_point(&drive)?
+            .ok_or_else(|| BackupError::DriveNotConnected(drive.to_string()))?;
+        return Ok(mount_point.join(folder));
+    }
+    if !destination.contains("://") {
+        return Ok(PathBuf::from(&config.destination_path));
+    }
+    // One folder per destination, named after its URL
+    let name: String = destination
+        .replacen("://", "/", 1)
+        .chars()
+        .map(|c| if ":@?*\"<>|".contains(c) { '_' } else { c })
+        .collect();
+    Ok(cache_root(config).join(name.trim_end_matches('/')))
+}
+
+/// Folder of the working copies: `cache_path`, or the cache folder of the user
+fn cache_root(config: &Config) -> PathBuf {
+    if !config.cache_path.trim().is_empty() {
+        return PathBuf::from(config.cache_path.trim());
+    }
+    let home = || env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+    let cache = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from).unwrap_or_else(env::temp_dir)
+    } else if cfg!(target_os = "macos") {
+        home().join("Library").join("Caches")
+    } else {
+        env::var_os("XDG_CACHE_HOME").map(PathBuf::from).unwrap_or_else(|| home().join(".cache"))
+    };
+    cache.join("backmeup")
+}
+
+/// Key of a file of the local series, relative to the working copy
+fn series_key(series_path: &Path, relative_path: &Path) -> String {
+    let series = series_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    let relative = repository::index_path(relative_path);
+    if relative.is_empty() {
+        series
+    } else {
+        format!("{}/{}", series, relative)
+    }
+}
+
+fn marker_key(series_path: &Path, snapshot_id: &str) -> String {
+    series_key(series_path, &Path::new(snapshot_id).join(SNAPSHOT_METADATA_DIR).join(UPLOADED_MARKER))
+}
+
+/// Snapshot written by a backup into a remote destination. Its files are sent as they are
+/// written, while its metadata is written in the staged snapshot of the working copy, and sent by
+/// `push_series` once the snapshot is committed.
+pub(crate) struct RemoteSnapshot<'a> {
+    backend: &'a dyn StorageBackend,
+    series_path: PathBuf,
+    snapshot_id: String,
+    // Id of the previous snapshot and the ETags of its objects, whose unchanged files are copied
+    previous: Option<(String, BTreeMap<String, String>)>,
+    // Cleared once the backend refuses to copy objects
+    can_copy: AtomicBool,
+    // ETags of the objects written, by path in the snapshot, saved in its marker
+    etags: Mutex<BTreeMap<String, String>>,
+    // Files of the chunk store, listed on first use
+    chunks: Mutex<Option<HashSet<String>>>,
+}
+
+impl<'a> RemoteSnapshot<'a> {
+    pub(crate) fn new(backend: &'a dyn StorageBackend, series_path: &Path, snapshot_id: &str, previous_snapshot: Option<&Path>) -> Self {
+        let previous = previous_snapshot.and_then(|path| Some((path.file_name()?.to_string_lossy().to_string(), read_etags(path))));
+        RemoteSnapshot {
+            backend,
+            series_path: series_path.to_path_buf(),
+            snapshot_id: snapshot_id.to_string(),
+            previous,
+            can_copy: AtomicBool::new(true),
+            etags: Mutex::new(BTreeMap::new()),
+            chunks: Mutex::new(None),
+        }
+    }
+
+    fn key(&self, relative_path: &Path) -> String {
+        series_key(&self.series_path, &Path::new(&self.snapshot_id).join(relative_path))
+    }
+
+    /// Write a file of the snapshot, given relative to its folder
+    pub(crate) fn create(&self, relative_path: &Path, size: Option<u64>) -> io::Result<RemoteFile<'_>> {
+        let writer = self.backend.create(&self.key(relative_path), size)?;
+        Ok(RemoteFile { snapshot: self, name: repository::index_path(relative_path), writer, written: 0 })
+    }
+
+    /// Size of a file of the snapshot written by an interrupted backup, `None` if there is none.
+    /// Its ETag is kept as if this backup had written it.
+    pub(crate) fn resumed_size(&self, relative_path: &Path) -> Option<u64> {
+        let info = self.backend.stat(&self.key(relative_path)).ok()??;
+        if let Some(etag) = info.etag {
+            self.etags.lock().unwrap().insert(repository::index_path(relative_path), etag);
+        }
+        Some(info.size)
+    }
+
+    /// Copy the file of the previous snapshot, unchanged since then, on the backend itself.
+    /// Returns false if the file has to be sent again: the backend cannot copy objects, or the
+    /// object was modified since it was written, its ETag having changed.
+    pub(crate) fn copy_previous(&self, relative_path: &Path) -> bool {
+        let name = repository::index_path(relative_path);
+        let Some((previous_id, previous_etags)) = &self.previous else {
+            return false;
+        };
+        let Some(previous_etag) = previous_etags.get(&name).filter(|_| self.can_copy.load(Ordering::Relaxed)) else {
+            return false;
+        };
+        let previous_key = series_key(&self.series_path, &Path::new(previous_id).join(relative_path));
+        if !self.backend.stat(&previous_key).is_ok_and(|info| info.is_some_and(|info| info.etag.as_ref() == Some(previous_etag))) {
+            return false;
+        }
+        match self.backend.copy(&previous_key, &self.key(relative_path)) {
+            Ok(etag) => {
+                self.etags.lock().unwrap().extend(etag.map(|etag| (name, etag)));
+                true
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::Unsupported {
+                    self.can_copy.store(false, Ordering::Relaxed);
+                }
+                false
+            }
+        }
+    }
+
+    /// Check whether the chunk store of the series holds a file, given relative to the store
+    pub(crate) fn has_chunk(&self, relative_path: &Path) -> io::Result<bool> {
+        let mut chunks = self.chunks.lock().unwrap();
+        if chunks.is_none() {
+            let prefix = format!("{}/", series_key(&self.series_path, Path::new(CHUNKS_DIR)));
+            *chunks = Some(list_tree(self.backend, &prefix)?.into_iter().map(|key| key[prefix.len()..].to_string()).collect());
+        }
+        Ok(chunks.as_ref().is_some_and(|chunks| chunks.contains(&repository::index_path(relative_path))))
+    }
+
+    /// Add a file to the chunk store of the series, given relative to the store
+    pub(crate) fn put_chunk(&self, relative_path: &Path, data: &[u8]) -> io::Result<()> {
+        let key = series_key(&self.series_path, &Path::new(CHUNKS_DIR).join(relative_path));
+        let mut writer = self.backend.create(&key, Some(data.len() as u64))?;
+        writer.write_all(data)?;
+        writer.finish()?;
+        if let Some(chunks) = self.chunks.lock().unwrap().as_mut() {
+            chunks.insert(repository::index_path(relative_path));
+        }
+        Ok(())
+    }
+
+    /// Save the ETags of the objects written in the metadata of the staged snapshot, which is only
+    /// marked as uploaded once its metadata is sent too
+    pub(crate) fn save_etags(&self, staged_path: &Path) -> io::Result<()> {
+        let contents = serde_json::to_vec(&*self.etags.lock().unwrap())?;
+        fs::write(staged_path.join(SNAPSHOT_METADATA_DIR).join(UPLOADED_MARKER), contents)
+    }
+}
+
+/// File of a remote snapshot being written
+pub(crate) struct RemoteFile<'a> {
+    snapshot: &'a RemoteSnapshot<'a>,
+    // Path in the snapshot, with `/` as separator
+    name: String,
+    writer: Box<dyn ObjectWriter + 'a>,
+    written: u64,
+}
+
+impl RemoteFile<'_> {
+    /// Complete the file, returning its size
+    pub(crate) fn finish(self) -> io::Result<u64> {
+        if let Some(etag) = self.writer.finish()? {
+            self.snapshot.etags.lock().unwrap().insert(self.name, etag);
+        }
+        Ok(self.written)
+    }
+}
+
+impl Write for RemoteFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// File of a snapshot being written by a backup: in the staged snapshot, or for a remote
+/// destination straight into the destination
+pub(crate) enum SnapshotFile<'a> {
+    Local(fs::File),
+    Remote(RemoteFile<'a>),
+}
+
+impl<'a> SnapshotFile<'a> {
+    /// Create a file of the snapshot staged at `snapshot_path`, given relative to it. `size` is
+    /// the size of the file once stored, when known in advance.
+    pub(crate) fn create(snapshot_path: &Path, relative_path: &Path, remote: Option<&'a RemoteSnapshot<'a>>, size: Option<u64>) -> io::Result<Self> {
+        match remote {
+            Some(remote) => Ok(SnapshotFile::Remote(remote.create(relative_path, size)?)),
+            None => Ok(SnapshotFile::Local(fs::File::create(snapshot_path.join(relative_path))?)),
+        }
+    }
+
+    /// Complete the file, returning the space it takes
+    pub(crate) fn finish(self) -> io::Result<u64> {
+        match self {
+            SnapshotFile::Local(file) => Ok(file.metadata()?.len()),
+            SnapshotFile::Remote(file) => file.finish(),
+        }
+    }
+}
+
+impl Write for SnapshotFile<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SnapshotFile::Local(file) => file.write(buf),
+            SnapshotFile::Remote(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SnapshotFile::Local(file) => file.flush(),
+            SnapshotFile::Remote(file) => file.flush(),
+        }
+    }
+}
+
+/// Where the files of the snapshots of a series are read from: the series folder, or the remote
+/// destination, whose working copy only holds the metadata of the snapshots
+#[derive(Clone, Copy)]
+pub(crate) enum SeriesFiles<'a> {
+    Local(&'a Path),
+    Remote(&'a dyn StorageBackend, &'a Path),
+}
+
+impl<'a> SeriesFiles<'a> {
+    pub(crate) fn new(series_path: &'a Path, backend: Option<&'a dyn StorageBackend>) -> Self {
+        match backend {
+            Some(backend) => SeriesFiles::Remote(backend, series_path),
+            None => SeriesFiles::Local(series_path),
+        }
+    }
+
+    /// Folder of the series, or its working copy
+    pub(crate) fn series_path(&self) -> &'a Path {
+        match *self {
+            SeriesFiles::Local(series_path) | SeriesFiles::Remote(_, series_path) => series_path,
+        }
+    }
+
+    pub(crate) fn is_remote(&self) -> bool {
+        matches!(self, SeriesFiles::Remote(..))
+    }
+
+    /// Open a file of the series, given relative to its folder
+    pub(crate) fn open(&self, relative_path: &Path) -> io::Result<Box<dyn Read + 'a>> {
+        match *self {
+            SeriesFiles::Local(series_path) => Ok(Box::new(fs::File::open(series_path.join(relative_path))?)),
+            SeriesFiles::Remote(backend, series_path) => backend.open(&series_key(series_path, relative_path)),
+        }
+    }
+
+    /// Check whether a file of the series exists
+    pub(crate) fn exists(&self, relative_path: &Path) -> bool {
+        match *self {
+            SeriesFiles::Local(series_path) => series_path.join(relative_path).is_file(),
+            SeriesFiles::Remote(backend, series_path) => backend.stat(&series_key(series_path, relative_path)).is_ok_and(|info| info.is_some()),
+        }
+    }
+
+    /// Files of a snapshot, relative to its folder with `/` as separator, leaving out its metadata
+    pub fn snapshot_files(&self, snapshot_id: &str) -> io::Result<Vec<String>> {
+        match *self {
+            SeriesFiles::Local(series_path) => {
+                let snapshot_path = series_path.join(snapshot_id);
+                Ok(WalkDir::new(&snapshot_path)
+                    .min_depth(1)
+                    .into_iter()
+                    .filter_entry(|e| e.file_name() != SNAPSHOT_METADATA_DIR)
+                    .filter_map(|e| e.ok())
+                    .filter(|e| e.file_type().is_file())
+                    .filter_map(|e| e.path().strip_prefix(&snapshot_path).ok().map(repository::index_path))
+                    .collect())
+            }
+            SeriesFiles::Remote(backend, series_path) => {
+                let prefix = format!("{}/", series_key(series_path, Path::new(snapshot_id)));
+                let metadata_prefix = format!("{}{}/", prefix, SNAPSHOT_METADATA_DIR);
+                Ok(list_tree(backend, &prefix)?
+                    .into_iter()
+                    .filter(|key| !key.starts_with(&metadata_prefix))
+                    .map(|key| key[prefix.len()..].to_string())
+                    .collect())
+            }
+        }
+    }
+}
+
+/// Send what the remote series is missing after a backup or a prune: the key, the metadata of the
+/// snapshots not uploaded yet, then `latest` and the log. The `pruned` snapshots are deleted, and
+/// so are the chunks no snapshot uses anymore.
+/// The files of a snapshot are written into the destination by the backup itself, and the snapshot
+/// only counts as uploaded once its metadata is too, its marker being written last. The working
+/// copy then only keeps its metadata: the snapshots and chunks older versions kept there are
+/// uploaded, then removed.
+pub fn push_series(backend: &dyn StorageBackend, series_path: &Path, pruned: &[String], cipher: Option<&Cipher>) -> Result<(), BackupError> {
+    let key_path = series_path.join(KEY_FILE);
+    let key = series_key(series_path, Path::new(KEY_FILE));
+    if key_path.is_file() && backend.stat(&key)?.is_none() {
+        backend.put(&key, &key_path)?;
+    }
+
+    // Chunks are never modified, so the ones with the same name are already uploaded
+    let chunks_path = series_path.join(CHUNKS_DIR);
+    let chunks_prefix = format!("{}/", series_key(series_path, Path::new(CHUNKS_DIR)));
+    let local_chunks = chunk_files(series_path);
+    if !local_chunks.is_empty() {
+        let remote_chunks: HashSet<String> = list_tree(backend, &chunks_prefix)?.into_iter().collect();
+        for relative_path in &local_chunks {
+            let key = series_key(series_path, &Path::new(CHUNKS_DIR).join(relative_path));
+            if !remote_chunks.contains(&key) {
+                backend.put(&key, &chunks_path.join(relative_path))?;
+            }
+        }
+    }
+    if chunks_path.exists() {
+        fs::remove_dir_all(&chunks_path)?;
+    }
+
+    let local_snapshots = backup::list_snapshots(series_path);
+    for snapshot_id in &local_snapshots {
+        let snapshot_path = series_path.join(snapshot_id);
+        let marker = marker_key(series_path, snapshot_id);
+        if backend.stat(&marker)?.is_none() {
+            println!("Uploading snapshot: {}", snapshot_id);
+            // The ETags of the files written by the backup are already in the working copy
+            let mut etags = read_etags(&snapshot_path);
+            etags.extend(upload_snapshot(backend, series_path, snapshot_id)?);
+            let contents = serde_json::to_vec(&etags).map_err(io::Error::other)?;
+            fs::write(snapshot_path.join(SNAPSHOT_METADATA_DIR).join(UPLOADED_MARKER), &contents)?;
+            put_contents(backend, series_path, &marker, &contents)?;
+        }
+        remove_files(&snapshot_path)?;
+    }
+
+    if let Some(latest) = backup::latest_snapshot(series_path) {
+        put_contents(backend, series_path, &series_key(series_path, Path::new(LATEST_SNAPSHOT)), latest.as_bytes())?;
+    }
+    let log_path = series_path.join(LOG_FILE);
+    if log_path.is_file() {
+        backend.put(&series_key(series_path, Path::new(LOG_FILE)), &log_path)?;
+    }
+
+    for snapshot_id in pruned {
+        println!("Deleting remote snapshot: {}", snapshot_id);
+        delete_snapshot(backend, series_path, snapshot_id)?;
+    }
+
+    // The chunks used by the remote snapshots missing from the working copy are unknown, such as
+    // the ones of a backup in progress on another computer: the chunks are only collected when
+    // the working copy has every snapshot
+    if !pruned.is_empty() {
+        let remote_snapshots = remote_snapshots(backend, series_path)?;
+        if remote_snapshots.iter().all(|snapshot_id| local_snapshots.contains(snapshot_id)) {
+            let used_chunks = repository::used_chunks(series_path, cipher)?;
+            let compressed_suffix = format!(".{}", compression::COMPRESSED_EXTENSION);
+            for key in list_tree(backend, &chunks_prefix)? {
+                let name = key.rsplit('/').next().unwrap_or_default();
+                if !used_chunks.contains(name.strip_suffix(&compressed_suffix).unwrap_or(name)) {
+                    backend.delete(&key)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Upload the files of a snapshot of the working copy, and return the ETags of their objects by
+/// path in the snapshot. Files already on the backend with the same size were uploaded by an
+/// interrupted push and are skipped, except the metadata, which is small.
+fn upload_snapshot(backend: &dyn StorageBackend, series_path: &Path, snapshot_id: &str) -> io::Result<BTreeMap<String, String>> {
+    let snapshot_path = series_path.join(snapshot_id);
+    let resumed = !backend.list(&format!("{}/", series_key(series_path, Path::new(snapshot_id))))?.is_empty();
+    let mut etags = BTreeMap::new();
+
+    for entry in WalkDir::new(&snapshot_path) {
+        let entry = entry?;
+        // Folders only exist through their files, and symlinks are saved in the manifest
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative_path = entry.path().strip_prefix(&snapshot_path).map_err(io::Error::other)?;
+        if relative_path == Path::new(SNAPSHOT_METADATA_DIR).join(UPLOADED_MARKER) {
+            continue;
+        }
+        let key = series_key(series_path, &Path::new(snapshot_id).join(relative_path));
+        let name = repository::index_path(relative_path);
+
+        if resumed && !relative_path.starts_with(SNAPSHOT_METADATA_DIR) {
+            if let Some(info) = backend.stat(&key)?.filter(|info| info.size == entry.metadata().map(|m| m.len()).unwrap_or(0)) {
+                etags.extend(info.etag.map(|etag| (name, etag)));
+                continue;
+            }
+        }
+
+        if let Some(etag) = backend.put(&key, entry.path())? {
+            etags.insert(name, etag);
+        }
+    }
+    Ok(etags)
+}
+
+/// Remove the files of an uploaded snapshot from the working copy, keeping its metadata but the
+/// archive of a "tar-zst" snapshot, which is read from the destination like the files
+fn remove_files(snapshot_path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(snapshot_path)? {
+        let entry = entry?;
+        if entry.file_name() == SNAPSHOT_METADATA_DIR {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    match fs::remove_file(snapshot_path.join(SNAPSHOT_METADATA_DIR).join(ARCHIVE_FILE)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Delete a snapshot from the remote destination, its marker first, so that a snapshot partly
+/// deleted does not count as uploaded anymore
+pub(crate) fn delete_snapshot(backend: &dyn StorageBackend, series_path: &Path, snapshot_id: &str) -> io::Result<()> {
+    backend.delete(&marker_key(series_path, snapshot_id))?;
+    for key in list_tree(backend, &format!("{}/", series_key(series_path, Path::new(snapshot_id))))? {
+        backend.delete(&key)?;
+    }
+    Ok(())
+}
+
+/// ETags of the objects of an uploaded snapshot, saved in its marker. Snapshots uploaded by older
+/// versions, or to a backend without ETags, have none.
+fn read_etags(snapshot_path: &Path) -> BTreeMap<String, String> {
+    fs::read(snapshot_path.join(SNAPSHOT_METADATA_DIR).join(UPLOADED_MARKER))
+        .ok()
+        .and_then(|contents| serde_json::from_slice(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Chunk files of the working copy, relative to its chunk store
+fn chunk_files(series_path: &Path) -> Vec<PathBuf> {
+    let chunks_path = series_path.join(CHUNKS_DIR);
+    WalkDir::new(&chunks_path)
+        .min_depth(2)
+        .max_depth(2)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        // Skip the leftovers of interrupted writes
+        .filter(|entry| entry.file_type().is_file() && !entry.file_name().to_string_lossy().ends_with(".tmp"))
+        .filter_map(|entry| entry.path().strip_prefix(&chunks_path).ok().map(Path::to_path_buf))
+        .collect()
+}
+
+/// Keys of all the objects inside the folder `prefix`, at any depth
+fn list_tree(backend: &dyn StorageBackend, prefix: &str) -> io::Result<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut folders = vec![prefix.to_string()];
+    while let Some(folder) = folders.pop() {
+        for name in backend.list(&folder)? {
+            if name.ends_with('/') {
+                folders.push(format!("{}{}", folder, name));
+            } else {
+                keys.push(format!("{}{}", folder, name));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Ids of the snapshots of the remote series, complete or not
+fn remote_snapshots(backend: &dyn StorageBackend, series_path: &Path) -> io::Result<Vec<String>> {
+    Ok(backend.list(&format!("{}/", series_key(series_path, Path::new(""))))?
+        .into_iter()
+        .filter_map(|name| name.strip_suffix('/').map(str::to_string))
+        .filter(|name| backup::snapshot_time(name).is_some())
+        .collect())
+}
+
+/// Check whether a remote snapshot is complete: its marker is written last
+fn is_uploaded(backend: &dyn StorageBackend, series_path: &Path, snapshot_id: &str) -> io::Result<bool> {
+    Ok(backend.stat(&marker_key(series_path, snapshot_id))?.is_some())
+}
+
+/// Store a small object, written first in a scratch file of the working copy
+fn put_contents(backend: &dyn StorageBackend, series_path: &Path, key: &str, contents: &[u8]) -> io::Result<()> {
+    let scratch_path = series_path.join("object.upload");
+    fs::write(&scratch_path, contents)?;
+    let result = backend.put(key, &scratch_path);
+    let _ = fs::remove_file(&scratch_path);
+    result.map(|_| ())
+}
+
+/// Read a small object, `None` if it does not exist
+fn get_contents(backend: &dyn StorageBackend, series_path: &Path, key: &str) -> io::Result<Option<Vec<u8>>> {
+    if backend.stat(key)?.is_none() {
+        return Ok(None);
+    }
+    fs::create_dir_all(series_path)?;
+    let scratch_path = series_path.join("object.download");
+    let result = backend.get(key, &scratch_path).and_then(|_| fs::read(&scratch_path));
+    let _ = fs::remove_file(&scratch_path);
+    result.map(Some)
+}
+
+/// Download the key of the remote series into the working copy, if it is missing there.
+/// Without it a new key would be created, and the remote snapshots could not be read anymore.
+fn fetch_key(backend: &dyn StorageBackend, series_path: &Path) -> io::Result<()> {
+    let key_path = series_path.join(KEY_FILE);
+    if key_path.exists() {
+        return Ok(());
+    }
+    if let Some(contents) = get_contents(backend, series_path, &series_key(series_path, Path::new(KEY_FILE)))? {
+        fs::write(key_path, contents)?;
+    }
+    Ok(())
+}
+
+/// Id of the snapshot `latest` points to in the remote series
+fn remote_latest(backend: &dyn StorageBackend, series_path: &Path) -> io::Result<Option<String>> {
+    let contents = get_contents(backend, series_path, &series_key(series_path, Path::new(LATEST_SNAPSHOT)))?;
+    Ok(contents.map(|contents| String::from_utf8_lossy(&contents).trim().to_string()))
+}
+
+/// Bring the working copy of a remote series up to date before a backup or a prune: its key, and
+/// the metadata of the remote snapshots it is missing, such as the ones made from another
+/// computer. The latest remote snapshot becomes the latest of the working copy if it is newer.
+pub fn sync_series(backend: &dyn StorageBackend, series_path: &Path) -> io::Result<()> {
+    fetch_key(backend, series_path)?;
+    for snapshot_id in remote_snapshots(backend, series_path)? {
+        if !series_path.join(&snapshot_id).is_dir() && is_uploaded(backend, series_path, &snapshot_id)? {
+            download_metadata(backend, series_path, &snapshot_id)?;
+        }
+    }
+    if let Some(latest) = remote_latest(backend, series_path)? {
+        update_latest(series_path, &latest)?;
+    }
+    Ok(())
+}
+
+/// Make a snapshot of a remote series available in the working copy before it is restored or
+/// verified: its metadata is downloaded if it is not there, its files being read from the
+/// destination.
+/// Without an id the latest remote snapshot is fetched, and becomes the latest of the working copy
+/// if it is newer.
+pub(crate) fn fetch_snapshot(backend: &dyn StorageBackend, series_path: &Path, snapshot_id: Option<&str>) -> io::Result<()> {
+    fetch_key(backend, series_path)?;
+
+    let snapshot_id = match snapshot_id {
+        Some(snapshot_id) => snapshot_id.to_string(),
+        None => match remote_latest(backend, series_path)? {
+            Some(latest) => latest,
+            None => return Ok(()),
+        },
+    };
+    if !series_path.join(&snapshot_id).is_dir()
+        && backup::snapshot_time(&snapshot_id).is_some()
+        && is_uploaded(backend, series_path, &snapshot_id)?
+    {
+        download_metadata(backend, series_path, &snapshot_id)?;
+    }
+    update_latest(series_path, &snapshot_id)
+}
+
+/// Point `latest` to a snapshot of the working copy if it is newer than the current one: the
+/// working copy may be missing the latest backups, when made from another computer
+fn update_latest(series_path: &Path, snapshot_id: &str) -> io::Result<()> {
+    let newer = match backup::latest_snapshot(series_path) {
+        Some(latest) => backup::snapshot_time(&latest) < backup::snapshot_time(snapshot_id),
+        None => true,
+    };
+    if series_path.join(snapshot_id).is_dir() && newer {
+        backup::update_latest_snapshot(series_path, snapshot_id)?;
+    }
+    Ok(())
+}
+
+/// Download the metadata of a snapshot next to the snapshots of the working copy, and move it
+/// among them once complete, so that an interrupted download does not leave a partial snapshot.
+/// The archive of a "tar-zst" snapshot stays in the destination with the files.
+fn download_metadata(backend: &dyn StorageBackend, series_path: &Path, snapshot_id: &str) -> io::Result<()> {
+    println!("Downloading the metadata of snapshot: {}", snapshot_id);
+    let download_path = series_path.join(format!(".{}.download", snapshot_id));
+    if download_path.exists() {
+        fs::remove_dir_all(&download_path)?;
+    }
+
+    let metadata_path = download_path.join(SNAPSHOT_METADATA_DIR);
+    fs::create_dir_all(&metadata_path)?;
+    let prefix = format!("{}/", series_key(series_path, &Path::new(snapshot_id).join(SNAPSHOT_METADATA_DIR)));
+    for key in list_tree(backend, &prefix)? {
+        let relative_path = &key[prefix.len()..];
+        if relative_path == ARCHIVE_FILE {
+            continue;
+        }
+        let path = metadata_path.join(relative_path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        backend.get(&key, &path)?;
+    }
+
+    fs::rename(&download_path, series_path.join(snapshot_id))
+}
+
+/// Ids of the snapshots of a series, from the oldest to the newest, including the ones of a remote
+/// destination not in the working copy
+pub fn list_snapshots(config: &Config) -> Result<Vec<String>, BackupError> {
+    let series_path = backup::series_path(config)?;
+    let mut snapshots = backup::list_snapshots(series_path.as_path());
+    if let Some(backend) = open_backend(config)? {
+        for snapshot_id in remote_snapshots(backend.as_ref(), series_path.as_path())? {
+            if !snapshots.contains(&snapshot_id) && is_uploaded(backend.as_ref(), series_path.as_path(), &snapshot_id)? {
+                snapshots.push(snapshot_id);
+            }
+        }
+    }
+    Ok(backup::sort_snapshots(snapshots))
+}
+
+/// Checks shared by the tests of the backends. The remote ones run against a server given by
+/// environment variables, and are ignored unless asked for with `cargo test -- --ignored`.
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::backup::tests::{test_config, test_folder};
+    use crate::compression::CompressionConfig;
+    use crate::encryption::EncryptionConfig;
+    use crate::manifest;
+    use crate::restore::{self, ConflictPolicy, RestoreOptions};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Name unique to a test run, so that runs sharing a server do not meet
+    pub(crate) fn unique_name(name: &str) -> String {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        format!("backmeup-test-{}-{}-{}", name, std::process::id(), nanos)
+    }
+
+    pub(crate) fn temp_folder(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(unique_name(name));
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Content that is not the same at any offset, so that a misplaced byte is seen
+    pub(crate) fn test_bytes(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i % 251) as u8 ^ (i / 251) as u8).collect()
+    }
+
+    fn sorted(mut names: Vec<String>) -> Vec<String> {
+        names.sort();
+        names
+    }
+
+    /// Put, get, list, stat, copy and delete objects, leaving nothing behind
+    pub(crate) fn check_operations(backend: &dyn StorageBackend, large_size: usize) {
+        let folder = temp_folder("operations");
+        let prefix = unique_name("operations");
+        let large = test_bytes(large_size);
+        fs::write(folder.join("small.txt"), "small file").unwrap();
+        fs::write(folder.join("large.bin"), &large).unwrap();
+
+        let small_key = format!("{}/small.txt", prefix);
+        let large_key = format!("{}/folder/large.bin", prefix);
+        backend.put(&small_key, &folder.join("small.txt")).unwrap();
+        backend.put(&large_key, &folder.join("large.bin")).unwrap();
+
+        assert_eq!(backend.stat(&small_key).unwrap().unwrap().size, 10);
+        assert_eq!(backend.stat(&large_key).unwrap().unwrap().size, large_size as u64);
+        assert!(backend.stat(&format!("{}/missing.txt", prefix)).unwrap().is_none());
+        assert_eq!(sorted(backend.list(&format!("{}/", prefix)).unwrap()), ["folder/", "small.txt"]);
+        assert_eq!(backend.list(&format!("{}/folder/", prefix)).unwrap(), ["large.bin"]);
+
+        backend.get(&large_key, &folder.join("large.copy")).unwrap();
+        assert!(fs::read(folder.join("large.copy")).unwrap() == large);
+        let missing = backend.get(&format!("{}/missing.txt", prefix), &folder.join("missing.txt")).unwrap_err();
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+        assert!(!folder.join("missing.txt").exists());
+
+        // Replacing an object
+        fs::write(folder.join("small.txt"), "replaced").unwrap();
+        backend.put(&small_key, &folder.join("small.txt")).unwrap();
+        backend.get(&small_key, &folder.join("small.copy")).unwrap();
+        assert_eq!(fs::read_to_string(folder.join("small.copy")).unwrap(), "replaced");
+
+        let copy_key = format!("{}/copies/small.txt", prefix);
+        match backend.copy(&small_key, &copy_key) {
+            Ok(_) => {
+                backend.get(&copy_key, &folder.join("copied.txt")).unwrap();
+                assert_eq!(fs::read_to_string(folder.join("copied.txt")).unwrap(), "replaced");
+            }
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported, "{}", e),
+        }
+
+        for key in list_tree(backend, &format!("{}/", prefix)).unwrap() {
+            backend.delete(&key).unwrap();
+        }
+        backend.delete(&small_key).unwrap();
+        assert!(backend.stat(&small_key).unwrap().is_none());
+        assert!(list_tree(backend, &format!("{}/", prefix)).unwrap().is_empty());
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    /// Backend losing the connection after a number of uploads, and recording the keys uploaded
+    struct Interrupted<'a> {
+        inner: &'a dyn StorageBackend,
+        uploads_left: AtomicUsize,
+        uploaded: Mutex<Vec<String>>,
+    }
+
+    impl StorageBackend for Interrupted<'_> {
+        fn put(&self, key: &str, source: &Path) -> io::Result<Option<String>> {
+            if self.uploads_left.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| left.checked_sub(1)).is_err() {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection lost"));
+            }
+            self.uploaded.lock().unwrap().push(key.to_string());
+            self.inner.put(key, source)
+        }
+
+        fn get(&self, key: &str, destination: &Path) -> io::Result<()> {
+            self.inner.get(key, destination)
+        }
+
+        fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+            self.inner.list(prefix)
+        }
+
+        fn delete(&self, key: &str) -> io::Result<()> {
+            self.inner.delete(key)
+        }
+
+        fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>> {
+            self.inner.stat(key)
+        }
+
+        fn copy(&self, from: &str, to: &str) -> io::Result<Option<String>> {
+            self.inner.copy(from, to)
+        }
+    }
+
+    /// Push a series whose upload is cut, then push it again: the files already uploaded are not
+    /// sent again, the snapshot is only marked as uploaded once complete, and the working copy then
+    /// only keeps its metadata
+    pub(crate) fn check_resumed_push(backend: &dyn StorageBackend) {
+        let folder = temp_folder("push");
+        let series = unique_name("series");
+        let series_path = folder.join(&series);
+        let snapshot_id = "2024-01-01_00-00-00";
+        let snapshot_path = series_path.join(snapshot_id);
+        fs::create_dir_all(snapshot_path.join(SNAPSHOT_METADATA_DIR)).unwrap();
+        fs::create_dir_all(snapshot_path.join("folder")).unwrap();
+        let files = [("a.bin", 1000), ("b.bin", 2000), ("folder/c.bin", 3000), ("folder/d.bin", 4000)];
+        for (name, size) in files {
+            fs::write(snapshot_path.join(name), test_bytes(size)).unwrap();
+        }
+        fs::write(snapshot_path.join(SNAPSHOT_METADATA_DIR).join("manifest.json"), "{}").unwrap();
+        backup::update_latest_snapshot(&series_path, snapshot_id).unwrap();
+        let marker = format!("{}/{}/{}/{}", series, snapshot_id, SNAPSHOT_METADATA_DIR, UPLOADED_MARKER);
+
+        let interrupted = Interrupted { inner: backend, uploads_left: AtomicUsize::new(2), uploaded: Mutex::new(Vec::new()) };
+        assert!(push_series(&interrupted, &series_path, &[], None).is_err());
+        assert!(backend.stat(&marker).unwrap().is_none());
+        let first_uploads = interrupted.uploaded.into_inner().unwrap();
+        assert_eq!(first_uploads.len(), 2);
+
+        let resumed = Interrupted { inner: backend, uploads_left: AtomicUsize::new(usize::MAX), uploaded: Mutex::new(Vec::new()) };
+        push_series(&resumed, &series_path, &[], None).unwrap();
+        assert!(backend.stat(&marker).unwrap().is_some());
+        let resumed_uploads = resumed.uploaded.into_inner().unwrap();
+        let sent_again: Vec<&String> = first_uploads.iter()
+            .filter(|key| !key.contains(SNAPSHOT_METADATA_DIR) && resumed_uploads.contains(key))
+            .collect();
+        assert!(sent_again.is_empty(), "uploaded twice: {:?}", sent_again);
+
+        for (name, size) in files {
+            backend.get(&format!("{}/{}/{}", series, snapshot_id, name), &folder.join("downloaded")).unwrap();
+            assert!(fs::read(folder.join("downloaded")).unwrap() == test_bytes(size), "{}", name);
+            assert!(!snapshot_path.join(name).exists(), "{}", name);
+        }
+        assert!(snapshot_path.join(SNAPSHOT_METADATA_DIR).join("manifest.json").is_file());
+
+        for key in list_tree(backend, &format!("{}/", series)).unwrap() {
+            backend.delete(&key).unwrap();
+        }
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn local_backend_operations() {
+        let root = temp_folder("local");
+        check_operations(&LocalBackend::new(&root), 3 * 1024 * 1024);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn local_upload_keeps_the_extension_in_its_temporary_name() {
+        let root = temp_folder("local");
+        let folder = temp_folder("local-files");
+        let backend = LocalBackend::new(&root);
+        fs::write(folder.join("first"), "first file").unwrap();
+        fs::write(folder.join("second"), "second file").unwrap();
+        backend.put("data.upload", &folder.join("first")).unwrap();
+        backend.put("data.txt", &folder.join("second")).unwrap();
+        assert_eq!(fs::read_to_string(root.join("data.upload")).unwrap(), "first file");
+        assert_eq!(fs::read_to_string(root.join("data.txt")).unwrap(), "second file");
+        fs::remove_dir_all(root).unwrap();
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    /// Back up a folder twice into a `file://` destination, then restore and verify the latest
+    /// snapshot: the working copy only holds the metadata, the files being read from the
+    /// destination
+    fn check_remote_round_trip(name: &str, storage_format: &str, compression: &str, encryption: Option<EncryptionConfig>) {
+        let files = [("a.txt", "first file ".repeat(100)), ("folder/b.txt", "second file".to_string()), ("empty.txt", String::new())];
+        let folder = test_folder(&format!("remote-{}", name), &files);
+        let config = Config {
+            destination_path: format!("file://{}", folder.join("destination").display()),
+            cache_path: folder.join("cache").display().to_string(),
+            storage_format: storage_format.to_string(),
+            compression: CompressionConfig { mode: compression.to_string(), level: 3 },
+            encryption,
+            ..test_config(&folder)
+        };
+        backup::run_job(&config, false).unwrap();
+        // Snapshot ids have a resolution of one second
+        std::thread::sleep(std::time::Duration::from_millis(1100));
+        fs::write(folder.join("source/folder/b.txt"), "second file, modified").unwrap();
+        backup::run_job(&config, false).unwrap();
+
+        let series_path = backup::series_path(&config).unwrap();
+        assert!(!series_path.join(CHUNKS_DIR).exists());
+        let snapshots = backup::list_snapshots(&series_path);
+        assert_eq!(snapshots.len(), 2);
+        for snapshot_id in &snapshots {
+            let names: Vec<_> = fs::read_dir(series_path.join(snapshot_id)).unwrap().map(|e| e.unwrap().file_name()).collect();
+            assert_eq!(names, [SNAPSHOT_METADATA_DIR], "{}", snapshot_id);
+            assert!(!series_path.join(snapshot_id).join(SNAPSHOT_METADATA_DIR).join(ARCHIVE_FILE).exists());
+        }
+
+        // A working copy lost in the meantime is rebuilt from the destination
+        fs::remove_dir_all(folder.join("cache")).unwrap();
+        let options = RestoreOptions {
+            snapshot_id: None,
+            paths: Vec::new(),
+            target_path: Some(folder.join("restored")),
+            conflict_policy: ConflictPolicy::Skip,
+        };
+        assert_eq!(restore::restore_snapshot(&config, &options).unwrap().len(), files.len());
+        for (path, contents) in [("a.txt", files[0].1.as_str()), ("folder/b.txt", "second file, modified"), ("empty.txt", "")] {
+            assert_eq!(fs::read_to_string(folder.join("restored").join(path)).unwrap(), contents, "{}", path);
+        }
+        let report = manifest::verify_snapshot(&config, None, true).unwrap();
+        assert_eq!((report.verified, report.issues.len()), (files.len(), 0), "{:?}", report.issues);
+        fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[test]
+    fn remote_snapshots_are_written_into_the_destination() {
+        let encryption = EncryptionConfig { passphrase: Some("secret".to_string()), key_file: None };
+        check_remote_round_trip("plain", "plain", "none", None);
+        check_remote_round_trip("repository", "repository", "none", None);
+        check_remote_round_trip("archive", "plain", "tar-zst", None);
+        check_remote_round_trip("encrypted", "plain", "zstd", Some(encryption));
+    }
+
+    #[test]
+    fn local_backend_resumed_push() {
+        let root = temp_folder("local");
+        check_resumed_push(&LocalBackend::new(&root));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,327 @@
+use std::fmt;
+#[cfg(target_os = "linux")]
+use std::fs;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use sysinfo::Disks;
+use crate::backup::BackupError;
+
+/// Patterns left out of full-disk backups, before the filters of the configuration: swap and
+/// hibernation files, caches, temporary files and trash, which can be large and are rebuilt or
+/// thrown away anyway. They are relative to the root of each volume, and a filter can include
+/// them again with `!pattern`. Hidden entries, such as `.cache` or `.Trash-1000`, are never
+/// backed up.
+pub(crate) const DEFAULT_EXCLUDES: &[&str] = &[
+    "/swapfile",
+    "/swap.img",
+    "/pagefile.sys",
+    "/swapfile.sys",
+    "/hiberfil.sys",
+    "/tmp/",
+    "/var/tmp/",
+    "/var/cache/",
+    "/lost+found/",
+    "**/Library/Caches/",
+    "**/AppData/Local/Temp/",
+    "/$RECYCLE.BIN/",
+    "/System Volume Information/",
+];
+
+/// A volume mounted on this computer, as listed for full-disk backups
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub mount_point: PathBuf,
+    // Name of the device or label, may be empty
+    pub name: String,
+    pub file_system: String,
+    pub total_space: u64,
+    pub available_space: u64,
+    pub removable: bool,
+}
+
+impl fmt::Display for Volume {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gigabytes = |bytes: u64| bytes as f64 / 1e9;
+        let name = if self.name.is_empty() { String::new() } else { format!("{}, ", self.name) };
+        write!(
+            f,
+            "{} ({}{}, {:.1} of {:.1} GB used{})",
+            self.mount_point.display(),
+            name,
+            self.file_system,
+            gigabytes(self.total_space.saturating_sub(self.available_space)),
+            gigabytes(self.total_space),
+            if self.removable { ", removable" } else { "" }
+        )
+    }
+}
+
+/// Volumes mounted on this computer, sorted by mount point.
+/// Pseudo filesystems such as `/proc` are not listed, and a volume mounted more than once is
+/// listed at its first mount point only.
+pub fn list_volumes() -> Vec<Volume> {
+    let disks = Disks::new_with_refreshed_list();
+    let mut volumes: Vec<Volume> = Vec::new();
+
+    for disk in disks.list() {
+        if volumes.iter().any(|volume| volume.mount_point == disk.mount_point()) {
+            continue;
+        }
+        volumes.push(Volume {
+            mount_point: disk.mount_point().to_path_buf(),
+            name: disk.name().to_string_lossy().to_string(),
+            file_system: disk.file_system().to_string_lossy().to_string(),
+            total_space: disk.total_space(),
+            available_space: disk.available_space(),
+            removable: disk.is_removable(),
+        });
+    }
+
+    volumes.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+    volumes
+}
+
+/// Mount point of the volume holding `path`: the longest one it starts with
+pub(crate) fn volume_of(path: &Path) -> Option<PathBuf> {
+    let path = path.canonicalize().ok()?;
+    list_volumes()
+        .into_iter()
+        .map(|volume| volume.mount_point)
+        .filter(|mount_point| path.starts_with(mount_point))
+        .max_by_key(|mount_point| mount_point.components().count())
+}
+
+/// Folder of a full-disk snapshot holding a volume, named after its mount point:
+/// `/` is saved in `root`, `/home` in `home`, `/mnt/data` in `mnt_data` and `C:\` in `C`
+pub(crate) fn subtree_name(mount_point: &Path) -> String {
+    let names: Vec<String> = mount_point
+        .components()
+        .filter_map(|component| match component {
+            Component::Prefix(prefix) => Some(prefix.as_os_str().to_string_lossy().trim_end_matches(':').to_string()),
+            Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    if names.is_empty() {
+        "root".to_string()
+    } else {
+        names.join("_")
+    }
+}
+
+/// Filesystem a destination can name instead of a path, so that the removable drive holding it is
+/// found wherever it is mounted: `UUID=<uuid>/<folder>` or `LABEL=<label>/<folder>`, as in /etc/fstab
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drive {
+    Uuid(String),
+    Label(String),
+}
+
+impl fmt::Display for Drive {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drive::Uuid(uuid) => write!(f, "UUID={}", uuid),
+            Drive::Label(label) => write!(f, "LABEL={}", label),
+        }
+    }
+}
+
+/// Drive named by a destination, with the folder on it, or `None` for a plain path
+pub fn parse_drive(destination: &str) -> Option<(Drive, &str)> {
+    let destination = destination.trim();
+    let (drive, folder) = destination.split_once('/').unwrap_or((destination, ""));
+    let drive = if let Some(uuid) = drive.strip_prefix("UUID=") {
+        Drive::Uuid(uuid.to_string())
+    } else if let Some(label) = drive.strip_prefix("LABEL=") {
+        Drive::Label(label.to_string())
+    } else {
+        return None;
+    };
+    Some((drive, folder.trim_matches('/')))
+}
+
+/// Mount point of a drive, or `None` when it is not connected or not mounted.
+/// The device is found through the links of /dev/disk, then its mount point in
+/// /proc/self/mountinfo.
+#[cfg(target_os = "linux")]
+pub fn drive_mount_point(drive: &Drive) -> Result<Option<PathBuf>, BackupError> {
+    let Some(device) = drive_device(drive) else {
+        return Ok(None);
+    };
+    let device_number = fs::metadata(&device)?.rdev();
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    Ok(find_mount_point(&mountinfo, &device_id(device_number), &device))
+}
+
+/// Mount point of a drive, or `None` when it is not connected.
+/// Only labels are supported, compared with the volume names the system gives.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn drive_mount_point(drive: &Drive) -> Result<Option<PathBuf>, BackupError> {
+    let Drive::Label(label) = drive else {
+        return Err(BackupError::InvalidDestination(format!(
+            "{} is not supported on this platform, name the drive by its label with LABEL=",
+            drive
+        )));
+    };
+    let disks = Disks::new_with_refreshed_list();
+    Ok(disks.list().iter().find(|disk| disk.name().to_string_lossy() == *label).map(|disk| disk.mount_point().to_path_buf()))
+}
+
+/// Device of a drive, found through the links of /dev/disk, or `None` if it is not connected
+#[cfg(target_os = "linux")]
+fn drive_device(drive: &Drive) -> Option<PathBuf> {
+    let (links_path, name) = match drive {
+        Drive::Uuid(uuid) => ("/dev/disk/by-uuid", uuid),
+        Drive::Label(label) => ("/dev/disk/by-label", label),
+    };
+    // UUIDs are written in lowercase or uppercase depending on the tool
+    let link = fs::read_dir(links_path).ok()?.filter_map(Result::ok).find(|entry| {
+        let link_name = decode_udev_name(&entry.file_name().to_string_lossy());
+        match drive {
+            Drive::Uuid(_) => link_name.eq_ignore_ascii_case(name),
+            Drive::Label(_) => link_name == *name,
+        }
+    })?;
+    link.path().canonicalize().ok()
+}
+
+/// `<major>:<minor>` of a device number, as in /proc/self/mountinfo
+#[cfg(target_os = "linux")]
+fn device_id(device_number: u64) -> String {
+    // Encoding of the device numbers of the GNU C library
+    let major = ((device_number >> 8) & 0xfff) | ((device_number >> 32) & !0xfff);
+    let minor = (device_number & 0xff) | ((device_number >> 12) & !0xff);
+    format!("{}:{}", major, minor)
+}
+
+/// Mount point of a device in the contents of /proc/self/mountinfo, given its `<major>:<minor>`.
+/// A folder of the filesystem mounted on its own, such as a bind mount, is left out.
+#[cfg(target_os = "linux")]
+fn find_mount_point(mountinfo: &str, device_id: &str, device: &Path) -> Option<PathBuf> {
+    for line in mountinfo.lines() {
+        // `<id> <parent> <major:minor> <root> <mount point> <options...> - <type> <source> <options>`
+        let Some((mount, source)) = line.split_once(" - ") else {
+            continue;
+        };
+        let fields: Vec<&str> = mount.split(' ').collect();
+        let source = source.split(' ').nth(1).unwrap_or_default();
+        let (Some(id), Some(&"/"), Some(mount_point)) = (fields.get(2), fields.get(3), fields.get(4)) else {
+            continue;
+        };
+        // Filesystems such as Btrfs show another device number than the one of their device
+        if *id == device_id || Path::new(source) == device {
+            return Some(PathBuf::from(decode_mountinfo_path(mount_point)));
+        }
+    }
+    None
+}
+
+/// Name of a link of /dev/disk, where udev writes the characters other than letters, digits and
+/// `#+-.:=@_` as `\xNN`, e.g. `My\x20Disk`
+#[cfg(target_os = "linux")]
+fn decode_udev_name(name: &str) -> String {
+    decode_escapes(name, "\\x", 16, 2)
+}
+
+/// Mount point of /proc/self/mountinfo, where spaces, tabs, newlines and backslashes are written
+/// as octal escapes, e.g. `/media/me/My\040Disk`
+#[cfg(target_os = "linux")]
+fn decode_mountinfo_path(path: &str) -> String {
+    decode_escapes(path, "\\", 8, 3)
+}
+
+/// Replace the bytes written as `<prefix><digits>`, leaving invalid escapes as they are
+#[cfg(target_os = "linux")]
+fn decode_escapes(text: &str, prefix: &str, radix: u32, digits: usize) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text;
+    while !rest.is_empty() {
+        let escaped = rest.strip_prefix(prefix)
+            .and_then(|escape| escape.get(..digits))
+            .and_then(|value| u8::from_str_radix(value, radix).ok());
+        match escaped {
+            Some(byte) => {
+                bytes.push(byte);
+                rest = &rest[prefix.len() + digits..];
+            }
+            None => {
+                let length = rest.chars().next().map(char::len_utf8).unwrap_or(1);
+                bytes.extend_from_slice(&rest.as_bytes()[..length]);
+                rest = &rest[length..];
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).to_string()
+}
+
+/// Write the data still cached by the system to the disks, so that a removable drive can be
+/// unplugged once a backup is over. Windows mounts removable drives without write caching.
+pub fn flush_writes() {
+    #[cfg(unix)]
+    unsafe {
+        libc::sync();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtrees_are_named_after_their_mount_point() {
+        assert_eq!(subtree_name(Path::new("/")), "root");
+        assert_eq!(subtree_name(Path::new("/home")), "home");
+        assert_eq!(subtree_name(Path::new("/mnt/data")), "mnt_data");
+        assert_eq!(subtree_name(Path::new("/media/me/My Disk/")), "media_me_My Disk");
+        #[cfg(windows)]
+        {
+            assert_eq!(subtree_name(Path::new("C:\\")), "C");
+            assert_eq!(subtree_name(Path::new("D:\\Data")), "D_Data");
+        }
+    }
+
+    #[test]
+    fn drives_are_named_by_uuid_or_label() {
+        assert_eq!(parse_drive("UUID=1234-ABCD/Backups"), Some((Drive::Uuid("1234-ABCD".to_string()), "Backups")));
+        assert_eq!(parse_drive(" LABEL=My Disk/Backups/Laptop/ "), Some((Drive::Label("My Disk".to_string()), "Backups/Laptop")));
+        assert_eq!(parse_drive("LABEL=Backups"), Some((Drive::Label("Backups".to_string()), "")));
+        assert_eq!(parse_drive("/media/me/Backups"), None);
+        assert_eq!(parse_drive("s3://bucket/UUID=1234"), None);
+        assert_eq!(Drive::Uuid("1234-ABCD".to_string()).to_string(), "UUID=1234-ABCD");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(decode_udev_name("My\\x20Disk"), "My Disk");
+        assert_eq!(decode_udev_name("Caf\\xc3\\xa9"), "Café");
+        assert_eq!(decode_udev_name("Bad\\xZZ\\x2"), "Bad\\xZZ\\x2");
+        assert_eq!(decode_mountinfo_path("/media/me/My\\040Disk"), "/media/me/My Disk");
+        assert_eq!(decode_mountinfo_path("/media/é\\134x\\9"), "/media/é\\x\\9");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn device_numbers_are_written_as_in_mountinfo() {
+        assert_eq!(device_id(0x801), "8:1");
+        assert_eq!(device_id(0x10303), "259:3");
+        assert_eq!(device_id(0x10082c), "8:300");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn mount_point_is_found_in_mountinfo() {
+        let mountinfo = "\
+22 1 8:2 / / rw,relatime shared:1 - ext4 /dev/sda2 rw
+30 22 8:17 /backups /mnt/bind rw,relatime shared:2 - ext4 /dev/sdb1 rw
+31 22 8:17 / /media/me/My\\040Disk rw,nosuid shared:3 master:1 - ext4 /dev/sdb1 rw
+40 22 0:45 / /mnt/btrfs rw,relatime shared:4 - btrfs /dev/sdc1 rw,space_cache
+";
+        assert_eq!(find_mount_point(mountinfo, "8:17", Path::new("/dev/sdb1")), Some(PathBuf::from("/media/me/My Disk")));
+        assert_eq!(find_mount_point(mountinfo, "8:33", Path::new("/dev/sdc1")), Some(PathBuf::from("/mnt/btrfs")));
+        assert_eq!(find_mount_point(mountinfo, "8:49", Path::new("/dev/sdd1")), None);
+    }
+}
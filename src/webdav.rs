@@ -0,0 +1,656 @@
+use std::{fs, io, mem, thread};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use crate::backup::BackupError;
+use crate::s3::uri_encode;
+use crate::storage::{self, ObjectInfo, ObjectWriter, StorageBackend};
+
+// Failed requests are retried this many times, waiting twice as long each time
+const RETRIES: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Properties asked for in the PROPFIND requests
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/><d:getetag/></d:prop></d:propfind>"#;
+// Free space of a collection, asked for on its own as computing it can be slow (RFC 4331)
+const QUOTA_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:quota-available-bytes/></d:prop></d:propfind>"#;
+
+// Size of the pieces a streamed object is handed to the request in, and how many can wait
+const PIECE_SIZE: usize = 256 * 1024;
+const PIECES_WAITING: usize = 4;
+
+/// Login of a `webdav://` or `webdavs://` destination, the `[webdav]` section of `config.toml`.
+/// The server and the folder are given by the destination: `webdavs://<host>:<port>/<path>`.
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct WebDavConfig {
+    // Basic authentication, e.g. a Nextcloud user with an app password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    // Sent instead of the user and the password when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bearer_token: Option<String>,
+}
+
+/// Folder of a WebDAV server, such as Nextcloud or ownCloud. Folders are collections, created with
+/// MKCOL before the files are uploaded into them with PUT.
+pub(crate) struct WebDavBackend {
+    agent: ureq::Agent,
+    // Scheme and host of the server
+    base_url: String,
+    // Percent-encoded path of the destination folder, without trailing `/`
+    base_path: String,
+    authorization: Option<String>,
+    // Collections known to exist, so that they are not created again for every upload
+    collections: Mutex<HashSet<String>>,
+}
+
+/// Body of a request
+enum Body<'a> {
+    Empty,
+    Xml(&'a str),
+    // Sent as it is read, with its length, as some servers refuse chunked uploads
+    File(&'a Path),
+}
+
+/// Entry of a PROPFIND answer
+struct Resource {
+    // Decoded path, without trailing `/`
+    path: String,
+    is_collection: bool,
+    size: u64,
+    etag: Option<String>,
+    // Free space, when asked for and known: servers give a negative value when it is not
+    available: Option<u64>,
+}
+
+impl WebDavBackend {
+    /// Backend of the destination `webdav://<location>`, or `webdavs://<location>` over TLS, where
+    /// the location is `<host>:<port>/<path>`
+    pub(crate) fn new(config: &WebDavConfig, secure: bool, location: &str) -> Result<Self, BackupError> {
+        let (host, path) = location.split_once('/').unwrap_or((location, ""));
+        if host.is_empty() {
+            return Err(BackupError::InvalidDestination("the WebDAV destination has no host".to_string()));
+        }
+
+        let authorization = match (&config.bearer_token, &config.username) {
+            (Some(token), _) if !token.trim().is_empty() => Some(format!("Bearer {}", token.trim())),
+            (_, Some(username)) if !username.is_empty() => {
+                let credentials = format!("{}:{}", username, config.password.as_deref().unwrap_or_default());
+                Some(format!("Basic {}", STANDARD.encode(credentials)))
+            }
+            _ => None,
+        };
+
+        Ok(WebDavBackend {
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(Duration::from_secs(30))
+                .timeout_read(Duration::from_secs(300))
+                .timeout_write(Duration::from_secs(300))
+                .build(),
+            base_url: format!("{}://{}", if secure { "https" } else { "http" }, host),
+            base_path: encode_path(path),
+            authorization,
+            collections: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Percent-encoded path of an object, or of a collection with a key ending with `/`
+    fn path(&self, key: &str) -> String {
+        let encoded = encode_path(key);
+        if key.ends_with('/') {
+            format!("{}{}/", self.base_path, encoded)
+        } else {
+            format!("{}{}", self.base_path, encoded)
+        }
+    }
+
+    /// Request to the server, logged in
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        let request = self.agent.request(method, &format!("{}{}", self.base_url, path));
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    /// Send a request, again when it fails on the network or the server is busy
+    fn send(&self, method: &str, path: &str, headers: &[(&str, &str)], body: Body) -> io::Result<ureq::Response> {
+        let mut attempt = 0;
+        loop {
+            let mut request = self.request(method, path);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            let result = match body {
+                Body::Empty => request.call(),
+                Body::Xml(xml) => request.set("Content-Type", "application/xml; charset=utf-8").send_string(xml),
+                Body::File(source) => {
+                    let file = fs::File::open(source)?;
+                    let size = file.metadata()?.len().to_string();
+                    request.set("Content-Length", &size).send(file)
+                }
+            };
+            match result {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < RETRIES && is_retryable(&e) => {
+                    thread::sleep(RETRY_DELAY * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                Err(e) => return Err(request_error(e)),
+            }
+        }
+    }
+
+    /// Properties of a resource, and of its members with `depth` 1, `None` if it does not exist
+    fn propfind(&self, path: &str, depth: &str) -> io::Result<Option<Vec<Resource>>> {
+        match self.send("PROPFIND", path, &[("Depth", depth)], Body::Xml(PROPFIND_BODY)) {
+            Ok(response) => Ok(Some(parse_multistatus(&response.into_string()?))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Create the collection at `path`, and the missing ones above it
+    fn create_collection(&self, path: &str) -> io::Result<()> {
+        if path.len() <= 1 || self.collections.lock().unwrap().contains(path) {
+            return Ok(());
+        }
+        let mkcol = || self.send("MKCOL", &format!("{}/", path), &[], Body::Empty);
+        match mkcol() {
+            // The parent is missing
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                self.create_collection(path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or_default())?;
+                match mkcol() {
+                    Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+                    _ => {}
+                }
+            }
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+            _ => {}
+        }
+        self.collections.lock().unwrap().insert(path.to_string());
+        Ok(())
+    }
+
+    /// Create the collections an object goes in
+    fn create_parents(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        match path.rsplit_once('/') {
+            Some((parent, _)) => self.create_collection(parent),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StorageBackend for WebDavBackend {
+    fn put(&self, key: &str, source: &Path) -> io::Result<Option<String>> {
+        self.create_parents(key)?;
+        let response = self.send("PUT", &self.path(key), &[], Body::File(source))?;
+        Ok(response_etag(&response))
+    }
+
+    fn get(&self, key: &str, destination: &Path) -> io::Result<()> {
+        // The object is written under a temporary name, and the download starts over if it is cut
+        let path = self.path(key);
+        let tmp_path = storage::temporary_path(destination, ".download");
+        let mut attempt = 0;
+        loop {
+            let result = self.send("GET", &path, &[], Body::Empty).and_then(|response| {
+                let mut file = fs::File::create(&tmp_path)?;
+                io::copy(&mut response.into_reader(), &mut file)?;
+                file.flush()
+            });
+            match result {
+                Ok(()) => return fs::rename(&tmp_path, destination),
+                Err(e) if attempt < RETRIES && e.kind() != io::ErrorKind::NotFound && e.kind() != io::ErrorKind::PermissionDenied => {
+                    thread::sleep(RETRY_DELAY * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                Err(e) => {
+                    let _ = fs::remove_file(&tmp_path);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let path = format!("{}/", self.path(prefix).trim_end_matches('/'));
+        let Some(resources) = self.propfind(&path, "1")? else {
+            return Ok(Vec::new());
+        };
+        let own_path = decode_path(&path);
+        let mut names = Vec::new();
+        for resource in resources {
+            // The answer starts with the collection itself
+            if resource.path == own_path.trim_end_matches('/') {
+                continue;
+            }
+            let name = resource.path.rsplit('/').next().unwrap_or_default();
+            if resource.is_collection {
+                names.push(format!("{}/", name));
+            } else {
+                names.push(name.to_string());
+            }
+        }
+        Ok(names)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key);
+        match self.send("DELETE", &path, &[], Body::Empty) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        };
+        // Folders only exist through their objects, as in a bucket
+        let mut collection = path.as_str();
+        while let Some((parent, _)) = collection.rsplit_once('/') {
+            if parent.len() <= self.base_path.len() || self.propfind(&format!("{}/", parent), "1")?.is_none_or(|members| members.len() > 1) {
+                break;
+            }
+            self.send("DELETE", &format!("{}/", parent), &[], Body::Empty)?;
+            self.collections.lock().unwrap().remove(parent);
+            collection = parent;
+        }
+        Ok(())
+    }
+
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectInfo>> {
+        let resources = self.propfind(&self.path(key), "0")?.unwrap_or_default();
+        Ok(resources
+            .into_iter()
+            .find(|resource| !resource.is_collection)
+            .map(|resource| ObjectInfo { size: resource.size, etag: resource.etag }))
+    }
+
+    fn copy(&self, from: &str, to: &str) -> io::Result<Option<String>> {
+        self.create_parents(to)?;
+        let destination = format!("{}{}", self.base_url, self.path(to));
+        let response = self.send("COPY", &self.path(from), &[("Destination", &destination), ("Overwrite", "T")], Body::Empty)?;
+        // Most servers only give the ETag of the copy when asked
+        match response_etag(&response) {
+            Some(etag) => Ok(Some(etag)),
+            None => Ok(self.stat(to)?.and_then(|info| info.etag)),
+        }
+    }
+
+    /// Stream the object in a single PUT when its size is known, as the request gives its length.
+    /// Otherwise it is written into a temporary file first, stored with `put`.
+    fn create(&self, key: &str, size: Option<u64>) -> io::Result<Box<dyn ObjectWriter + '_>> {
+        let Some(size) = size else {
+            return storage::spooled_object(self, key);
+        };
+        self.create_parents(key)?;
+        let request = self.request("PUT", &self.path(key)).set("Content-Length", &size.to_string());
+        let (sender, receiver) = mpsc::sync_channel(PIECES_WAITING);
+        let body = ObjectBody { receiver, piece: Vec::new(), position: 0 };
+        let upload = thread::spawn(move || request.send(body).map(|response| response_etag(&response)).map_err(request_error));
+        Ok(Box::new(WebDavObject { sender: Some(sender), upload: Some(upload), piece: Vec::with_capacity(PIECE_SIZE), size, written: 0 }))
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        let response = self.send("PROPFIND", &format!("{}/", self.base_path), &[("Depth", "0")], Body::Xml(QUOTA_BODY)).ok()?;
+        parse_multistatus(&response.into_string().ok()?).into_iter().next()?.available
+    }
+}
+
+/// Object streamed to the server by `WebDavBackend::create`, sent by another thread as it is
+/// written. The server only stores it once its whole length arrived: the request is stopped if
+/// the writer is dropped before being finished, or is given another length.
+struct WebDavObject {
+    sender: Option<SyncSender<io::Result<Vec<u8>>>>,
+    upload: Option<JoinHandle<io::Result<Option<String>>>>,
+    // Written, but not handed to the request yet
+    piece: Vec<u8>,
+    size: u64,
+    written: u64,
+}
+
+impl WebDavObject {
+    /// Hand the pending piece to the request
+    fn send_piece(&mut self) -> io::Result<()> {
+        let piece = mem::replace(&mut self.piece, Vec::with_capacity(PIECE_SIZE));
+        let sender = self.sender.as_ref().expect("written before being finished");
+        if sender.send(Ok(piece)).is_ok() {
+            return Ok(());
+        }
+        // The request stopped reading the object: it failed
+        self.sender = None;
+        match self.upload.take().map(JoinHandle::join) {
+            Some(Ok(Err(e))) => Err(e),
+            _ => Err(io::Error::new(io::ErrorKind::BrokenPipe, "the upload stopped")),
+        }
+    }
+
+    fn wrong_size(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} bytes written instead of {}, the file may have changed while it was saved", self.written, self.size),
+        )
+    }
+}
+
+impl Write for WebDavObject {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written += buf.len() as u64;
+        if self.written > self.size {
+            return Err(self.wrong_size());
+        }
+        self.piece.extend_from_slice(buf);
+        if self.piece.len() >= PIECE_SIZE {
+            self.send_piece()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ObjectWriter for WebDavObject {
+    fn finish(mut self: Box<Self>) -> io::Result<Option<String>> {
+        if self.written != self.size {
+            return Err(self.wrong_size());
+        }
+        if !self.piece.is_empty() {
+            self.send_piece()?;
+        }
+        // The end of the object, then the answer of the server
+        self.sender = None;
+        match self.upload.take().expect("finished once").join() {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::other("the upload thread panicked")),
+        }
+    }
+}
+
+impl Drop for WebDavObject {
+    fn drop(&mut self) {
+        // The request fails on the error instead of waiting for the rest of the object. It must not
+        // be `Interrupted`, which copying the body retries.
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Err(io::Error::other("the upload was abandoned")));
+        }
+        if let Some(upload) = self.upload.take() {
+            let _ = upload.join();
+        }
+    }
+}
+
+/// Body of the request streaming an object, read from the pieces the writer hands it
+struct ObjectBody {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    piece: Vec<u8>,
+    position: usize,
+}
+
+impl Read for ObjectBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.piece.len() {
+            match self.receiver.recv() {
+                Ok(piece) => {
+                    self.piece = piece?;
+                    self.position = 0;
+                }
+                // The writer is finished
+                Err(_) => return Ok(0),
+            }
+        }
+        let read = buf.len().min(self.piece.len() - self.position);
+        buf[..read].copy_from_slice(&self.piece[self.position..self.position + read]);
+        self.position += read;
+        Ok(read)
+    }
+}
+
+/// ETag of the resource written by a request
+fn response_etag(response: &ureq::Response) -> Option<String> {
+    response.header("ETag").or_else(|| response.header("OC-ETag")).map(normalize_etag)
+}
+
+/// Servers write the same ETag with or without quotes and weak prefix depending on where it is given
+fn normalize_etag(etag: &str) -> String {
+    etag.trim().trim_start_matches("W/").trim_matches('"').to_string()
+}
+
+/// Resources of the `multistatus` answer to a PROPFIND request. The `DAV:` namespace may come with
+/// any prefix, so elements are matched by their local name.
+fn parse_multistatus(xml: &str) -> Vec<Resource> {
+    let mut reader = Reader::from_str(xml);
+    let mut stack: Vec<String> = Vec::new();
+    let mut resources = Vec::new();
+    let mut current: Option<Resource> = None;
+
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(element)) => {
+                let name = String::from_utf8_lossy(element.local_name().as_ref()).to_string();
+                if name == "response" {
+                    current = Some(Resource { path: String::new(), is_collection: false, size: 0, etag: None, available: None });
+                }
+                mark_collection(&mut current, &stack, &name);
+                stack.push(name);
+            }
+            Ok(Event::Empty(element)) => mark_collection(&mut current, &stack, &String::from_utf8_lossy(element.local_name().as_ref())),
+            Ok(Event::End(_)) => {
+                let name = stack.pop();
+                if name.as_deref() == Some("response") {
+                    resources.extend(current.take());
+                }
+            }
+            Ok(Event::Text(text)) => {
+                let (Some(resource), Some(element), Ok(text)) = (current.as_mut(), stack.last(), text.unescape()) else {
+                    continue;
+                };
+                match element.as_str() {
+                    "href" => {
+                        // Absolute URL or absolute path
+                        let path = match text.split_once("://") {
+                            Some((_, rest)) => rest.find('/').map(|start| &rest[start..]).unwrap_or("/"),
+                            None => &text,
+                        };
+                        resource.path = decode_path(path).trim_end_matches('/').to_string();
+                    }
+                    "getcontentlength" => resource.size = text.trim().parse().unwrap_or(0),
+                    "getetag" => resource.etag = Some(normalize_etag(&text)),
+                    "quota-available-bytes" => resource.available = text.trim().parse().ok(),
+                    _ => {}
+                }
+            }
+            Ok(Event::Eof) | Err(_) => return resources,
+            _ => {}
+        }
+    }
+}
+
+/// A `collection` element in the `resourcetype` of a resource makes it a collection
+fn mark_collection(current: &mut Option<Resource>, stack: &[String], name: &str) {
+    if name == "collection" && stack.last().is_some_and(|parent| parent == "resourcetype") {
+        if let Some(resource) = current.as_mut() {
+            resource.is_collection = true;
+        }
+    }
+}
+
+/// Percent-encode each segment of a path, with a leading `/` unless empty
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| format!("/{}", uri_encode(segment, true)))
+        .collect()
+}
+
+/// Decode a percent-encoded path, leaving invalid sequences as they are
+fn decode_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok()).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Network errors, server errors and throttling are worth another try, the other errors are not
+fn is_retryable(error: &ureq::Error) -> bool {
+    match error {
+        ureq::Error::Status(status, _) => *status >= 500 || *status == 408 || *status == 429,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// IO error for a failed request. A missing parent collection gives `NotFound` too, and an existing
+/// collection `AlreadyExists`.
+fn request_error(error: ureq::Error) -> io::Error {
+    match error {
+        ureq::Error::Status(status, response) => {
+            let kind = match status {
+                404 | 409 => io::ErrorKind::NotFound,
+                405 => io::ErrorKind::AlreadyExists,
+                401 | 403 => io::ErrorKind::PermissionDenied,
+                _ => io::ErrorKind::Other,
+            };
+            io::Error::new(kind, format!("WebDAV error {}: {}", status, response.status_text()))
+        }
+        ureq::Error::Transport(transport) => io::Error::other(transport.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use crate::storage::tests::{check_operations, check_resumed_push, temp_folder, test_bytes, unique_name};
+
+    // Headers and body of a request, and whether the body had the announced length
+    type Received = (Vec<String>, Vec<u8>, bool);
+
+    /// Server answering a single PUT, which returns what it received
+    fn put_server() -> (String, JoinHandle<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let location = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                headers.push(line.trim().to_string());
+            }
+            let length: u64 = headers.iter()
+                .find_map(|header| header.to_lowercase().strip_prefix("content-length:").map(|value| value.trim().parse().unwrap()))
+                .unwrap();
+            let mut body = Vec::new();
+            reader.by_ref().take(length).read_to_end(&mut body).unwrap();
+            let complete = body.len() as u64 == length;
+            if complete {
+                (&stream).write_all(b"HTTP/1.1 201 Created\r\nETag: \"streamed\"\r\nContent-Length: 0\r\n\r\n").unwrap();
+            }
+            (headers, body, complete)
+        });
+        (location, server)
+    }
+
+    #[test]
+    fn object_of_known_size_is_streamed_with_its_length() {
+        let (location, server) = put_server();
+        let backend = WebDavBackend::new(&WebDavConfig::default(), false, &location).unwrap();
+        let contents = test_bytes(3 * PIECE_SIZE + 7);
+        let mut writer = backend.create("file.bin", Some(contents.len() as u64)).unwrap();
+        for piece in contents.chunks(1000) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap().as_deref(), Some("streamed"));
+
+        let (headers, body, complete) = server.join().unwrap();
+        assert!(complete);
+        assert_eq!(headers[0], "PUT /file.bin HTTP/1.1");
+        assert!(!headers.iter().any(|header| header.to_lowercase().starts_with("transfer-encoding")), "{:?}", headers);
+        assert!(body == contents);
+    }
+
+    #[test]
+    fn dropped_or_longer_object_is_not_stored() {
+        let (location, server) = put_server();
+        let backend = WebDavBackend::new(&WebDavConfig::default(), false, &location).unwrap();
+        let mut writer = backend.create("file.bin", Some(2 * PIECE_SIZE as u64)).unwrap();
+        writer.write_all(&test_bytes(PIECE_SIZE + 1)).unwrap();
+        drop(writer);
+        let (_, body, complete) = server.join().unwrap();
+        assert!(!complete);
+        assert!(body.len() < 2 * PIECE_SIZE);
+
+        let (location, server) = put_server();
+        let backend = WebDavBackend::new(&WebDavConfig::default(), false, &location).unwrap();
+        let mut writer = backend.create("file.bin", Some(10)).unwrap();
+        let error = writer.write_all(&test_bytes(11)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        drop(writer);
+        assert!(!server.join().unwrap().2);
+    }
+
+    /// Runs against BACKMEUP_TEST_WEBDAV_URL, as `webdav://<host>:<port>/<path>` or `webdavs://...`,
+    /// logged in as BACKMEUP_TEST_WEBDAV_USER with BACKMEUP_TEST_WEBDAV_PASSWORD
+    #[test]
+    #[ignore]
+    fn webdav_backend() {
+        let url = env::var("BACKMEUP_TEST_WEBDAV_URL").expect("BACKMEUP_TEST_WEBDAV_URL is not set");
+        let (secure, location) = match url.strip_prefix("webdavs://") {
+            Some(location) => (true, location),
+            None => (false, url.strip_prefix("webdav://").expect("BACKMEUP_TEST_WEBDAV_URL is not a webdav:// URL")),
+        };
+        let config = WebDavConfig {
+            username: env::var("BACKMEUP_TEST_WEBDAV_USER").ok(),
+            password: env::var("BACKMEUP_TEST_WEBDAV_PASSWORD").ok(),
+            bearer_token: None,
+        };
+        let backend = WebDavBackend::new(&config, secure, location).unwrap();
+        check_operations(&backend, 3 * 1024 * 1024);
+        check_resumed_push(&backend);
+
+        // Files are copied on the server, with the ETag of the copy
+        let folder = temp_folder("webdav-copy");
+        let prefix = unique_name("copy");
+        fs::write(folder.join("file.txt"), "copied on the server").unwrap();
+        backend.put(&format!("{}/file.txt", prefix), &folder.join("file.txt")).unwrap();
+        let etag = backend.copy(&format!("{}/file.txt", prefix), &format!("{}/copies/file.txt", prefix)).unwrap();
+        let copy = backend.stat(&format!("{}/copies/file.txt", prefix)).unwrap().unwrap();
+        assert_eq!(copy.size, 20);
+        if etag.is_some() {
+            assert_eq!(copy.etag, etag);
+        }
+        backend.get(&format!("{}/copies/file.txt", prefix), &folder.join("copied.txt")).unwrap();
+        assert_eq!(fs::read_to_string(folder.join("copied.txt")).unwrap(), "copied on the server");
+
+        backend.delete(&format!("{}/file.txt", prefix)).unwrap();
+        backend.delete(&format!("{}/copies/file.txt", prefix)).unwrap();
+        fs::remove_dir_all(folder).unwrap();
+    }
+}